@@ -0,0 +1,136 @@
+/* .well-known resolver for the matrix protocol */
+
+#ifndef MATRIX_ORACLE_H
+#define MATRIX_ORACLE_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The resolution succeeded.
+ */
+#define MATRIX_ORACLE_OK 0
+
+/**
+ * There is no well-known information, so the server name itself is used.
+ * Corresponds to the `IGNORE` outcome in the spec.
+ */
+#define MATRIX_ORACLE_IGNORE 1
+
+/**
+ * Corresponds to the `FAIL_PROMPT` outcome in the spec.
+ */
+#define MATRIX_ORACLE_FAIL_PROMPT 2
+
+/**
+ * Corresponds to the `FAIL_ERROR` outcome in the spec. Failed server
+ * resolution is reported with this code as well.
+ */
+#define MATRIX_ORACLE_FAIL_ERROR 3
+
+/**
+ * A pointer was null or a string wasn't valid UTF-8.
+ */
+#define MATRIX_ORACLE_INVALID_ARGUMENT 4
+
+/**
+ * The resolver couldn't be constructed, or failed unexpectedly.
+ */
+#define MATRIX_ORACLE_INTERNAL 5
+
+/**
+ * Resolver for the client-server API, named `MatrixOracleClient` in C.
+ */
+typedef struct MatrixOracleClient MatrixOracleClient;
+
+/**
+ * Resolver for the server-server API, named `MatrixOracleServer` in C.
+ */
+typedef struct MatrixOracleServer MatrixOracleServer;
+
+/**
+ * Constructs a client resolver and stores it in `out`. It must be released
+ * with [`matrix_oracle_client_free`].
+ *
+ * # Safety
+ *
+ * `out` must be null or valid for writes.
+ */
+int matrix_oracle_client_new(struct MatrixOracleClient **out);
+
+/**
+ * Releases a client resolver.
+ *
+ * # Safety
+ *
+ * `resolver` must be null or returned by [`matrix_oracle_client_new`], and
+ * must not be used afterwards.
+ */
+void matrix_oracle_client_free(struct MatrixOracleClient *resolver);
+
+/**
+ * Resolves the base URL of the homeserver for the given server name and
+ * stores it in `homeserver`. The URL is stored for both
+ * [`MATRIX_ORACLE_OK`] and [`MATRIX_ORACLE_IGNORE`].
+ *
+ * # Safety
+ *
+ * `resolver` must be null or a live client resolver, `name` must be null or
+ * a NUL-terminated string and `homeserver` must be null or valid for
+ * writes.
+ */
+int matrix_oracle_client_resolve(const struct MatrixOracleClient *resolver,
+                                 const char *name,
+                                 char **homeserver);
+
+/**
+ * Constructs a server resolver with the system DNS configuration and stores
+ * it in `out`. It must be released with [`matrix_oracle_server_free`].
+ *
+ * # Safety
+ *
+ * `out` must be null or valid for writes.
+ */
+int matrix_oracle_server_new(struct MatrixOracleServer **out);
+
+/**
+ * Releases a server resolver.
+ *
+ * # Safety
+ *
+ * `resolver` must be null or returned by [`matrix_oracle_server_new`], and
+ * must not be used afterwards.
+ */
+void matrix_oracle_server_free(struct MatrixOracleServer *resolver);
+
+/**
+ * Resolves the given server name. The address to connect to is stored in
+ * `address`, and the value for the `Host` header in `host`.
+ *
+ * # Safety
+ *
+ * `resolver` must be null or a live server resolver, `name` must be null or
+ * a NUL-terminated string and `address` and `host` must be null or valid for
+ * writes.
+ */
+int matrix_oracle_server_resolve(const struct MatrixOracleServer *resolver,
+                                 const char *name,
+                                 char **address,
+                                 char **host);
+
+/**
+ * Releases a string returned by one of the functions.
+ *
+ * # Safety
+ *
+ * `string` must be null or returned by one of the functions, and must not be
+ * used afterwards.
+ */
+void matrix_oracle_string_free(char *string);
+
+#endif  /* MATRIX_ORACLE_H */
@@ -0,0 +1,350 @@
+//! Command-line tool showing how the library resolves a server name.
+
+// Printing is what the tool is for.
+#![allow(clippy::print_stdout, clippy::print_stderr)]
+
+use std::{
+	net::{IpAddr, SocketAddr},
+	path::PathBuf,
+	process::ExitCode,
+};
+
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use matrix_oracle::{
+	client::{self, versions::Versions, Discovery},
+	lint::{self, Lint, Severity},
+	overrides::Overrides,
+	server::{
+		self,
+		diagnose::{Check, Report, Verdict},
+		Resolution, Server,
+	},
+};
+use serde::Serialize;
+use tracing::Level;
+use trust_dns_resolver::{
+	config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+	TokioAsyncResolver,
+};
+
+/// The error type of the commands.
+type Error = Box<dyn std::error::Error>;
+
+/// Resolve matrix server names the way matrix-oracle does.
+#[derive(Debug, Parser)]
+#[command(name = "matrix-oracle", version)]
+struct Cli {
+	/// Print JSON instead of human-readable output.
+	#[arg(long, global = true)]
+	json: bool,
+	/// DNS server to use instead of the system configuration, as an IP
+	/// address with an optional port. Can be given several times.
+	#[arg(long, global = true, value_name = "ADDRESS", value_parser = dns_server)]
+	dns: Vec<SocketAddr>,
+	/// TOML or JSON file with static overrides, reloaded when it changes.
+	#[arg(long, global = true, value_name = "FILE")]
+	overrides: Option<PathBuf>,
+	/// Log what the resolvers do to stderr. Repeat for more detail.
+	#[arg(short, long, global = true, action = ArgAction::Count)]
+	verbose: u8,
+	/// What to resolve.
+	#[command(subcommand)]
+	command: Command,
+}
+
+/// The subcommands.
+#[derive(Debug, Subcommand)]
+enum Command {
+	/// Discover the homeserver for the client-server API.
+	Client {
+		/// The server name to discover.
+		name: String,
+	},
+	/// Resolve the server for the server-server API.
+	Server {
+		/// The server name to resolve.
+		name: String,
+	},
+	/// Resolve the server and look up the addresses to connect to.
+	Socket {
+		/// The server name to resolve.
+		name: String,
+	},
+	/// Check the federation setup, like the federation tester.
+	Diagnose {
+		/// The server name to check.
+		name: String,
+	},
+	/// Check a draft .well-known document without publishing it.
+	Lint {
+		/// Which document the file contains.
+		document: Document,
+		/// The JSON file to check.
+		file: PathBuf,
+	},
+	/// Serve the HTTP API, sharing one cache between all requests.
+	#[cfg(feature = "service")]
+	Serve {
+		/// The address to listen on.
+		#[arg(long, default_value = "127.0.0.1:8080")]
+		listen: SocketAddr,
+	},
+}
+
+/// The .well-known documents which can be checked.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Document {
+	/// `/.well-known/matrix/server`
+	Server,
+	/// `/.well-known/matrix/client`
+	Client,
+}
+
+/// The output of the `socket` subcommand.
+#[derive(Debug, Serialize)]
+struct Sockets {
+	/// The resolved server.
+	server: Server,
+	/// The addresses to connect to.
+	sockets: Vec<SocketAddr>,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+	let cli = Cli::parse();
+	if cli.verbose > 0 {
+		let level = if cli.verbose == 1 { Level::DEBUG } else { Level::TRACE };
+		tracing_subscriber::fmt().with_max_level(level).with_writer(std::io::stderr).init();
+	}
+	match run(&cli).await {
+		Ok(code) => code,
+		Err(e) => {
+			eprintln!("error: {}", e);
+			ExitCode::FAILURE
+		}
+	}
+}
+
+/// Runs the subcommand and prints its output.
+async fn run(cli: &Cli) -> Result<ExitCode, Error> {
+	let overrides = match &cli.overrides {
+		Some(path) => Overrides::from_file(path)?,
+		None => Overrides::new(),
+	};
+	let server =
+		server_resolver(&cli.dns)?.with_overrides(overrides.clone()).with_delegation_checks(true);
+	match &cli.command {
+		Command::Client { name } => {
+			let client = client_resolver(&server)?.with_overrides(overrides).with_cors_check(true);
+			let discovery = client.discover(name).await?;
+			print(cli.json, &discovery, print_discovery)?;
+		}
+		Command::Server { name } => {
+			let resolution = server.resolve_traced(name).await?;
+			print(cli.json, &resolution, print_resolution)?;
+		}
+		Command::Socket { name } => {
+			let resolved = server.resolve(name).await?;
+			let sockets = server.sockets(&resolved).await?;
+			print(cli.json, &Sockets { server: resolved, sockets }, print_sockets)?;
+		}
+		Command::Diagnose { name } => {
+			let report = server.diagnose(name).await;
+			print(cli.json, &report, print_report)?;
+			if report.verdict() == Verdict::Fail {
+				return Ok(ExitCode::FAILURE);
+			}
+		}
+		Command::Lint { document, file } => {
+			let json = std::fs::read_to_string(file)?;
+			let lints = match document {
+				Document::Server => lint::server(&json),
+				Document::Client => lint::client(&json),
+			};
+			print(cli.json, &lints, print_lints)?;
+			if lints.iter().any(|lint| lint.severity == Severity::Error) {
+				return Ok(ExitCode::FAILURE);
+			}
+		}
+		#[cfg(feature = "service")]
+		Command::Serve { listen } => {
+			let client = client_resolver(&server)?
+				.with_cache(server.cache().clone())
+				.with_overrides(overrides);
+			let app = matrix_oracle::service::router(server, client);
+			axum::Server::bind(listen).serve(app.into_make_service()).await?;
+		}
+	}
+	Ok(ExitCode::SUCCESS)
+}
+
+/// Parses a DNS server address, defaulting to port 53.
+fn dns_server(address: &str) -> Result<SocketAddr, String> {
+	address
+		.parse::<SocketAddr>()
+		.or_else(|_| address.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+		.map_err(|_| format!("{} is not an IP address", address))
+}
+
+/// Constructs the server resolver, using the given DNS servers if there are
+/// any.
+fn server_resolver(dns: &[SocketAddr]) -> Result<server::Resolver, Error> {
+	let dns = if dns.is_empty() {
+		TokioAsyncResolver::tokio_from_system_conf()?
+	} else {
+		let mut servers = NameServerConfigGroup::new();
+		for address in dns {
+			servers.merge(NameServerConfigGroup::from_ips_clear(
+				&[address.ip()],
+				address.port(),
+				true,
+			));
+		}
+		TokioAsyncResolver::tokio(
+			ResolverConfig::from_parts(None, Vec::new(), servers),
+			ResolverOpts::default(),
+		)?
+	};
+	Ok(server::Resolver::with_builder(reqwest::Client::builder, dns)?)
+}
+
+/// Constructs the client resolver, looking up hostnames with the DNS
+/// configuration of the server resolver.
+fn client_resolver(server: &server::Resolver) -> Result<client::Resolver, Error> {
+	Ok(client::Resolver::with_builder(
+		reqwest::Client::builder().dns_resolver(server.dns_resolver()),
+	)?)
+}
+
+/// Prints the value as JSON or with the given function.
+fn print<T: Serialize>(json: bool, value: &T, human: fn(&T)) -> Result<(), Error> {
+	if json {
+		println!("{}", serde_json::to_string_pretty(value)?);
+	} else {
+		human(value);
+	}
+	Ok(())
+}
+
+/// Prints the result of the `client` subcommand.
+fn print_discovery(discovery: &Discovery) {
+	println!("Homeserver:           {}", discovery.homeserver);
+	if let Some(identity_server) = &discovery.identity_server {
+		println!("Identity server:      {}", identity_server);
+	}
+	match discovery.versions.as_ref().and_then(Versions::latest) {
+		Some(latest) => println!("Latest spec version:  {}", latest),
+		None => println!("Latest spec version:  unknown"),
+	}
+	if let Some(sliding_sync) = &discovery.sliding_sync {
+		println!("Sliding sync:         {:?} at {}", sliding_sync.mechanism, sliding_sync.url);
+	}
+	if let Some(tile_server) = &discovery.tile_server {
+		println!("Tile server:          {}", tile_server.map_style_url);
+	}
+	for manager in &discovery.integration_managers {
+		println!("Integration manager:  {}", manager.api_url);
+	}
+	for warning in &discovery.cors_warnings {
+		println!("CORS warning:         {}: {}", warning.url, warning.problem);
+	}
+	for warning in &discovery.warnings {
+		println!("Warning:              {}", warning);
+	}
+}
+
+/// Prints the result of the `server` subcommand.
+fn print_resolution(resolution: &Resolution) {
+	println!("Address:  {}", resolution.server.address());
+	println!("Host:     {}", resolution.server.host_header());
+	if resolution.stale {
+		println!("The resolution failed, this is the last good result");
+	}
+	if let Some(pattern) = &resolution.trace.matched_override {
+		println!("Override: {}", pattern);
+	}
+	for warning in &resolution.warnings {
+		println!("Warning:  {}", warning);
+	}
+	println!("Steps:");
+	for step in &resolution.trace.steps {
+		println!(
+			"  {} {:?}: {} ({} ms)",
+			step.step.number(),
+			step.step,
+			if step.matched { "matched" } else { "no match" },
+			step.elapsed.as_millis()
+		);
+	}
+}
+
+/// Prints the result of the `socket` subcommand.
+fn print_sockets(sockets: &Sockets) {
+	println!("Address:  {}", sockets.server.address());
+	for socket in &sockets.sockets {
+		println!("Socket:   {}", socket);
+	}
+}
+
+/// Prints the result of the `diagnose` subcommand.
+fn print_report(report: &Report) {
+	print_check("Resolution", &report.resolution);
+	print_check("Well-known", &report.well_known);
+	print_check("SRV records", &report.srv);
+	print_check("Addresses", &report.addresses);
+	for connection in &report.connections {
+		println!("{}:", connection.address);
+		print_check("  TLS", &connection.tls);
+		print_check("  Version", &connection.version);
+		print_check("  Keys", &connection.keys);
+	}
+	println!("Verdict: {:?}", report.verdict());
+}
+
+/// Prints the result of the `lint` subcommand.
+fn print_lints(lints: &Vec<Lint>) {
+	for lint in lints {
+		let pointer = if lint.pointer.is_empty() { "/" } else { &lint.pointer };
+		println!("{:?} at {}: {}", lint.severity, pointer, lint.message);
+	}
+	if lints.is_empty() {
+		println!("No problems found");
+	}
+}
+
+/// Prints a single check of a diagnosis.
+fn print_check<T>(name: &str, check: &Check<T>) {
+	println!("{:<12} {:?}: {}", format!("{}:", name), check.verdict, check.message);
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+
+	use clap::{CommandFactory, Parser};
+
+	use super::{Cli, Command};
+
+	/// Validates the argument definitions and parsing of global flags.
+	#[test]
+	fn arguments() -> Result<(), Box<dyn std::error::Error>> {
+		Cli::command().debug_assert();
+		let cli = Cli::try_parse_from([
+			"matrix-oracle",
+			"server",
+			"example.test",
+			"--json",
+			"--dns",
+			"192.0.2.1",
+			"--dns",
+			"[2001:db8::1]:5353",
+			"-vv",
+		])?;
+		assert!(cli.json);
+		assert_eq!(cli.verbose, 2);
+		assert_eq!(cli.dns, ["192.0.2.1:53".parse::<SocketAddr>()?, "[2001:db8::1]:5353".parse()?]);
+		assert!(matches!(cli.command, Command::Server { name } if name == "example.test"));
+		assert!(Cli::try_parse_from(["matrix-oracle", "client", "a", "--dns", "a.test"]).is_err());
+		Ok(())
+	}
+}
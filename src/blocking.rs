@@ -0,0 +1,39 @@
+//! Blocking versions of the resolvers, for synchronous code.
+//!
+//! The resolvers wrap the asynchronous ones and run them on a runtime they
+//! manage themselves, like `reqwest::blocking`. Clones share the runtime.
+//! They must not be called from within an async runtime, which panics.
+
+use std::{future::Future, io, sync::Arc};
+
+use tokio::runtime::{Builder, Runtime};
+
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "server")]
+pub mod server;
+
+/// The runtime the asynchronous resolvers run on.
+#[derive(Debug, Clone)]
+struct Handle {
+	/// The shared runtime. A worker thread keeps background tasks, like
+	/// refreshing stale resolutions, running between calls.
+	runtime: Arc<Runtime>,
+}
+
+impl Handle {
+	/// Starts a new runtime.
+	fn new() -> io::Result<Self> {
+		let runtime = Builder::new_multi_thread()
+			.worker_threads(1)
+			.thread_name("matrix-oracle-blocking")
+			.enable_all()
+			.build()?;
+		Ok(Self { runtime: Arc::new(runtime) })
+	}
+
+	/// Runs the future to completion, blocking the current thread.
+	fn block_on<F: Future>(&self, future: F) -> F::Output {
+		self.runtime.block_on(future)
+	}
+}
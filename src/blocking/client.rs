@@ -0,0 +1,93 @@
+//! Blocking resolution for the client-server API
+
+use std::io;
+
+use reqwest::Url;
+
+use super::Handle;
+use crate::client::{self, error::Error, Discovery};
+
+/// Blocking version of [`client::Resolver`].
+#[derive(Debug, Clone)]
+pub struct Resolver {
+	/// The runtime the resolver runs on.
+	handle: Handle,
+	/// The asynchronous resolver.
+	inner: client::Resolver,
+}
+
+impl Resolver {
+	/// Constructs a new resolver.
+	pub fn new() -> io::Result<Self> {
+		Self::from_async(client::Resolver::new())
+	}
+
+	/// Constructs a blocking resolver from a configured asynchronous one.
+	pub fn from_async(inner: client::Resolver) -> io::Result<Self> {
+		Ok(Self { handle: Handle::new()?, inner })
+	}
+
+	/// The asynchronous resolver, for inspecting its cache.
+	#[must_use]
+	pub fn inner(&self) -> &client::Resolver {
+		&self.inner
+	}
+
+	/// Get the base URL for the client-server API with the given name, see
+	/// [`client::Resolver::resolve`].
+	pub fn resolve(&self, name: &str) -> Result<Url, Error> {
+		self.handle.block_on(self.inner.resolve(name))
+	}
+
+	/// Discover the homeserver for the given name, see
+	/// [`client::Resolver::discover`].
+	pub fn discover(&self, name: &str) -> Result<Discovery, Error> {
+		self.handle.block_on(self.inner.discover(name))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use wiremock::{
+		matchers::{method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::Resolver;
+	use crate::client;
+
+	/// Validates discovery without an async runtime.
+	#[test]
+	fn discover() -> Result<(), Box<dyn std::error::Error>> {
+		let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+		let mock_server = runtime.block_on(async {
+			let mock_server = MockServer::start().await;
+			Mock::given(method("GET"))
+				.and(path("/.well-known/matrix/client"))
+				.respond_with(ResponseTemplate::new(404))
+				.expect(1)
+				.mount(&mock_server)
+				.await;
+			Mock::given(method("GET"))
+				.and(path("/_matrix/client/versions"))
+				.respond_with(
+					ResponseTemplate::new(200)
+						.set_body_raw(r#"{"versions":["r0.0.1"]}"#, "application/json"),
+				)
+				.expect(1)
+				.mount(&mock_server)
+				.await;
+			mock_server
+		});
+		let port = mock_server.address().port();
+
+		let http = reqwest::Client::builder().resolve("example.test", *mock_server.address());
+		let resolver = Resolver::from_async(client::Resolver::with_builder(http)?)?;
+		let discovery = resolver.discover(&format!("example.test:{}", port))?;
+		assert_eq!(discovery.homeserver.as_str(), format!("http://example.test:{}/", port));
+		assert!(discovery.versions.is_some());
+
+		runtime.block_on(mock_server.verify());
+		Ok(())
+	}
+}
@@ -0,0 +1,97 @@
+//! Blocking resolution for the server-server API
+
+use std::{io, net::SocketAddr};
+
+use trust_dns_resolver::error::ResolveError;
+
+use super::Handle;
+use crate::server::{self, error, Resolution, Server};
+
+/// Blocking version of [`server::Resolver`].
+#[derive(Debug, Clone)]
+pub struct Resolver {
+	/// The runtime the resolver runs on.
+	handle: Handle,
+	/// The asynchronous resolver.
+	inner: server::Resolver,
+}
+
+impl Resolver {
+	/// Constructs a new resolver with the system DNS configuration.
+	pub fn new() -> Result<Self, ResolveError> {
+		let handle = Handle::new()?;
+		let inner = {
+			let _guard = handle.runtime.enter();
+			server::Resolver::new()?
+		};
+		Ok(Self { handle, inner })
+	}
+
+	/// Constructs a blocking resolver from a configured asynchronous one.
+	pub fn from_async(inner: server::Resolver) -> io::Result<Self> {
+		Ok(Self { handle: Handle::new()?, inner })
+	}
+
+	/// The asynchronous resolver, for inspecting its cache.
+	#[must_use]
+	pub fn inner(&self) -> &server::Resolver {
+		&self.inner
+	}
+
+	/// Resolve the given server name, see [`server::Resolver::resolve`].
+	pub fn resolve(&self, name: &str) -> error::Result<Server> {
+		self.handle.block_on(self.inner.resolve(
+			name,
+			#[cfg(test)]
+			None,
+		))
+	}
+
+	/// Resolve the given server name, recording every step taken, see
+	/// [`server::Resolver::resolve_traced`].
+	pub fn resolve_traced(&self, name: &str) -> error::Result<Resolution> {
+		self.handle.block_on(self.inner.resolve_traced(
+			name,
+			#[cfg(test)]
+			None,
+		))
+	}
+
+	/// Get the [`SocketAddr`] of an address, see [`server::Resolver::socket`].
+	pub fn socket(&self, server: &Server) -> error::Result<SocketAddr> {
+		self.handle.block_on(self.inner.socket(server))
+	}
+
+	/// Get every [`SocketAddr`] an address resolves to, see
+	/// [`server::Resolver::sockets`].
+	pub fn sockets(&self, server: &Server) -> error::Result<Vec<SocketAddr>> {
+		self.handle.block_on(self.inner.sockets(server))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::{IpAddr, SocketAddr};
+
+	use super::Resolver;
+	use crate::server::{trace::Step, Server};
+
+	/// Validates resolving literals without an async runtime.
+	#[test]
+	fn literals() -> Result<(), Box<dyn std::error::Error>> {
+		let resolver = Resolver::new()?;
+		let socket = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 4884);
+		let server = resolver.resolve("127.0.0.1:4884")?;
+		assert_eq!(server, Server::Socket(socket));
+		assert_eq!(resolver.socket(&server)?, socket);
+		assert_eq!(
+			resolver.sockets(&Server::Ip(IpAddr::from([127, 0, 0, 1])))?,
+			[SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 8448)]
+		);
+
+		let resolution = resolver.clone().resolve_traced("example.test:1234")?;
+		assert_eq!(resolution.server, Server::HostPort(String::from("example.test:1234")));
+		assert_eq!(resolution.trace.decisive_step().map(|step| step.step), Some(Step::HostPort));
+		Ok(())
+	}
+}
@@ -0,0 +1,602 @@
+//! HTTP cache which can be shared by several resolvers, with statistics and
+//! ways to inspect and purge its entries.
+//!
+//! With the `server` feature, the cache also holds the results of server name
+//! resolutions, see `server::Resolver::with_resolution_caching`. With the
+//! `persistent` feature, the cache can be saved to and loaded from a file to
+//! keep it across restarts.
+
+#[cfg(feature = "server")]
+use std::{
+	collections::HashMap,
+	sync::Mutex,
+	time::{Duration, SystemTime},
+};
+#[cfg(feature = "persistent")]
+use std::{
+	io,
+	path::{Path, PathBuf},
+};
+use std::{
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+};
+
+#[cfg(feature = "persistent")]
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use http_cache_reqwest::{Cache, CacheMode, CacheOptions, HttpCache, MokaManager};
+use moka::future::ConcurrentCacheExt;
+use reqwest::{
+	header::{HeaderMap, HeaderValue},
+	Request, Response, Url,
+};
+use reqwest_middleware::{ClientWithMiddleware, Middleware, Next};
+#[cfg(any(feature = "server", feature = "persistent"))]
+use serde::Deserialize;
+use serde::Serialize;
+use task_local_extensions::Extensions;
+
+use crate::fetch;
+#[cfg(feature = "server")]
+use crate::server::Server;
+
+/// Number of responses cached by default.
+const DEFAULT_CAPACITY: u64 = 1000;
+/// Header the caching middleware sets to `HIT` or `MISS`.
+const X_CACHE: &str = "x-cache";
+/// Header recording the address a response was received from. It is stored
+/// with the response, so that the address is still known on cache hits.
+const REMOTE_ADDR: &str = "x-matrix-oracle-remote-addr";
+
+/// Handle to an HTTP cache. Clones refer to the same cache, so passing a clone
+/// to several resolvers lets them share cached responses.
+#[derive(Debug, Clone)]
+pub struct SharedCache {
+	/// The storage of cached responses.
+	manager: MokaManager,
+	/// Counters of cache lookups and evictions.
+	stats: Arc<Stats>,
+	/// Results of server name resolutions by server name.
+	#[cfg(feature = "server")]
+	resolutions: Arc<Mutex<HashMap<String, CachedResolution>>>,
+}
+
+/// The result of a server name resolution, stored until its grace period
+/// ends.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct CachedResolution {
+	/// The resolved server.
+	server: Server,
+	/// When the resolution has to be repeated.
+	expires: SystemTime,
+	/// Until when the resolution may still be used if repeating it fails.
+	stale_until: SystemTime,
+	/// Whether the resolution is being repeated in the background.
+	#[serde(skip)]
+	refreshing: bool,
+}
+
+/// A cached resolution of a server name.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone)]
+pub(crate) struct CachedServer {
+	/// The resolved server.
+	pub server: Server,
+	/// Whether the resolution has expired and is within its grace period.
+	pub stale: bool,
+}
+
+/// The contents of a cache as saved to a file.
+#[cfg(feature = "persistent")]
+#[derive(Deserialize, Serialize)]
+struct Snapshot {
+	/// The cached HTTP responses by cache key, encoded as base64.
+	responses: Vec<(String, String)>,
+	/// The cached resolutions by server name.
+	#[cfg(feature = "server")]
+	#[serde(default)]
+	resolutions: HashMap<String, CachedResolution>,
+}
+
+/// Counters shared by all clones of a cache.
+#[derive(Debug, Default)]
+struct Stats {
+	/// Number of responses served from the cache.
+	hits: AtomicU64,
+	/// Number of requests which weren't answered from the cache.
+	misses: AtomicU64,
+	/// Number of entries removed to make room for new ones or because they
+	/// expired.
+	evictions: AtomicU64,
+}
+
+/// Snapshot of the statistics of a cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+	/// Number of responses served from the cache.
+	pub hits: u64,
+	/// Number of requests which weren't answered from the cache.
+	pub misses: u64,
+	/// Number of entries removed to make room for new ones or because they
+	/// expired.
+	pub evictions: u64,
+	/// Number of entries currently in the cache.
+	pub entries: u64,
+}
+
+/// A response stored in the cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CacheEntry {
+	/// The method of the cached request.
+	pub method: String,
+	/// The URL of the cached request.
+	pub url: Url,
+}
+
+/// Middleware counting cache hits and misses, which has to run before the
+/// caching middleware to see the header it sets.
+struct Counter(Arc<Stats>);
+
+/// Middleware recording the address a response was received from, which has
+/// to run after the caching middleware so the address is cached as well.
+struct PeerAddress;
+
+impl SharedCache {
+	/// Constructs a cache which holds up to the given number of responses.
+	#[must_use]
+	pub fn new(capacity: u64) -> Self {
+		let stats = Arc::new(Stats::default());
+		let evictions = Arc::clone(&stats);
+		let cache = moka::future::Cache::builder()
+			.max_capacity(capacity)
+			.eviction_listener_with_queued_delivery_mode(move |_, _, cause| {
+				if cause.was_evicted() {
+					evictions.evictions.fetch_add(1, Ordering::Relaxed);
+				}
+			})
+			.build();
+		Self {
+			manager: MokaManager::new(cache),
+			stats,
+			#[cfg(feature = "server")]
+			resolutions: Arc::default(),
+		}
+	}
+
+	/// Loads a cache saved with [`save`](Self::save), holding up to the given
+	/// number of responses. A missing file results in an empty cache.
+	/// Resolutions past their grace period are dropped, and the freshness of
+	/// the responses is checked according to their caching headers before they
+	/// are used.
+	#[cfg(feature = "persistent")]
+	pub async fn load(path: impl AsRef<Path>, capacity: u64) -> io::Result<Self> {
+		let cache = Self::new(capacity);
+		let data = match tokio::fs::read(path).await {
+			Ok(data) => data,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(cache),
+			Err(e) => return Err(e),
+		};
+		let snapshot: Snapshot = serde_json::from_slice(&data)?;
+		for (key, value) in snapshot.responses {
+			let value =
+				BASE64.decode(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+			cache.manager.cache.insert(key, Arc::new(value)).await;
+		}
+		#[cfg(feature = "server")]
+		if let Ok(mut resolutions) = cache.resolutions.lock() {
+			let now = SystemTime::now();
+			*resolutions = snapshot.resolutions;
+			resolutions.retain(|_, resolution| resolution.stale_until > now);
+		}
+		Ok(cache)
+	}
+
+	/// Saves the cached responses and the resolutions which are still within
+	/// their grace period to the given file, replacing it.
+	#[cfg(feature = "persistent")]
+	pub async fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+		let path = path.as_ref();
+		let snapshot = Snapshot {
+			responses: self
+				.manager
+				.cache
+				.iter()
+				.map(|(key, value)| ((*key).clone(), BASE64.encode(&*value)))
+				.collect(),
+			#[cfg(feature = "server")]
+			resolutions: self
+				.resolutions
+				.lock()
+				.map(|resolutions| {
+					let now = SystemTime::now();
+					resolutions
+						.iter()
+						.filter(|(_, resolution)| resolution.stale_until > now)
+						.map(|(name, resolution)| (name.clone(), resolution.clone()))
+						.collect()
+				})
+				.unwrap_or_default(),
+		};
+		// Write to a temporary file first, so an interrupted save doesn't
+		// destroy the previous contents.
+		let mut temporary = path.as_os_str().to_owned();
+		temporary.push(".tmp");
+		let temporary = PathBuf::from(temporary);
+		let contents = serde_json::to_vec(&snapshot)?;
+		let mut file = tokio::fs::File::create(&temporary).await?;
+		tokio::io::AsyncWriteExt::write_all(&mut file, &contents).await?;
+		file.sync_all().await?;
+		tokio::fs::rename(temporary, path).await
+	}
+
+	/// The current statistics of the cache.
+	#[must_use]
+	pub fn stats(&self) -> CacheStats {
+		self.manager.cache.sync();
+		CacheStats {
+			hits: self.stats.hits.load(Ordering::Relaxed),
+			misses: self.stats.misses.load(Ordering::Relaxed),
+			evictions: self.stats.evictions.load(Ordering::Relaxed),
+			entries: self.manager.cache.entry_count(),
+		}
+	}
+
+	/// The requests whose responses are currently cached.
+	#[must_use]
+	pub fn entries(&self) -> Vec<CacheEntry> {
+		self.manager.cache.iter().filter_map(|(key, _)| parse_key(&key)).collect()
+	}
+
+	/// Removes every cached response from the given server name and its
+	/// cached resolutions, so that the next resolution fetches its .well-known
+	/// information again. Returns the number of removed entries.
+	pub async fn purge(&self, server_name: &str) -> usize {
+		let host = strip_port(server_name).to_ascii_lowercase();
+		let keys: Vec<_> = self
+			.manager
+			.cache
+			.iter()
+			.map(|(key, _)| key)
+			.filter(|key| {
+				parse_key(key).is_some_and(|entry| entry.url.host_str() == Some(host.as_str()))
+			})
+			.collect();
+		for key in &keys {
+			self.manager.cache.invalidate(key.as_str()).await;
+		}
+		#[cfg(feature = "server")]
+		let resolutions = self.resolutions.lock().map_or(0, |mut resolutions| {
+			let before = resolutions.len();
+			resolutions.retain(|name, _| !strip_port(name).eq_ignore_ascii_case(&host));
+			before - resolutions.len()
+		});
+		#[cfg(not(feature = "server"))]
+		let resolutions = 0;
+		keys.len() + resolutions
+	}
+
+	/// Removes every cached response and resolution.
+	pub fn clear(&self) {
+		self.manager.cache.invalidate_all();
+		#[cfg(feature = "server")]
+		if let Ok(mut resolutions) = self.resolutions.lock() {
+			resolutions.clear();
+		}
+	}
+
+	/// The cached resolution of the given server name, if its grace period
+	/// hasn't ended.
+	#[cfg(feature = "server")]
+	pub(crate) fn resolution(&self, name: &str) -> Option<CachedServer> {
+		let mut resolutions = self.resolutions.lock().ok()?;
+		let resolution = resolutions.get(name)?;
+		let now = SystemTime::now();
+		if resolution.stale_until > now {
+			return Some(CachedServer {
+				server: resolution.server.clone(),
+				stale: resolution.expires <= now,
+			});
+		}
+		resolutions.remove(name);
+		None
+	}
+
+	/// Stores the resolution of the given server name for the given time,
+	/// keeping it for the grace period afterwards.
+	#[cfg(feature = "server")]
+	pub(crate) fn insert_resolution(
+		&self,
+		name: &str,
+		server: &Server,
+		ttl: Duration,
+		grace: Duration,
+	) {
+		if ttl + grace == Duration::ZERO {
+			return;
+		}
+		if let Ok(mut resolutions) = self.resolutions.lock() {
+			let now = SystemTime::now();
+			resolutions.insert(
+				name.to_owned(),
+				CachedResolution {
+					server: server.clone(),
+					expires: now + ttl,
+					stale_until: now + ttl + grace,
+					refreshing: false,
+				},
+			);
+		}
+	}
+
+	/// Marks the cached resolution of the given server name as being
+	/// refreshed. Returns `false` if a refresh is already running.
+	#[cfg(feature = "server")]
+	pub(crate) fn start_refresh(&self, name: &str) -> bool {
+		let Ok(mut resolutions) = self.resolutions.lock() else { return false };
+		match resolutions.get_mut(name) {
+			Some(resolution) if !resolution.refreshing => {
+				resolution.refreshing = true;
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Marks the refresh of the cached resolution of the given server name as
+	/// finished, whether or not it succeeded.
+	#[cfg(feature = "server")]
+	pub(crate) fn finish_refresh(&self, name: &str) {
+		if let Ok(mut resolutions) = self.resolutions.lock() {
+			if let Some(resolution) = resolutions.get_mut(name) {
+				resolution.refreshing = false;
+			}
+		}
+	}
+
+	/// Wraps the given HTTP client with middleware caching responses in this
+	/// cache, with appropriate settings for matrix-oracle's use-case.
+	pub(crate) fn client(&self, http: reqwest::Client) -> ClientWithMiddleware {
+		reqwest_middleware::ClientBuilder::new(http)
+			.with(Counter(Arc::clone(&self.stats)))
+			.with(Cache(HttpCache {
+				mode: CacheMode::Default,
+				manager: self.manager.clone(),
+				options: Some(CacheOptions { shared: false, ..CacheOptions::default() }),
+			}))
+			.with(fetch::BodyLimit)
+			.with(PeerAddress)
+			.build()
+	}
+}
+
+impl Default for SharedCache {
+	fn default() -> Self {
+		Self::new(DEFAULT_CAPACITY)
+	}
+}
+
+#[async_trait::async_trait]
+impl Middleware for Counter {
+	async fn handle(
+		&self,
+		req: Request,
+		extensions: &mut Extensions,
+		next: Next<'_>,
+	) -> reqwest_middleware::Result<Response> {
+		let response = next.run(req, extensions).await?;
+		match response.headers().get(X_CACHE).map(HeaderValue::as_bytes) {
+			Some(b"HIT") => self.0.hits.fetch_add(1, Ordering::Relaxed),
+			_ => self.0.misses.fetch_add(1, Ordering::Relaxed),
+		};
+		Ok(response)
+	}
+}
+
+#[async_trait::async_trait]
+impl Middleware for PeerAddress {
+	async fn handle(
+		&self,
+		req: Request,
+		extensions: &mut Extensions,
+		next: Next<'_>,
+	) -> reqwest_middleware::Result<Response> {
+		let mut response = next.run(req, extensions).await?;
+		if let Some(addr) = response.remote_addr() {
+			if let Ok(value) = HeaderValue::from_str(&addr.to_string()) {
+				response.headers_mut().insert(REMOTE_ADDR, value);
+			}
+		}
+		Ok(response)
+	}
+}
+
+/// The address a response was received from, as recorded before it was
+/// cached.
+pub(crate) fn remote_addr(headers: &HeaderMap) -> Option<SocketAddr> {
+	headers.get(REMOTE_ADDR)?.to_str().ok()?.parse().ok()
+}
+
+/// Parses a cache key of the form `GET:https://example.org/path`.
+fn parse_key(key: &str) -> Option<CacheEntry> {
+	let (method, url) = key.split_once(':')?;
+	Some(CacheEntry { method: method.to_owned(), url: Url::parse(url).ok()? })
+}
+
+/// Removes the port from a server name, if it has one.
+fn strip_port(name: &str) -> &str {
+	match name.rsplit_once(':') {
+		Some((host, port)) if port.parse::<u16>().is_ok() => host,
+		_ => name,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::strip_port;
+
+	/// Validates removing ports from server names.
+	#[test]
+	fn ports() {
+		assert_eq!(strip_port("example.test:8448"), "example.test");
+		assert_eq!(strip_port("example.test"), "example.test");
+		assert_eq!(strip_port("[::1]:8448"), "[::1]");
+		assert_eq!(strip_port("[::1]"), "[::1]");
+	}
+
+	/// Validates saving and loading the cached responses and resolutions.
+	#[cfg(all(feature = "server", feature = "persistent"))]
+	#[tokio::test]
+	async fn persist() -> Result<(), Box<dyn std::error::Error>> {
+		use std::{
+			sync::Arc,
+			time::{Duration, SystemTime},
+		};
+
+		use super::{CachedResolution, SharedCache};
+		use crate::server::Server;
+
+		let cache = SharedCache::new(10);
+		cache
+			.manager
+			.cache
+			.insert(String::from("GET:https://example.test/"), Arc::new(vec![0, 1, 255]))
+			.await;
+		let server = Server::Host(String::from("destination.test"));
+		cache.insert_resolution("example.test", &server, Duration::from_secs(60), Duration::ZERO);
+		cache.insert_resolution("stale.test", &server, Duration::ZERO, Duration::from_secs(60));
+		let expired = SystemTime::now() - Duration::from_secs(1);
+		cache.resolutions.lock().map_err(|_| "poisoned")?.insert(
+			String::from("expired.test"),
+			CachedResolution {
+				server: server.clone(),
+				expires: expired,
+				stale_until: expired,
+				refreshing: false,
+			},
+		);
+
+		let path =
+			std::env::temp_dir().join(format!("matrix-oracle-cache-{}.json", std::process::id()));
+		let sibling = path.with_extension("tmp");
+		std::fs::write(&sibling, "unrelated")?;
+		cache.save(&path).await?;
+		let loaded = SharedCache::load(&path, 10).await?;
+		std::fs::remove_file(&path)?;
+		assert_eq!(std::fs::read_to_string(&sibling)?, "unrelated");
+		std::fs::remove_file(&sibling)?;
+		assert_eq!(
+			loaded.manager.cache.get("GET:https://example.test/").as_deref(),
+			Some(&vec![0, 1, 255])
+		);
+		assert_eq!(loaded.entries()[0].url.as_str(), "https://example.test/");
+		let cached = loaded.resolution("example.test").ok_or("missing resolution")?;
+		assert_eq!((cached.server, cached.stale), (server.clone(), false));
+		let cached = loaded.resolution("stale.test").ok_or("missing stale resolution")?;
+		assert_eq!((cached.server, cached.stale), (server, true));
+		assert!(loaded.resolutions.lock().map_err(|_| "poisoned")?.get("expired.test").is_none());
+
+		let missing = SharedCache::load(&path, 10).await?;
+		assert!(missing.entries().is_empty());
+		Ok(())
+	}
+
+	/// Validates sharing one cache between a client and a server resolver,
+	/// and purging a server name from it.
+	#[cfg(all(feature = "client", feature = "server"))]
+	#[tokio::test]
+	async fn shared() -> Result<(), Box<dyn std::error::Error>> {
+		use std::net::Ipv4Addr;
+
+		use trust_dns_resolver::proto::rr::{Name, RData, Record};
+		use wiremock::{
+			matchers::{method, path},
+			Mock, MockServer, ResponseTemplate,
+		};
+
+		use super::SharedCache;
+		use crate::{
+			client,
+			server::{self, error::Error, filter::IpFilter, test_dns},
+		};
+
+		let mock_server = MockServer::start().await;
+		let addr = mock_server.address();
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/client"))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.insert_header("cache-control", "max-age=3600")
+					.set_body_raw(
+						format!(r#"{{"m.homeserver": {{"base_url": "http://{}"}}}}"#, addr),
+						"application/json",
+					),
+			)
+			.expect(2)
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/_matrix/client/versions"))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.insert_header("cache-control", "no-store")
+					.set_body_raw(r#"{"versions": ["v1.11"]}"#, "application/json"),
+			)
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/server"))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.insert_header("cache-control", "max-age=3600")
+					.set_body_raw(format!(r#"{{"m.server": "{}"}}"#, addr), "application/json"),
+			)
+			.expect(1)
+			.mount(&mock_server)
+			.await;
+
+		let socket = *addr;
+		let http = move || reqwest::Client::builder().resolve("example.test", socket);
+		let cache = SharedCache::new(100);
+		let clients = (0..2)
+			.map(|_| Ok(client::Resolver::with_builder(http())?.with_cache(cache.clone())))
+			.collect::<Result<Vec<_>, reqwest::Error>>()?;
+		// The name passes the IP filter, the address of the cached response doesn't
+		let dns = test_dns::resolver(vec![Record::from_rdata(
+			Name::from_ascii("example.test.")?,
+			300,
+			RData::A(Ipv4Addr::new(93, 184, 215, 14)),
+		)])
+		.await?;
+		let server = server::Resolver::with_builder(http, dns)?.with_cache(cache.clone());
+
+		let name = format!("example.test:{}", addr.port());
+		for resolver in &clients {
+			resolver.discover(&name).await?;
+		}
+		for _ in 0..2 {
+			server.resolve("example.test", Some(addr.port())).await?;
+		}
+		let stats = cache.stats();
+		assert_eq!((stats.hits, stats.misses, stats.entries), (2, 4, 2));
+		let mut urls: Vec<_> =
+			cache.entries().into_iter().map(|entry| entry.url.path().to_owned()).collect();
+		urls.sort();
+		assert_eq!(urls, ["/.well-known/matrix/client", "/.well-known/matrix/server"]);
+
+		let strict = server.clone().with_ip_filter(IpFilter::federation_default());
+		assert!(matches!(
+			strict.resolve("example.test", Some(addr.port())).await,
+			Err(Error::Denied(ip)) if ip == addr.ip()
+		));
+
+		assert_eq!(server.cache().purge("other.test").await, 0);
+		assert_eq!(clients[0].cache().purge(&name).await, 2);
+		assert!(cache.entries().is_empty());
+		clients[1].discover(&name).await?;
+		Ok(())
+	}
+}
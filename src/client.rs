@@ -1,15 +1,33 @@
 //! Resolution for the client-server API
 
+pub mod capabilities;
+pub mod cors;
 pub mod error;
-
-use std::collections::BTreeMap;
+pub mod integrations;
+pub mod sliding_sync;
+pub mod versions;
+pub mod warning;
 
 use reqwest::{StatusCode, Url};
 use reqwest_middleware::ClientWithMiddleware;
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "overrides")]
+use tracing::info;
 
-use self::error::{Error, FailError};
-use crate::cache;
+use self::{
+	cors::CorsWarning,
+	error::{Error, FailError},
+	integrations::{IntegrationManager, IntegrationsInfo, TileServer, TileServerInfo},
+	sliding_sync::{SlidingSync, SlidingSyncProxyInfo},
+	versions::Versions,
+	warning::Warning,
+};
+#[cfg(feature = "overrides")]
+use crate::overrides::Overrides;
+use crate::{
+	cache::SharedCache,
+	fetch::{self, FetchPolicy},
+};
 
 /// well-known information for the client-server API.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,110 +37,361 @@ pub struct ClientWellKnown {
 	pub homeserver: HomeserverInfo,
 
 	/// Information about the identity server to connect to.
-	#[serde(rename = "m.identity_server")]
+	#[serde(rename = "m.identity_server", skip_serializing_if = "Option::is_none")]
 	pub identity_server: Option<IdentityServerInfo>,
+
+	/// Information about the sliding sync proxy to use. An invalid value is
+	/// ignored.
+	#[serde(
+		rename = "org.matrix.msc3575.proxy",
+		default,
+		deserialize_with = "warning::lenient",
+		skip_serializing_if = "Option::is_none"
+	)]
+	pub sliding_sync_proxy: Option<SlidingSyncProxyInfo>,
+
+	/// Information about the tile server for location sharing. An invalid
+	/// value is ignored.
+	#[serde(
+		rename = "m.tile_server",
+		alias = "org.matrix.msc3488.tile_server",
+		default,
+		deserialize_with = "warning::lenient",
+		skip_serializing_if = "Option::is_none"
+	)]
+	pub tile_server: Option<TileServerInfo>,
+
+	/// Information about the integration managers to offer. An invalid value
+	/// is ignored.
+	#[serde(
+		rename = "m.integrations",
+		default,
+		deserialize_with = "warning::lenient",
+		skip_serializing_if = "Option::is_none"
+	)]
+	pub integrations: Option<IntegrationsInfo>,
 }
 
 /// Information about the homeserver to connect to.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HomeserverInfo {
 	/// The base url to use for client-server API endpoints.
-	base_url: String,
+	pub base_url: String,
 }
 
 /// Information about the identity server to connect to.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IdentityServerInfo {
 	/// The base url to use for identity server API endpoints.
-	base_url: String,
+	pub base_url: String,
+}
+
+/// Discovery of homeservers for the client-server API, implemented by
+/// [`Resolver`]. Code depending on this trait instead of the resolver can be
+/// given fixed results in tests, see the `testing` feature.
+#[async_trait::async_trait]
+pub trait ClientDiscover: Send + Sync {
+	/// Discover the homeserver for the given name.
+	async fn discover_homeserver(&self, name: &str) -> Result<Discovery, Error>;
+
+	/// Get the base URL for the client-server API with the given name.
+	async fn resolve_homeserver(&self, name: &str) -> Result<Url, Error> {
+		Ok(self.discover_homeserver(name).await?.homeserver)
+	}
 }
 
 /// Resolver for well-known lookups for the client-server API.
 #[derive(Clone, Debug)]
 pub struct Resolver {
+	/// The HTTP client without the caching middleware.
+	client: reqwest::Client,
 	/// The HTTP client used to send and receive requests. Should transparently
 	/// handle HTTP caching.
 	http: ClientWithMiddleware,
+	/// Cache of HTTP responses.
+	cache: SharedCache,
+	/// Limits for fetching the .well-known information and versions.
+	fetch_policy: FetchPolicy,
+	/// Whether to check that an advertised sliding sync proxy is reachable.
+	check_sliding_sync_proxy: bool,
+	/// Whether to check that the tile server and integration managers are
+	/// reachable.
+	check_integrations: bool,
+	/// Whether to check the CORS headers web clients need.
+	check_cors: bool,
+	/// Static overrides taking precedence over discovery.
+	#[cfg(feature = "overrides")]
+	overrides: Overrides,
 }
 
-/// Represents the set of matrix versions a server support. Used exclusively for
-/// validating the contents of a response
-#[allow(dead_code)]
-#[derive(Deserialize)]
-struct Versions {
-	/// List of matrix spec versions the server supports.
-	pub versions: Vec<String>,
-	/// Set of unstable matrix extensions which the server supports
-	#[serde(default)]
-	pub unstable_features: BTreeMap<String, bool>,
+/// The result of discovering the homeserver for a server name.
+#[derive(Debug, Clone, Serialize)]
+pub struct Discovery {
+	/// The base URL for client-server API endpoints.
+	pub homeserver: Url,
+	/// Whether the well-known request returned 404, so the server name itself
+	/// is used. This is the `IGNORE` outcome of the specification.
+	pub well_known_missing: bool,
+	/// The base URL of the identity server, if the well-known information
+	/// names one.
+	pub identity_server: Option<Url>,
+	/// The spec versions and unstable features the homeserver supports. This
+	/// is only missing if there was no well-known information and the
+	/// versions couldn't be fetched from the fallback homeserver either.
+	pub versions: Option<Versions>,
+	/// The sliding sync mechanism to use, if the homeserver supports sliding
+	/// sync natively or advertises a proxy.
+	pub sliding_sync: Option<SlidingSync>,
+	/// The tile server for location sharing, if one is advertised.
+	pub tile_server: Option<TileServer>,
+	/// The integration managers in order of preference.
+	pub integration_managers: Vec<IntegrationManager>,
+	/// Responses web clients can't read because of their CORS headers, if
+	/// this was checked.
+	pub cors_warnings: Vec<CorsWarning>,
+	/// Optional parts of the well-known information which were ignored
+	/// because they are invalid.
+	pub warnings: Vec<Warning>,
 }
 
 impl Resolver {
 	/// Construct a new resolver.
 	#[must_use]
 	pub fn new() -> Self {
+		#[allow(clippy::expect_used)]
+		Self::with_builder(reqwest::Client::builder())
+			.expect("the TLS backend and resolver can be initialized")
+	}
+
+	/// Construct a new resolver with the given reqwest client. The client
+	/// must not follow redirects itself, or responses with redirects are
+	/// rejected, see [`FetchPolicy`]. Prefer
+	/// [`with_builder`](Self::with_builder).
+	#[must_use]
+	pub fn with(http: reqwest::Client) -> Self {
+		let cache = SharedCache::default();
 		Self {
-			http: reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
-				.with(cache())
-				.build(),
+			http: cache.client(http.clone()),
+			client: http,
+			cache,
+			fetch_policy: FetchPolicy::default(),
+			check_sliding_sync_proxy: false,
+			check_integrations: false,
+			check_cors: false,
+			#[cfg(feature = "overrides")]
+			overrides: Overrides::default(),
 		}
 	}
 
-	/// Construct a new resolver with the given reqwest client.
+	/// Construct a new resolver with a reqwest client built with the given
+	/// settings. Redirects are never followed by the client itself, see
+	/// [`FetchPolicy`].
+	pub fn with_builder(http: reqwest::ClientBuilder) -> Result<Self, reqwest::Error> {
+		Ok(Self::with(fetch::configure(http).build()?))
+	}
+
+	/// Sets the limits for fetching the .well-known information and versions.
 	#[must_use]
-	pub fn with(http: reqwest::Client) -> Self {
-		Self { http: reqwest_middleware::ClientBuilder::new(http).with(cache()).build() }
+	pub fn with_fetch_policy(mut self, policy: FetchPolicy) -> Self {
+		self.fetch_policy = policy;
+		self
 	}
 
-	/// Get the base URL for the client-server API with the given name.
+	/// Sets the cache of HTTP responses, which may be shared with other
+	/// resolvers.
+	#[must_use]
+	pub fn with_cache(mut self, cache: SharedCache) -> Self {
+		self.http = cache.client(self.client.clone());
+		self.cache = cache;
+		self
+	}
+
+	/// Sets static overrides, which take precedence over the well-known
+	/// information. The versions of an overridden homeserver are still
+	/// fetched, but failing to do so doesn't fail discovery.
+	#[cfg(feature = "overrides")]
+	#[must_use]
+	pub fn with_overrides(mut self, overrides: Overrides) -> Self {
+		self.overrides = overrides;
+		self
+	}
+
+	/// The cache of HTTP responses, for inspecting and purging its entries.
+	#[must_use]
+	pub fn cache(&self) -> &SharedCache {
+		&self.cache
+	}
+
+	/// Sets whether discovery checks that an advertised sliding sync proxy is
+	/// reachable.
+	#[must_use]
+	pub fn with_sliding_sync_check(mut self, check: bool) -> Self {
+		self.check_sliding_sync_proxy = check;
+		self
+	}
+
+	/// Sets whether discovery checks that the advertised tile server and
+	/// integration managers are reachable.
+	#[must_use]
+	pub fn with_integrations_check(mut self, check: bool) -> Self {
+		self.check_integrations = check;
+		self
+	}
+
+	/// Sets whether discovery checks the CORS headers of the well-known and
+	/// versions responses, which web clients need to read them.
+	#[must_use]
+	pub fn with_cors_check(mut self, check: bool) -> Self {
+		self.check_cors = check;
+		self
+	}
+
+	/// Get the base URL for the client-server API with the given name. Unlike
+	/// [`discover`](Self::discover), this doesn't fetch the versions of a
+	/// homeserver without well-known information.
 	pub async fn resolve(&self, name: &str) -> Result<Url, Error> {
+		Ok(self.lookup(name, false).await?.homeserver)
+	}
+
+	/// Discover the homeserver for the given name, together with the spec
+	/// versions and features it supports.
+	pub async fn discover(&self, name: &str) -> Result<Discovery, Error> {
+		self.lookup(name, true).await
+	}
+
+	/// Discover the homeserver for the given name. The versions of a
+	/// homeserver without well-known information are only fetched if
+	/// `probe` is set.
+	async fn lookup(&self, name: &str, probe: bool) -> Result<Discovery, Error> {
+		#[cfg(feature = "overrides")]
+		if let Some((pattern, homeserver)) = self.overrides.homeserver(name).await {
+			info!("The server name matches the override {}", pattern);
+			return Ok(self.fallback(homeserver, false, probe).await);
+		}
 		#[cfg(not(test))]
 		let url = Url::parse(&format!("https://{}", name))?;
 		#[cfg(test)]
 		let url = Url::parse(&format!("http://{}", name))?;
 
 		// 3. make a GET request to the well-known endpoint
-		let response = self.http.get(url.join(".well-known/matrix/client")?).send().await?;
+		let response = self.get_cors(url.join(".well-known/matrix/client")?).await?;
+		let mut cors_warnings = Vec::new();
+		self.check_cors(&response, &mut cors_warnings);
 		// a. if the returned status code is 404, then IGNORE
 		if response.status() == StatusCode::NOT_FOUND {
-			return Ok(url);
+			let mut discovery = self.fallback(url, true, probe).await;
+			cors_warnings.append(&mut discovery.cors_warnings);
+			discovery.cors_warnings = cors_warnings;
+			return Ok(discovery);
 		};
 		// c. parse the response as json
-		let well_known = response.json::<ClientWellKnown>().await?;
+		let well_known = response.json::<ClientWellKnown>()?;
+		let mut warnings = Vec::new();
+		warning::invalid_keys(
+			&response.json::<serde_json::Value>()?,
+			&[
+				("org.matrix.msc3575.proxy", well_known.sliding_sync_proxy.is_some()),
+				(integrations::TILE_SERVER, well_known.tile_server.is_some()),
+				(integrations::UNSTABLE_TILE_SERVER, well_known.tile_server.is_some()),
+				(integrations::INTEGRATIONS, well_known.integrations.is_some()),
+			],
+			&mut warnings,
+		);
 		// d+e.i Extract base_url and parse it as a URL
 		let url = Url::parse(&well_known.homeserver.base_url)?;
 		// e.ii Validate versions endpoint
-		self.http
-			.get(url.join("_matrix/client/versions")?)
-			.send()
-			.await
-			.map_err(FailError::Http)?
-			.json::<Versions>()
-			.await
-			.map_err(|e| FailError::Http(e.into()))?;
+		let versions = self.versions(&url, &mut cors_warnings).await?;
 
 		// f. if present, validate identity server endpoint
-		if let Some(identity) = well_known.identity_server {
-			let url = Url::parse(&identity.base_url)?;
-			let result: Result<_, FailError> = async {
-				self.http
-					.get(url.join("_matrix/identity/api/v1")?)
-					.send()
-					.await?
-					.error_for_status()?;
-				Ok(())
+		let identity_server = match &well_known.identity_server {
+			Some(identity) => {
+				let url = Url::parse(&identity.base_url)?;
+				let result: Result<_, FailError> = async {
+					let api = url.join("_matrix/identity/api/v1")?;
+					let response = fetch::get(&self.http, api, &self.fetch_policy).await?;
+					if !response.status().is_success() {
+						return Err(fetch::Error::Status(response.status()).into());
+					}
+					Ok(())
+				}
+				.await;
+				result?;
+				Some(url)
 			}
-			.await;
-			result?;
+			None => None,
+		};
+
+		let sliding_sync =
+			self.sliding_sync(&url, Some(&well_known), Some(&versions), &mut warnings).await;
+		let tile_server = self.tile_server(&well_known, &mut warnings).await;
+		let integration_managers = self.integration_managers(&well_known, &mut warnings).await;
+		Ok(Discovery {
+			homeserver: url,
+			well_known_missing: false,
+			identity_server,
+			versions: Some(versions),
+			sliding_sync,
+			tile_server,
+			integration_managers,
+			cors_warnings,
+			warnings,
+		})
+	}
+
+	/// Discovery for a homeserver without well-known information, which is
+	/// missing rather than overridden if `missing` is set. If `probe` is set,
+	/// the versions are fetched on a best-effort basis.
+	async fn fallback(&self, homeserver: Url, missing: bool, probe: bool) -> Discovery {
+		let mut cors_warnings = Vec::new();
+		let mut warnings = Vec::new();
+		let (versions, sliding_sync) = if probe {
+			let versions = self.versions(&homeserver, &mut cors_warnings).await.ok();
+			let sliding_sync =
+				self.sliding_sync(&homeserver, None, versions.as_ref(), &mut warnings).await;
+			(versions, sliding_sync)
+		} else {
+			(None, None)
+		};
+		Discovery {
+			homeserver,
+			well_known_missing: missing,
+			identity_server: None,
+			versions,
+			sliding_sync,
+			tile_server: None,
+			integration_managers: Vec::new(),
+			cors_warnings,
+			warnings,
 		}
+	}
+
+	/// Fetch the spec versions supported by the homeserver at the given base
+	/// URL, adding a warning if web clients can't read them.
+	async fn versions(
+		&self,
+		base_url: &Url,
+		cors_warnings: &mut Vec<CorsWarning>,
+	) -> Result<Versions, FailError> {
+		let response = self.get_cors(base_url.join("_matrix/client/versions")?).await?;
+		self.check_cors(&response, cors_warnings);
+		Ok(response.json::<Versions>()?)
+	}
+}
+
+#[async_trait::async_trait]
+impl ClientDiscover for Resolver {
+	async fn discover_homeserver(&self, name: &str) -> Result<Discovery, Error> {
+		Resolver::discover(self, name).await
+	}
 
-		Ok(url)
+	async fn resolve_homeserver(&self, name: &str) -> Result<Url, Error> {
+		Resolver::resolve(self, name).await
 	}
 }
 
 impl Default for Resolver {
 	fn default() -> Self {
-		Self { http: ClientWithMiddleware::from(reqwest::Client::new()) }
+		Self::new()
 	}
 }
 
@@ -133,7 +402,7 @@ mod tests {
 		Mock, MockServer, ResponseTemplate,
 	};
 
-	use super::Resolver;
+	use super::{versions::SpecVersion, Resolver};
 
 	/// Tests that a 404 response is correctly handled
 	#[tokio::test]
@@ -146,6 +415,12 @@ mod tests {
 			.expect(1)
 			.mount(&mock_server)
 			.await;
+		Mock::given(method("GET"))
+			.and(path("/_matrix/client/versions"))
+			.respond_with(ResponseTemplate::new(200))
+			.expect(0)
+			.mount(&mock_server)
+			.await;
 
 		let http =
 			reqwest::Client::builder().resolve("example.test", *mock_server.address()).build()?;
@@ -191,9 +466,8 @@ mod tests {
 
 		let http = reqwest::Client::builder()
 			.resolve("example.test", *mock_server.address())
-			.resolve("destination.test", *mock_server.address())
-			.build()?;
-		let resolver = Resolver::with(http);
+			.resolve("destination.test", *mock_server.address());
+		let resolver = Resolver::with_builder(http)?;
 
 		let url =
 			resolver.resolve(&format!("example.test:{}", mock_server.address().port())).await?;
@@ -201,4 +475,90 @@ mod tests {
 		assert_eq!(url.to_string(), format!("http://destination.test:{}/", port));
 		Ok(())
 	}
+
+	/// Validates the versions and identity server of a discovery result.
+	#[tokio::test]
+	async fn discover() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+		let port = mock_server.address().port();
+
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/client"))
+			.respond_with(ResponseTemplate::new(200).set_body_raw(
+				format!(
+					r#"{{"m.homeserver": {{"base_url": "http://destination.test:{0}"}}, "m.identity_server": {{"base_url": "http://identity.test:{0}"}} }}"#,
+					port
+				),
+				"application/json",
+			))
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/_matrix/client/versions"))
+			.respond_with(ResponseTemplate::new(200).set_body_raw(
+				r#"{"versions": ["r0.6.1", "v1.11", "v1.2"], "unstable_features": {"org.matrix.msc3575": true}}"#,
+				"application/json",
+			))
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/_matrix/identity/api/v1"))
+			.respond_with(ResponseTemplate::new(200).set_body_raw("{}", "application/json"))
+			.expect(1)
+			.mount(&mock_server)
+			.await;
+
+		let http = reqwest::Client::builder()
+			.resolve("example.test", *mock_server.address())
+			.resolve("destination.test", *mock_server.address())
+			.resolve("identity.test", *mock_server.address());
+		let resolver = Resolver::with_builder(http)?;
+
+		let discovery = resolver.discover(&format!("example.test:{}", port)).await?;
+		assert_eq!(discovery.homeserver.as_str(), format!("http://destination.test:{}/", port));
+		assert_eq!(
+			discovery.identity_server.map(String::from),
+			Some(format!("http://identity.test:{}/", port))
+		);
+		let versions = discovery.versions.ok_or("missing versions")?;
+		assert_eq!(versions.latest(), Some(SpecVersion::Stable(1, 11)));
+		assert!(versions.unstable_feature("org.matrix.msc3575"));
+		Ok(())
+	}
+
+	/// Validates that an overridden homeserver skips the well-known lookup.
+	#[cfg(feature = "overrides")]
+	#[tokio::test]
+	async fn overrides() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+		let port = mock_server.address().port();
+
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/client"))
+			.respond_with(ResponseTemplate::new(500))
+			.expect(0)
+			.mount(&mock_server)
+			.await;
+
+		Mock::given(method("GET"))
+			.and(path("/_matrix/client/versions"))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.set_body_raw(r#"{"versions":["r0.0.1"]}"#, "application/json"),
+			)
+			.expect(1)
+			.mount(&mock_server)
+			.await;
+
+		let http = reqwest::Client::builder().resolve("destination.test", *mock_server.address());
+		let homeserver = reqwest::Url::parse(&format!("http://destination.test:{}/", port))?;
+		let overrides =
+			crate::overrides::Overrides::new().with_homeserver("example.test", homeserver.clone());
+		let resolver = Resolver::with_builder(http)?.with_overrides(overrides);
+
+		let discovery = resolver.discover("example.test").await?;
+		assert_eq!(discovery.homeserver, homeserver);
+		assert!(discovery.versions.is_some());
+		Ok(())
+	}
 }
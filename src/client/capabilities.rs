@@ -0,0 +1,331 @@
+//! Probing the unauthenticated capabilities of a discovered homeserver.
+
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument};
+
+use super::{error::FailError, Resolver};
+use crate::fetch;
+
+/// Login type for logging in with a password.
+const PASSWORD: &str = "m.login.password";
+/// Login type for logging in through single sign-on.
+const SSO: &str = "m.login.sso";
+
+/// What a homeserver supports for logging in and registering, as far as it can
+/// be determined without authentication.
+#[derive(Debug, Clone, Serialize)]
+pub struct HomeserverCapabilities {
+	/// The login flows from `/_matrix/client/v3/login`.
+	pub login_flows: Vec<LoginFlow>,
+	/// Whether registration is possible.
+	pub registration: Registration,
+	/// The OAuth 2.0 authorization server metadata, if the homeserver
+	/// delegates authentication to one.
+	pub auth_metadata: Option<AuthMetadata>,
+}
+
+/// A way to log in to a homeserver.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LoginFlow {
+	/// The login type, such as `m.login.password`.
+	#[serde(rename = "type")]
+	pub kind: String,
+	/// The identity providers of an `m.login.sso` flow.
+	#[serde(default)]
+	pub identity_providers: Vec<IdentityProvider>,
+	/// Whether an `m.login.token` flow can be used to generate login tokens.
+	#[serde(default)]
+	pub get_login_token: bool,
+	/// Whether an `m.login.sso` flow is provided for compatibility with a
+	/// delegated OpenID Connect provider.
+	#[serde(rename = "org.matrix.msc3824.delegated_oidc_compatibility", default)]
+	pub delegated_oidc_compatibility: bool,
+}
+
+/// An identity provider of an `m.login.sso` flow.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IdentityProvider {
+	/// Opaque identifier of the provider, used in the SSO redirect URL.
+	pub id: String,
+	/// Name of the provider to display to the user.
+	pub name: String,
+	/// Optional `mxc://` URI of an icon for the provider.
+	pub icon: Option<String>,
+	/// Optional brand of the provider, such as `github`.
+	pub brand: Option<String>,
+}
+
+/// Whether new accounts can be registered, determined by starting a
+/// registration with an empty body.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum Registration {
+	/// Registration is possible by completing any of the given flows of
+	/// authentication stages.
+	Enabled(Vec<Vec<String>>),
+	/// Registration is disabled.
+	Disabled,
+	/// The server responded with an unexpected status code, or without a
+	/// status code if the request or its response was invalid.
+	Unknown(Option<u16>),
+}
+
+/// OAuth 2.0 authorization server metadata from
+/// `/_matrix/client/v1/auth_metadata`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AuthMetadata {
+	/// The authorization server's issuer identifier.
+	pub issuer: String,
+	/// URL of the authorization endpoint.
+	pub authorization_endpoint: String,
+	/// URL of the token endpoint.
+	pub token_endpoint: String,
+	/// URL of the dynamic client registration endpoint.
+	pub registration_endpoint: Option<String>,
+	/// URL of the token revocation endpoint.
+	pub revocation_endpoint: Option<String>,
+	/// URL where users can manage their account.
+	pub account_management_uri: Option<String>,
+	/// Supported values of the `prompt` parameter, `create` indicating that
+	/// accounts can be registered.
+	#[serde(default)]
+	pub prompt_values_supported: Vec<String>,
+}
+
+/// Response body of `/_matrix/client/v3/login`.
+#[derive(Deserialize)]
+struct LoginFlows {
+	/// The supported login flows.
+	flows: Vec<LoginFlow>,
+}
+
+/// User-interactive authentication response of `/_matrix/client/v3/register`.
+#[derive(Deserialize)]
+struct RegisterFlows {
+	/// The flows of authentication stages which can complete registration.
+	#[serde(default)]
+	flows: Vec<RegisterFlow>,
+}
+
+/// A flow of user-interactive authentication stages.
+#[derive(Deserialize)]
+struct RegisterFlow {
+	/// The login types of the stages.
+	stages: Vec<String>,
+}
+
+impl HomeserverCapabilities {
+	/// Whether logging in with a password is supported.
+	#[must_use]
+	pub fn supports_password(&self) -> bool {
+		self.login_flows.iter().any(|flow| flow.kind == PASSWORD)
+	}
+
+	/// Whether logging in through single sign-on is supported.
+	#[must_use]
+	pub fn supports_sso(&self) -> bool {
+		self.login_flows.iter().any(|flow| flow.kind == SSO)
+	}
+
+	/// Whether authentication is delegated to an OAuth 2.0 authorization
+	/// server.
+	#[must_use]
+	pub fn supports_oauth(&self) -> bool {
+		self.auth_metadata.is_some()
+	}
+
+	/// The identity providers of all single sign-on flows.
+	pub fn identity_providers(&self) -> impl Iterator<Item = &IdentityProvider> {
+		self.login_flows
+			.iter()
+			.filter(|flow| flow.kind == SSO)
+			.flat_map(|flow| flow.identity_providers.iter())
+	}
+}
+
+impl Resolver {
+	/// Query the unauthenticated endpoints of the homeserver at the given
+	/// base URL, as returned by [`resolve`](Self::resolve), for the ways to
+	/// log in and register.
+	#[instrument(skip(self), fields(base_url = %base_url), err)]
+	pub async fn capabilities(&self, base_url: &Url) -> Result<HomeserverCapabilities, FailError> {
+		let response =
+			fetch::get(&self.http, base_url.join("_matrix/client/v3/login")?, &self.fetch_policy)
+				.await?;
+		if !response.status().is_success() {
+			return Err(fetch::Error::Status(response.status()).into());
+		}
+		let login_flows = response.json::<LoginFlows>()?.flows;
+
+		Ok(HomeserverCapabilities {
+			login_flows,
+			registration: self.registration(base_url).await,
+			auth_metadata: self.auth_metadata(base_url).await?,
+		})
+	}
+
+	/// Start a registration without any parameters to find out whether
+	/// registration is possible.
+	async fn registration(&self, base_url: &Url) -> Registration {
+		let Ok(url) = base_url.join("_matrix/client/v3/register") else {
+			return Registration::Unknown(None);
+		};
+		let response = match fetch::post_json(&self.http, url, "{}", &self.fetch_policy).await {
+			Ok(response) => response,
+			Err(e) => {
+				debug!("Starting a registration failed: {}", e);
+				return Registration::Unknown(None);
+			}
+		};
+		match response.status() {
+			StatusCode::UNAUTHORIZED => match response.json::<RegisterFlows>() {
+				Ok(flows) => {
+					Registration::Enabled(flows.flows.into_iter().map(|flow| flow.stages).collect())
+				}
+				Err(e) => {
+					debug!("Invalid registration response: {}", e);
+					Registration::Unknown(Some(StatusCode::UNAUTHORIZED.as_u16()))
+				}
+			},
+			StatusCode::FORBIDDEN => Registration::Disabled,
+			status => Registration::Unknown(Some(status.as_u16())),
+		}
+	}
+
+	/// Fetch the authorization server metadata, falling back to the unstable
+	/// endpoint of servers which don't support the stable one yet.
+	async fn auth_metadata(&self, base_url: &Url) -> Result<Option<AuthMetadata>, FailError> {
+		for path in [
+			"_matrix/client/v1/auth_metadata",
+			"_matrix/client/unstable/org.matrix.msc2965/auth_metadata",
+		]
+		.iter()
+		{
+			let response = fetch::get(&self.http, base_url.join(path)?, &self.fetch_policy).await?;
+			if response.status().is_success() {
+				return Ok(Some(response.json()?));
+			}
+			debug!("{} returned {}", path, response.status());
+		}
+		Ok(None)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use reqwest::Url;
+	use wiremock::{
+		matchers::{body_json_string, method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::Registration;
+	use crate::client::Resolver;
+
+	/// Mounts a JSON response on the mock server.
+	async fn mount(server: &MockServer, verb: &str, route: &str, status: u16, body: &str) {
+		Mock::given(method(verb))
+			.and(path(route))
+			.respond_with(
+				ResponseTemplate::new(status).set_body_raw(body.to_owned(), "application/json"),
+			)
+			.mount(server)
+			.await;
+	}
+
+	/// Validates the capabilities of a server with password and SSO login,
+	/// and of a server delegating authentication to an OAuth 2.0 server.
+	#[tokio::test]
+	async fn capabilities() -> Result<(), Box<dyn std::error::Error>> {
+		let classic = MockServer::start().await;
+		mount(
+			&classic,
+			"GET",
+			"/_matrix/client/v3/login",
+			200,
+			r#"{"flows": [
+				{"type": "m.login.password"},
+				{"type": "m.login.sso", "identity_providers": [{"id": "oidc-github", "name": "GitHub", "brand": "github"}]},
+				{"type": "m.login.token", "get_login_token": true}
+			]}"#,
+		)
+		.await;
+		Mock::given(method("POST"))
+			.and(path("/_matrix/client/v3/register"))
+			.and(body_json_string("{}"))
+			.respond_with(ResponseTemplate::new(401).set_body_raw(
+				r#"{"flows": [{"stages": ["m.login.recaptcha", "m.login.email.identity"]}], "params": {}, "session": "abc"}"#,
+				"application/json",
+			))
+			.expect(1)
+			.mount(&classic)
+			.await;
+
+		let resolver = Resolver::new();
+		let capabilities = resolver.capabilities(&Url::parse(&classic.uri())?).await?;
+		assert!(capabilities.supports_password());
+		assert!(capabilities.supports_sso());
+		assert!(!capabilities.supports_oauth());
+		let providers: Vec<_> =
+			capabilities.identity_providers().map(|provider| provider.id.as_str()).collect();
+		assert_eq!(providers, ["oidc-github"]);
+		assert!(capabilities.login_flows[2].get_login_token);
+		assert_eq!(
+			capabilities.registration,
+			Registration::Enabled(vec![vec![
+				String::from("m.login.recaptcha"),
+				String::from("m.login.email.identity")
+			]])
+		);
+
+		let delegated = MockServer::start().await;
+		mount(
+			&delegated,
+			"GET",
+			"/_matrix/client/v3/login",
+			200,
+			r#"{"flows": [{"type": "m.login.sso", "org.matrix.msc3824.delegated_oidc_compatibility": true}]}"#,
+		)
+		.await;
+		mount(
+			&delegated,
+			"POST",
+			"/_matrix/client/v3/register",
+			403,
+			r#"{"errcode": "M_FORBIDDEN", "error": "Registration is disabled"}"#,
+		)
+		.await;
+		mount(
+			&delegated,
+			"GET",
+			"/_matrix/client/unstable/org.matrix.msc2965/auth_metadata",
+			200,
+			r#"{
+				"issuer": "https://auth.example.test/",
+				"authorization_endpoint": "https://auth.example.test/authorize",
+				"token_endpoint": "https://auth.example.test/token",
+				"prompt_values_supported": ["create"]
+			}"#,
+		)
+		.await;
+
+		let capabilities = resolver.capabilities(&Url::parse(&delegated.uri())?).await?;
+		assert!(!capabilities.supports_password());
+		assert!(capabilities.login_flows[0].delegated_oidc_compatibility);
+		assert_eq!(capabilities.registration, Registration::Disabled);
+		let metadata = capabilities.auth_metadata.ok_or("missing auth metadata")?;
+		assert_eq!(metadata.issuer, "https://auth.example.test/");
+		assert_eq!(metadata.prompt_values_supported, ["create"]);
+
+		let broken = MockServer::start().await;
+		mount(&broken, "GET", "/_matrix/client/v3/login", 200, r#"{"flows": []}"#).await;
+		Mock::given(method("POST"))
+			.and(path("/_matrix/client/v3/register"))
+			.respond_with(ResponseTemplate::new(401).set_body_raw("<html></html>", "text/html"))
+			.mount(&broken)
+			.await;
+		let capabilities = resolver.capabilities(&Url::parse(&broken.uri())?).await?;
+		assert_eq!(capabilities.registration, Registration::Unknown(Some(401)));
+		Ok(())
+	}
+}
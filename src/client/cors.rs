@@ -0,0 +1,165 @@
+//! Checks of the CORS headers web clients need to discover the homeserver.
+
+use reqwest::{
+	header::{HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN},
+	Url,
+};
+use serde::Serialize;
+use tracing::debug;
+
+use super::Resolver;
+use crate::fetch;
+
+/// The origin sent like a web client would when the CORS headers are checked,
+/// as servers may only add them to requests with an `Origin` header.
+const WEB_ORIGIN: &str = "https://matrix-oracle.invalid";
+
+/// A response web clients can't read because of its CORS headers, although
+/// native clients can.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CorsWarning {
+	/// The URL of the response.
+	pub url: Url,
+	/// What is wrong with the headers.
+	pub problem: CorsProblem,
+}
+
+/// A problem with the `Access-Control-Allow-Origin` header, which the spec
+/// requires to be `*`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum CorsProblem {
+	/// The header is missing.
+	MissingAllowOrigin,
+	/// The header allows only the given origin.
+	AllowOrigin(String),
+	/// The header has several values, like `*, *`, which browsers reject. This
+	/// usually happens when both a reverse proxy and the homeserver add it.
+	MultipleAllowOrigin,
+}
+
+impl std::fmt::Display for CorsProblem {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::MissingAllowOrigin => {
+				write!(f, "the Access-Control-Allow-Origin header is missing")
+			}
+			Self::AllowOrigin(origin) => {
+				write!(f, "Access-Control-Allow-Origin allows only {:?} instead of *", origin)
+			}
+			Self::MultipleAllowOrigin => {
+				write!(f, "the Access-Control-Allow-Origin header has more than one value")
+			}
+		}
+	}
+}
+
+impl Resolver {
+	/// Fetches the URL, sending an `Origin` header if the CORS check is
+	/// enabled.
+	pub(super) async fn get_cors(&self, url: Url) -> Result<fetch::Response, fetch::Error> {
+		let mut headers = HeaderMap::new();
+		if self.check_cors {
+			headers.insert(ORIGIN, HeaderValue::from_static(WEB_ORIGIN));
+		}
+		fetch::get_with_headers(&self.http, url, headers, &self.fetch_policy).await
+	}
+
+	/// Checks the CORS headers of the response if the check is enabled,
+	/// adding a warning if web clients can't read it.
+	pub(super) fn check_cors(&self, response: &fetch::Response, warnings: &mut Vec<CorsWarning>) {
+		if !self.check_cors {
+			return;
+		}
+		let mut values = response.headers.get_all(ACCESS_CONTROL_ALLOW_ORIGIN).iter();
+		let problem = match (values.next(), values.next()) {
+			(Some(origin), None) if origin == "*" => return,
+			(Some(origin), None) if origin.as_bytes().contains(&b',') => {
+				CorsProblem::MultipleAllowOrigin
+			}
+			(Some(origin), None) => {
+				CorsProblem::AllowOrigin(String::from_utf8_lossy(origin.as_bytes()).into_owned())
+			}
+			(Some(_), Some(_)) => CorsProblem::MultipleAllowOrigin,
+			(None, _) => CorsProblem::MissingAllowOrigin,
+		};
+		debug!("Web clients can't read {}: {}", response.url(), problem);
+		warnings.push(CorsWarning { url: response.url().clone(), problem });
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use wiremock::{
+		matchers::{header, method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::CorsProblem;
+	use crate::client::Resolver;
+
+	/// Validates the warnings for the well-known and versions responses.
+	#[tokio::test]
+	async fn warnings() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+		let port = mock_server.address().port();
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/client"))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.set_body_raw(
+						format!(
+							r#"{{"m.homeserver": {{"base_url": "http://destination.test:{}"}} }}"#,
+							port
+						),
+						"application/json",
+					)
+					.insert_header("Access-Control-Allow-Origin", "*, *"),
+			)
+			.mount(&mock_server)
+			.await;
+		// The header is only added for requests from web clients
+		Mock::given(method("GET"))
+			.and(path("/_matrix/client/versions"))
+			.and(header("Origin", "https://matrix-oracle.invalid"))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.set_body_raw(r#"{"versions":["v1.11"]}"#, "application/json")
+					.insert_header("Access-Control-Allow-Origin", "https://app.example.test"),
+			)
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/_matrix/client/versions"))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.set_body_raw(r#"{"versions":["v1.11"]}"#, "application/json"),
+			)
+			.mount(&mock_server)
+			.await;
+		let resolver = Resolver::with_builder(
+			reqwest::Client::builder()
+				.resolve("example.test", *mock_server.address())
+				.resolve("destination.test", *mock_server.address()),
+		)?;
+		let name = format!("example.test:{}", port);
+
+		let discovery = resolver.discover(&name).await?;
+		assert!(discovery.cors_warnings.is_empty());
+
+		let discovery = resolver.with_cors_check(true).discover(&name).await?;
+		let problems: Vec<_> =
+			discovery.cors_warnings.iter().map(|warning| warning.problem.clone()).collect();
+		assert_eq!(
+			problems,
+			[
+				CorsProblem::MultipleAllowOrigin,
+				CorsProblem::AllowOrigin(String::from("https://app.example.test"))
+			]
+		);
+		assert_eq!(
+			discovery.cors_warnings[1].url.as_str(),
+			format!("http://destination.test:{}/_matrix/client/versions", port)
+		);
+		Ok(())
+	}
+}
@@ -1,13 +1,16 @@
 //! Errors that can occur during client-server lookup
 
+use crate::fetch;
+
 /// Errors that can occur during lookup. Refer to [the specification] to see how
 /// the two variants should be handled.
 ///
 /// [the spec]: https://matrix.org/docs/spec/client_server/latest#well-known-uri
 #[derive(Debug)]
 pub enum Error {
-	/// Corresponds to the `FAIL_PROMPT` code in the spec.
-	Prompt(reqwest_middleware::Error),
+	/// Corresponds to the `FAIL_PROMPT` code in the spec. HTTP errors are
+	/// wrapped in [`fetch::Error::Http`].
+	Prompt(fetch::Error),
 	/// Corresponds to the `FAIL_ERROR` code in the spec.
 	Fail(FailError),
 }
@@ -38,6 +41,12 @@ impl From<reqwest::Error> for Error {
 
 impl From<reqwest_middleware::Error> for Error {
 	fn from(e: reqwest_middleware::Error) -> Self {
+		Error::Prompt(e.into())
+	}
+}
+
+impl From<fetch::Error> for Error {
+	fn from(e: fetch::Error) -> Self {
 		Error::Prompt(e)
 	}
 }
@@ -61,6 +70,10 @@ pub enum FailError {
 	Url(url::ParseError),
 	/// HTTP error
 	Http(reqwest_middleware::Error),
+	/// Error while fetching a document that has to be validated
+	Fetch(fetch::Error),
+	/// A URL which does not use HTTP or HTTPS
+	Scheme(url::Url),
 }
 
 impl std::error::Error for FailError {
@@ -68,6 +81,8 @@ impl std::error::Error for FailError {
 		match *self {
 			Self::Http(ref e) => Some(e),
 			Self::Url(ref e) => Some(e),
+			Self::Fetch(ref e) => Some(e),
+			Self::Scheme(_) => None,
 		}
 	}
 }
@@ -77,6 +92,8 @@ impl std::fmt::Display for FailError {
 		match self {
 			Self::Http(e) => write!(f, "{}", e),
 			Self::Url(e) => write!(f, "{}", e),
+			Self::Fetch(e) => write!(f, "{}", e),
+			Self::Scheme(url) => write!(f, "{} does not use HTTP or HTTPS", url),
 		}
 	}
 }
@@ -98,3 +115,9 @@ impl From<url::ParseError> for FailError {
 		FailError::Url(e)
 	}
 }
+
+impl From<fetch::Error> for FailError {
+	fn from(e: fetch::Error) -> Self {
+		FailError::Fetch(e)
+	}
+}
@@ -0,0 +1,259 @@
+//! Discovery of the tile server and integration managers advertised in the
+//! well-known information.
+
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::{error::FailError, warning::Warning, ClientWellKnown, Resolver};
+use crate::fetch;
+
+/// Key of the tile server in the well-known information.
+pub(super) const TILE_SERVER: &str = "m.tile_server";
+/// Key of the unstable tile server in the well-known information.
+pub(super) const UNSTABLE_TILE_SERVER: &str = "org.matrix.msc3488.tile_server";
+/// Key of the integration managers in the well-known information.
+pub(super) const INTEGRATIONS: &str = "m.integrations";
+
+/// Information about the tile server for location sharing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TileServerInfo {
+	/// The URL of the map style to use.
+	pub map_style_url: String,
+}
+
+/// Information about the integration managers to offer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntegrationsInfo {
+	/// The integration managers in order of preference.
+	#[serde(default)]
+	pub managers: Vec<IntegrationManagerInfo>,
+}
+
+/// Information about an integration manager.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntegrationManagerInfo {
+	/// The base URL of the integration manager's API.
+	pub api_url: String,
+	/// The URL of the integration manager's user interface.
+	pub ui_url: String,
+}
+
+/// A validated tile server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TileServer {
+	/// The URL of the map style to use.
+	pub map_style_url: Url,
+	/// Whether the map style could be fetched, if this was checked.
+	pub reachable: Option<bool>,
+}
+
+/// A validated integration manager.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IntegrationManager {
+	/// The base URL of the integration manager's API.
+	pub api_url: Url,
+	/// The URL of the integration manager's user interface.
+	pub ui_url: Url,
+	/// Whether the API responded, if this was checked.
+	pub reachable: Option<bool>,
+}
+
+impl Resolver {
+	/// Validate the tile server in the well-known information, and check that
+	/// the map style can be fetched if enabled. An invalid URL is ignored
+	/// with a warning.
+	pub(super) async fn tile_server(
+		&self,
+		well_known: &ClientWellKnown,
+		warnings: &mut Vec<Warning>,
+	) -> Option<TileServer> {
+		let tile_server = well_known.tile_server.as_ref()?;
+		let map_style_url = valid_url(TILE_SERVER, &tile_server.map_style_url, warnings)?;
+		let reachable = match self.check_integrations {
+			true => Some(self.responds(&map_style_url, true).await),
+			false => None,
+		};
+		Some(TileServer { map_style_url, reachable })
+	}
+
+	/// Validate the integration managers in the well-known information, and
+	/// check that their APIs respond if enabled. Any HTTP response counts, as
+	/// the API requires authentication. Managers with an invalid URL are
+	/// ignored with a warning.
+	pub(super) async fn integration_managers(
+		&self,
+		well_known: &ClientWellKnown,
+		warnings: &mut Vec<Warning>,
+	) -> Vec<IntegrationManager> {
+		let Some(integrations) = &well_known.integrations else { return Vec::new() };
+		let mut managers = Vec::new();
+		for manager in &integrations.managers {
+			let api_url = valid_url(INTEGRATIONS, &manager.api_url, warnings);
+			let ui_url = valid_url(INTEGRATIONS, &manager.ui_url, warnings);
+			let (Some(api_url), Some(ui_url)) = (api_url, ui_url) else { continue };
+			let reachable = match self.check_integrations {
+				true => Some(self.responds(&api_url, false).await),
+				false => None,
+			};
+			managers.push(IntegrationManager { api_url, ui_url, reachable });
+		}
+		managers
+	}
+
+	/// Whether the URL responds, with a successful status if `success` is
+	/// set.
+	async fn responds(&self, url: &Url, success: bool) -> bool {
+		match fetch::get(&self.http, url.clone(), &self.fetch_policy).await {
+			Ok(response) => !success || response.status().is_success(),
+			Err(e) => {
+				debug!("Checking {} failed: {}", url, e);
+				false
+			}
+		}
+	}
+}
+
+/// Parse a URL found under the given key, adding a warning if it's not a
+/// valid HTTP or HTTPS URL.
+fn valid_url(key: &str, url: &str, warnings: &mut Vec<Warning>) -> Option<Url> {
+	let valid = http_url(url).ok();
+	if valid.is_none() {
+		warnings.push(Warning::InvalidUrl { key: key.to_owned(), url: url.to_owned() });
+	}
+	valid
+}
+
+/// Parse a URL, which has to use HTTP or HTTPS.
+pub(super) fn http_url(url: &str) -> Result<Url, FailError> {
+	let url = Url::parse(url)?;
+	match url.scheme() {
+		"http" | "https" => Ok(url),
+		_ => Err(FailError::Scheme(url)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use wiremock::{
+		matchers::{method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use crate::client::{warning::Warning, Resolver};
+
+	/// Mounts the client discovery endpoints with the given extra well-known
+	/// information.
+	async fn mount(server: &MockServer, extra: &str) {
+		let port = server.address().port();
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/client"))
+			.respond_with(ResponseTemplate::new(200).set_body_raw(
+				format!(
+					r#"{{"m.homeserver": {{"base_url": "http://destination.test:{}"}}, {}}}"#,
+					port, extra
+				),
+				"application/json",
+			))
+			.mount(server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/_matrix/client/versions"))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.set_body_raw(r#"{"versions": ["v1.11"]}"#, "application/json"),
+			)
+			.mount(server)
+			.await;
+	}
+
+	/// Constructs a resolver for the test hostnames at the mock server.
+	fn resolver(server: &MockServer) -> Result<Resolver, Box<dyn std::error::Error>> {
+		let http = reqwest::Client::builder()
+			.resolve("example.test", *server.address())
+			.resolve("destination.test", *server.address())
+			.resolve("tiles.test", *server.address())
+			.resolve("integrations.test", *server.address());
+		Ok(Resolver::with_builder(http)?)
+	}
+
+	/// Validates parsing and checking the tile server and integration
+	/// managers.
+	#[tokio::test]
+	async fn integrations() -> Result<(), Box<dyn std::error::Error>> {
+		let server = MockServer::start().await;
+		let port = server.address().port();
+		mount(
+			&server,
+			&format!(
+				r#""m.tile_server": {{"map_style_url": "http://tiles.test:{0}/style.json"}},
+				"m.integrations": {{"managers": [{{"api_url": "http://integrations.test:{0}/api", "ui_url": "http://integrations.test:{0}/ui"}}]}}"#,
+				port
+			),
+		)
+		.await;
+		Mock::given(method("GET"))
+			.and(path("/style.json"))
+			.respond_with(ResponseTemplate::new(200).set_body_raw("{}", "application/json"))
+			.expect(1)
+			.mount(&server)
+			.await;
+
+		let name = format!("example.test:{}", port);
+		let discovery = resolver(&server)?.discover(&name).await?;
+		let tile_server = discovery.tile_server.ok_or("missing tile server")?;
+		assert_eq!(
+			tile_server.map_style_url.as_str(),
+			format!("http://tiles.test:{}/style.json", port)
+		);
+		assert_eq!(discovery.integration_managers.len(), 1);
+		assert_eq!(
+			discovery.integration_managers[0].ui_url.as_str(),
+			format!("http://integrations.test:{}/ui", port)
+		);
+
+		assert_eq!(discovery.integration_managers[0].reachable, None);
+
+		let checked = resolver(&server)?.with_integrations_check(true).discover(&name).await?;
+		assert_eq!(checked.tile_server.and_then(|tile_server| tile_server.reachable), Some(true));
+		assert_eq!(checked.integration_managers[0].reachable, Some(true));
+
+		let invalid = MockServer::start().await;
+		mount(
+			&invalid,
+			r#""m.tile_server": {"map_style_url": "ftp://tiles.test/style.json"},
+			"m.integrations": {"managers": [{"api_url": "http://integrations.test/api", "ui_url": "ui"}]}"#,
+		)
+		.await;
+		let name = format!("example.test:{}", invalid.address().port());
+		let discovery = resolver(&invalid)?.discover(&name).await?;
+		assert!(discovery.tile_server.is_none());
+		assert!(discovery.integration_managers.is_empty());
+		assert_eq!(
+			discovery.warnings,
+			[
+				Warning::InvalidUrl {
+					key: String::from("m.tile_server"),
+					url: String::from("ftp://tiles.test/style.json")
+				},
+				Warning::InvalidUrl {
+					key: String::from("m.integrations"),
+					url: String::from("ui")
+				}
+			]
+		);
+
+		let broken = MockServer::start().await;
+		mount(
+			&broken,
+			r#""m.tile_server": "tiles.test", "m.integrations": {"managers": [{"api_url": "http://127.0.0.1:1/api", "ui_url": "http://127.0.0.1:1/ui"}]}"#,
+		)
+		.await;
+		let name = format!("example.test:{}", broken.address().port());
+		let checked = resolver(&broken)?.with_integrations_check(true).discover(&name).await?;
+		assert!(checked.tile_server.is_none());
+		assert_eq!(checked.warnings, [Warning::InvalidKey(String::from("m.tile_server"))]);
+		assert_eq!(checked.integration_managers[0].reachable, Some(false));
+		Ok(())
+	}
+}
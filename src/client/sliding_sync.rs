@@ -0,0 +1,203 @@
+//! Discovery of the sliding sync mechanism to use with a homeserver.
+
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::{
+	integrations::http_url, versions::Versions, warning::Warning, ClientWellKnown, Resolver,
+};
+use crate::fetch;
+
+/// Unstable feature advertised by homeservers supporting simplified sliding
+/// sync natively.
+pub const SIMPLIFIED_SLIDING_SYNC: &str = "org.matrix.simplified_msc3575";
+
+/// Information about a sliding sync proxy in the well-known information.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlidingSyncProxyInfo {
+	/// The URL of the sliding sync proxy.
+	pub url: String,
+}
+
+/// How sliding sync is provided.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SlidingSyncMechanism {
+	/// The homeserver supports simplified sliding sync itself.
+	Native,
+	/// A sliding sync proxy in front of the homeserver.
+	Proxy,
+}
+
+/// The sliding sync mechanism to use with a homeserver.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SlidingSync {
+	/// How sliding sync is provided.
+	pub mechanism: SlidingSyncMechanism,
+	/// The base URL to send sliding sync requests to.
+	pub url: Url,
+	/// Whether the proxy responded without a server error, if this was
+	/// checked.
+	pub reachable: Option<bool>,
+}
+
+impl Resolver {
+	/// Choose the sliding sync mechanism, preferring native support of the
+	/// homeserver over a proxy advertised in the well-known information. An
+	/// invalid proxy URL is ignored with a warning.
+	pub(super) async fn sliding_sync(
+		&self,
+		homeserver: &Url,
+		well_known: Option<&ClientWellKnown>,
+		versions: Option<&Versions>,
+		warnings: &mut Vec<Warning>,
+	) -> Option<SlidingSync> {
+		if versions.is_some_and(|versions| versions.unstable_feature(SIMPLIFIED_SLIDING_SYNC)) {
+			return Some(SlidingSync {
+				mechanism: SlidingSyncMechanism::Native,
+				url: homeserver.clone(),
+				reachable: None,
+			});
+		}
+
+		let proxy = well_known?.sliding_sync_proxy.as_ref()?;
+		let Ok(url) = http_url(&proxy.url) else {
+			warnings.push(Warning::InvalidUrl {
+				key: String::from("org.matrix.msc3575.proxy"),
+				url: proxy.url.clone(),
+			});
+			return None;
+		};
+		let reachable = if self.check_sliding_sync_proxy {
+			Some(match fetch::get(&self.http, url.clone(), &self.fetch_policy).await {
+				Ok(response) => !response.status().is_server_error(),
+				Err(e) => {
+					debug!("Checking the sliding sync proxy failed: {}", e);
+					false
+				}
+			})
+		} else {
+			None
+		};
+		Some(SlidingSync { mechanism: SlidingSyncMechanism::Proxy, url, reachable })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use wiremock::{
+		matchers::{method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::SlidingSyncMechanism;
+	use crate::client::{warning::Warning, Resolver};
+
+	/// Mounts the client discovery endpoints on the mock server.
+	async fn mount(server: &MockServer, unstable_features: &str) {
+		let port = server.address().port();
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/client"))
+			.respond_with(ResponseTemplate::new(200).set_body_raw(
+				format!(
+					r#"{{"m.homeserver": {{"base_url": "http://destination.test:{0}"}}, "org.matrix.msc3575.proxy": {{"url": "http://proxy.test:{0}"}} }}"#,
+					port
+				),
+				"application/json",
+			))
+			.mount(server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/_matrix/client/versions"))
+			.respond_with(ResponseTemplate::new(200).set_body_raw(
+				format!(r#"{{"versions": ["v1.11"], "unstable_features": {}}}"#, unstable_features),
+				"application/json",
+			))
+			.mount(server)
+			.await;
+	}
+
+	/// Constructs a resolver for the test hostnames at the mock server.
+	fn resolver(server: &MockServer) -> Result<Resolver, Box<dyn std::error::Error>> {
+		let http = reqwest::Client::builder()
+			.resolve("example.test", *server.address())
+			.resolve("destination.test", *server.address())
+			.resolve("proxy.test", *server.address());
+		Ok(Resolver::with_builder(http)?)
+	}
+
+	/// Validates choosing native sliding sync over the proxy, and checking
+	/// the proxy.
+	#[tokio::test]
+	async fn mechanism() -> Result<(), Box<dyn std::error::Error>> {
+		let native = MockServer::start().await;
+		mount(&native, r#"{"org.matrix.simplified_msc3575": true}"#).await;
+		let name = format!("example.test:{}", native.address().port());
+		let discovery = resolver(&native)?.discover(&name).await?;
+		let sliding_sync = discovery.sliding_sync.ok_or("missing sliding sync")?;
+		assert_eq!(sliding_sync.mechanism, SlidingSyncMechanism::Native);
+		assert_eq!(sliding_sync.url, discovery.homeserver);
+
+		let proxy = MockServer::start().await;
+		mount(&proxy, r#"{"org.matrix.simplified_msc3575": false}"#).await;
+		let name = format!("example.test:{}", proxy.address().port());
+		let discovery = resolver(&proxy)?.discover(&name).await?;
+		let sliding_sync = discovery.sliding_sync.ok_or("missing sliding sync")?;
+		assert_eq!(sliding_sync.mechanism, SlidingSyncMechanism::Proxy);
+		assert_eq!(
+			sliding_sync.url.as_str(),
+			format!("http://proxy.test:{}/", proxy.address().port())
+		);
+		assert_eq!(sliding_sync.reachable, None);
+
+		let checked = resolver(&proxy)?.with_sliding_sync_check(true).discover(&name).await?;
+		assert_eq!(
+			checked.sliding_sync.and_then(|sliding_sync| sliding_sync.reachable),
+			Some(true)
+		);
+
+		let failing = MockServer::start().await;
+		mount(&failing, "{}").await;
+		Mock::given(method("GET"))
+			.and(path("/"))
+			.respond_with(ResponseTemplate::new(502))
+			.mount(&failing)
+			.await;
+		let name = format!("example.test:{}", failing.address().port());
+		let checked = resolver(&failing)?.with_sliding_sync_check(true).discover(&name).await?;
+		assert_eq!(
+			checked.sliding_sync.and_then(|sliding_sync| sliding_sync.reachable),
+			Some(false)
+		);
+
+		let key = String::from("org.matrix.msc3575.proxy");
+		let cases = [
+			(
+				r#"{"url": "proxy.test"}"#,
+				Warning::InvalidUrl { key: key.clone(), url: String::from("proxy.test") },
+			),
+			(r#""proxy.test""#, Warning::InvalidKey(key)),
+		];
+		for (proxy, warning) in cases {
+			let invalid = MockServer::start().await;
+			Mock::given(method("GET"))
+				.and(path("/.well-known/matrix/client"))
+				.respond_with(ResponseTemplate::new(200).set_body_raw(
+					format!(
+						r#"{{"m.homeserver": {{"base_url": "http://destination.test:{}"}}, "org.matrix.msc3575.proxy": {} }}"#,
+						invalid.address().port(),
+						proxy
+					),
+					"application/json",
+				))
+				.mount(&invalid)
+				.await;
+			mount(&invalid, "{}").await;
+			let name = format!("example.test:{}", invalid.address().port());
+			let discovery = resolver(&invalid)?.discover(&name).await?;
+			assert!(discovery.sliding_sync.is_none(), "{}", proxy);
+			assert_eq!(discovery.warnings, [warning]);
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,165 @@
+//! Spec versions and unstable features supported by a homeserver.
+
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// A version of the client-server API specification.
+///
+/// Versions are ordered by release, so all legacy `r0` releases come before
+/// `v1.1`, and `v1.11` comes after `v1.2`. The variants must stay in release
+/// order for this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SpecVersion {
+	/// A release of the form `r0.6.1`, which predates `v1.1`.
+	Legacy(u32, u32, u32),
+	/// A release of the form `v1.11`.
+	Stable(u32, u32),
+}
+
+/// The error returned when a spec version can't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseVersionError(String);
+
+impl fmt::Display for ParseVersionError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:?} is not a spec version", self.0)
+	}
+}
+
+impl std::error::Error for ParseVersionError {}
+
+impl FromStr for SpecVersion {
+	type Err = ParseVersionError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let error = || ParseVersionError(s.to_owned());
+		let parts = s
+			.get(1..)
+			.ok_or_else(error)?
+			.split('.')
+			.map(str::parse)
+			.collect::<Result<Vec<u32>, _>>()
+			.map_err(|_| error())?;
+		match (s.as_bytes().first(), &parts[..]) {
+			(Some(b'r'), [major, minor, patch]) => Ok(Self::Legacy(*major, *minor, *patch)),
+			(Some(b'v'), [major, minor]) => Ok(Self::Stable(*major, *minor)),
+			_ => Err(error()),
+		}
+	}
+}
+
+impl fmt::Display for SpecVersion {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Legacy(major, minor, patch) => write!(f, "r{}.{}.{}", major, minor, patch),
+			Self::Stable(major, minor) => write!(f, "v{}.{}", major, minor),
+		}
+	}
+}
+
+impl Serialize for SpecVersion {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_str(self)
+	}
+}
+
+impl<'de> Deserialize<'de> for SpecVersion {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let s = String::deserialize(deserializer)?;
+		s.parse().map_err(serde::de::Error::custom)
+	}
+}
+
+/// The response of `/_matrix/client/versions`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Versions {
+	/// The spec versions the server supports, as sent by the server.
+	pub versions: Vec<String>,
+	/// Unstable features and whether the server has them enabled.
+	#[serde(default)]
+	pub unstable_features: BTreeMap<String, bool>,
+}
+
+impl Versions {
+	/// The spec versions the server supports in ascending order. Versions
+	/// which can't be parsed are left out.
+	#[must_use]
+	pub fn spec_versions(&self) -> Vec<SpecVersion> {
+		let mut versions: Vec<SpecVersion> =
+			self.versions.iter().filter_map(|version| version.parse().ok()).collect();
+		versions.sort();
+		versions.dedup();
+		versions
+	}
+
+	/// The latest spec version the server supports.
+	#[must_use]
+	pub fn latest(&self) -> Option<SpecVersion> {
+		self.spec_versions().pop()
+	}
+
+	/// Whether the server supports the given spec version.
+	#[must_use]
+	pub fn supports(&self, version: SpecVersion) -> bool {
+		self.spec_versions().contains(&version)
+	}
+
+	/// The highest spec version supported by both the server and the caller.
+	#[must_use]
+	pub fn highest_common(&self, supported: &[SpecVersion]) -> Option<SpecVersion> {
+		let versions = self.spec_versions();
+		supported.iter().filter(|version| versions.contains(version)).max().copied()
+	}
+
+	/// Whether the server has the given unstable feature enabled.
+	#[must_use]
+	pub fn unstable_feature(&self, feature: &str) -> bool {
+		self.unstable_features.get(feature).copied().unwrap_or(false)
+	}
+
+	/// The unstable features the server has enabled.
+	pub fn enabled_unstable_features(&self) -> impl Iterator<Item = &str> {
+		self.unstable_features
+			.iter()
+			.filter(|(_, enabled)| **enabled)
+			.map(|(feature, _)| feature.as_str())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{SpecVersion, Versions};
+
+	/// Validates parsing, ordering and negotiation of spec versions.
+	#[test]
+	fn negotiate() -> Result<(), Box<dyn std::error::Error>> {
+		let versions: Versions = serde_json::from_str(
+			r#"{
+				"versions": ["v1.11", "r0.6.1", "v1.2", "v1.1", "r0.5.0", "unknown"],
+				"unstable_features": {"org.matrix.msc3575": true, "org.matrix.msc2965": false}
+			}"#,
+		)?;
+		let parsed: Vec<String> =
+			versions.spec_versions().iter().map(ToString::to_string).collect();
+		assert_eq!(parsed, ["r0.5.0", "r0.6.1", "v1.1", "v1.2", "v1.11"]);
+		assert_eq!(versions.latest(), Some(SpecVersion::Stable(1, 11)));
+		assert!(versions.supports("r0.6.1".parse()?));
+		assert!(!versions.supports("v1.3".parse()?));
+		assert_eq!(
+			versions.highest_common(&["v1.3".parse()?, "v1.2".parse()?, "r0.6.1".parse()?]),
+			Some(SpecVersion::Stable(1, 2))
+		);
+		assert_eq!(versions.highest_common(&[SpecVersion::Stable(1, 4)]), None);
+		assert!(versions.unstable_feature("org.matrix.msc3575"));
+		assert!(!versions.unstable_feature("org.matrix.msc2965"));
+		assert!(!versions.unstable_feature("org.matrix.msc0000"));
+		assert_eq!(
+			versions.enabled_unstable_features().collect::<Vec<_>>(),
+			["org.matrix.msc3575"]
+		);
+		assert!("v1".parse::<SpecVersion>().is_err());
+		assert!("r0.6".parse::<SpecVersion>().is_err());
+		Ok(())
+	}
+}
@@ -0,0 +1,57 @@
+//! Problems with optional parts of the well-known information, which are
+//! ignored instead of failing discovery.
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// A problem with an optional part of the well-known information.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum Warning {
+	/// The value of the given key doesn't have the expected shape, so the key
+	/// was ignored.
+	InvalidKey(String),
+	/// The URL under the given key is not a valid HTTP or HTTPS URL, so it
+	/// was ignored.
+	InvalidUrl {
+		/// The key the URL is found under.
+		key: String,
+		/// The invalid URL.
+		url: String,
+	},
+}
+
+impl std::fmt::Display for Warning {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::InvalidKey(key) => write!(f, "the value of {} is invalid", key),
+			Self::InvalidUrl { key, url } => {
+				write!(f, "the URL {:?} of {} is not a valid HTTP URL", url, key)
+			}
+		}
+	}
+}
+
+/// Deserialize an optional key of the well-known information, treating a
+/// value of the wrong shape like a missing key. [`invalid_keys`] reports
+/// these values.
+pub(crate) fn lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+	D: Deserializer<'de>,
+	T: Deserialize<'de>,
+{
+	let value = serde_json::Value::deserialize(deserializer)?;
+	Ok(T::deserialize(value).ok())
+}
+
+/// The warnings for the given keys, which are present in the raw well-known
+/// information but were ignored when deserializing it with [`lenient`].
+pub(super) fn invalid_keys(
+	raw: &serde_json::Value,
+	keys: &[(&str, bool)],
+	warnings: &mut Vec<Warning>,
+) {
+	for (key, parsed) in keys {
+		if !parsed && raw.get(key).is_some_and(|value| !value.is_null()) {
+			warnings.push(Warning::InvalidKey((*key).to_owned()));
+		}
+	}
+}
@@ -0,0 +1,580 @@
+//! Hardened fetching of .well-known documents and related endpoints, shared by
+//! the client-server and server-server resolvers.
+
+use std::{
+	collections::HashSet,
+	convert::{Infallible, TryFrom},
+	future::Future,
+	net::SocketAddr,
+	ops::ControlFlow,
+};
+
+use reqwest::{
+	header::{HeaderMap, HeaderValue, CONTENT_TYPE, LOCATION},
+	redirect, Request, ResponseBuilderExt, StatusCode, Url,
+};
+use reqwest_middleware::{ClientWithMiddleware, Middleware, Next};
+use serde::de::DeserializeOwned;
+use task_local_extensions::Extensions;
+
+use crate::cache;
+
+/// Limits applied when fetching documents.
+///
+/// Redirects are followed by the resolvers themselves, so they disable the
+/// redirect policy of the reqwest clients they build. Clients passed to them
+/// must not follow redirects on their own, see [`redirect::Policy::none`].
+/// Responses of clients which do are rejected with [`Error::ClientRedirect`],
+/// as the redirects bypassed the checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FetchPolicy {
+	/// Maximum size of a response body in bytes.
+	pub max_body_size: usize,
+	/// Maximum number of redirects to follow.
+	pub max_redirects: usize,
+}
+
+impl Default for FetchPolicy {
+	fn default() -> Self {
+		// Synapse uses the same size limit for .well-known responses
+		Self { max_body_size: 50 * 1024, max_redirects: 5 }
+	}
+}
+
+/// Errors that can occur when fetching a document.
+#[derive(Debug)]
+pub enum Error {
+	/// An error happened while sending the request or receiving the response.
+	Http(reqwest_middleware::Error),
+	/// The response body exceeded the size limit in bytes.
+	TooLarge(usize),
+	/// More redirects than the given limit were returned.
+	TooManyRedirects(usize),
+	/// A redirect pointed to a URL that had already been requested.
+	RedirectLoop(Url),
+	/// A redirect pointed from HTTPS to the given HTTP URL.
+	Downgrade(Url),
+	/// A redirect had a missing, invalid or non-HTTP location.
+	InvalidRedirect(String),
+	/// The HTTP client followed a redirect to the given URL on its own.
+	ClientRedirect(Url),
+	/// The response had a content type which can't contain JSON.
+	ContentType(String),
+	/// The response body was not valid JSON of the expected shape.
+	Json(serde_json::Error),
+	/// The response had an unexpected status code.
+	Status(StatusCode),
+}
+
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Http(e) => write!(f, "{}", e),
+			Self::TooLarge(limit) => write!(f, "response body exceeds {} bytes", limit),
+			Self::TooManyRedirects(limit) => write!(f, "more than {} redirects", limit),
+			Self::RedirectLoop(url) => write!(f, "redirect loop at {}", url),
+			Self::Downgrade(url) => write!(f, "redirect from HTTPS to {}", url),
+			Self::InvalidRedirect(location) => write!(f, "invalid redirect to {:?}", location),
+			Self::ClientRedirect(url) => {
+				write!(f, "the HTTP client followed a redirect to {} on its own", url)
+			}
+			Self::ContentType(content_type) => {
+				write!(f, "unexpected content type {}", content_type)
+			}
+			Self::Json(e) => write!(f, "{}", e),
+			Self::Status(status) => write!(f, "unexpected status code {}", status),
+		}
+	}
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match *self {
+			Self::Http(ref e) => Some(e),
+			Self::Json(ref e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl From<reqwest::Error> for Error {
+	fn from(e: reqwest::Error) -> Self {
+		Self::Http(e.into())
+	}
+}
+
+impl From<reqwest_middleware::Error> for Error {
+	fn from(e: reqwest_middleware::Error) -> Self {
+		Self::Http(e)
+	}
+}
+
+impl From<serde_json::Error> for Error {
+	fn from(e: serde_json::Error) -> Self {
+		Self::Json(e)
+	}
+}
+
+/// Middleware enforcing the body size limit of a request, which has to run
+/// after the caching middleware, as that reads the whole body into memory.
+pub(crate) struct BodyLimit;
+
+/// The body size limit of a request, passed to [`BodyLimit`] as an extension.
+#[derive(Debug, Clone, Copy)]
+struct MaxBodySize(usize);
+
+/// A single request made while following redirects.
+#[derive(Debug, Clone)]
+pub(crate) struct Hop {
+	/// The URL which produced the response.
+	pub url: Url,
+	/// The status code of the response.
+	pub status: StatusCode,
+	/// The address the response was received from, if it came from the network
+	/// or was recorded before caching it.
+	#[cfg_attr(not(feature = "server"), allow(dead_code))]
+	pub remote_addr: Option<SocketAddr>,
+}
+
+impl Hop {
+	/// Record the request which produced a response.
+	fn of(response: &reqwest::Response) -> Self {
+		Self {
+			url: response.url().clone(),
+			status: response.status(),
+			remote_addr: response.remote_addr().or_else(|| cache::remote_addr(response.headers())),
+		}
+	}
+}
+
+/// A response fetched according to a [`FetchPolicy`].
+#[derive(Debug)]
+pub(crate) struct Response {
+	/// Every request made, the last one being the one which produced this
+	/// response.
+	pub hops: Vec<Hop>,
+	/// The headers of the response.
+	pub headers: HeaderMap,
+	/// The body of the response.
+	pub body: Vec<u8>,
+}
+
+impl Response {
+	/// The status code of the final response.
+	pub fn status(&self) -> StatusCode {
+		self.last().status
+	}
+
+	/// The URL of the final response.
+	pub fn url(&self) -> &Url {
+		&self.last().url
+	}
+
+	/// The final hop. There is always at least one.
+	fn last(&self) -> &Hop {
+		#[allow(clippy::expect_used)]
+		self.hops.last().expect("a response has at least one hop")
+	}
+
+	/// Parse the body as JSON, after checking that the content type allows it.
+	pub fn json<T: DeserializeOwned>(&self) -> Result<T, Error> {
+		if let Some(value) = self.headers.get(CONTENT_TYPE) {
+			let value = String::from_utf8_lossy(value.as_bytes());
+			let essence = value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+			if !json_compatible(&essence) {
+				return Err(Error::ContentType(essence));
+			}
+		}
+		Ok(serde_json::from_slice(&self.body)?)
+	}
+}
+
+/// Whether a media type could plausibly contain a JSON document. Servers
+/// commonly serve .well-known files as plain text or binary, so only types
+/// like HTML error pages are rejected.
+fn json_compatible(essence: &str) -> bool {
+	matches!(
+		essence,
+		"" | "application/json" | "text/json" | "text/plain" | "application/octet-stream"
+	) || essence.ends_with("+json")
+}
+
+/// Apply the settings [`get`] relies on to a client builder. Redirects are
+/// followed by [`get`] itself, so the client must not follow them before
+/// they are checked.
+pub(crate) fn configure(builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+	builder.redirect(redirect::Policy::none())
+}
+
+/// Perform a GET request, following redirects and reading the body within the
+/// limits of the policy.
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub(crate) async fn get(
+	http: &ClientWithMiddleware,
+	url: Url,
+	policy: &FetchPolicy,
+) -> Result<Response, Error> {
+	get_with_headers(http, url, HeaderMap::new(), policy).await
+}
+
+/// Like [`get`], but sends the given headers with every request.
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub(crate) async fn get_with_headers(
+	http: &ClientWithMiddleware,
+	url: Url,
+	headers: HeaderMap,
+	policy: &FetchPolicy,
+) -> Result<Response, Error> {
+	let unchecked = |_| async { Ok::<_, Infallible>(()) };
+	match fetch(http, url, &headers, policy, unchecked).await {
+		Ok(result) => result,
+		Err(never) => match never {},
+	}
+}
+
+/// Perform a POST request with a JSON body, reading the body of the response
+/// within the limits of the policy. Redirects are not followed.
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub(crate) async fn post_json(
+	http: &ClientWithMiddleware,
+	url: Url,
+	body: &'static str,
+	policy: &FetchPolicy,
+) -> Result<Response, Error> {
+	let response = http
+		.post(url)
+		.header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+		.body(body)
+		.with_extension(MaxBodySize(policy.max_body_size))
+		.send()
+		.await
+		.map_err(|e| too_large(&e).map_or(Error::Http(e), Error::TooLarge))?;
+	read(response, policy).await
+}
+
+/// Read the body of a response sent without [`get`] within the limits of the
+/// policy.
+pub(crate) async fn read(
+	response: reqwest::Response,
+	policy: &FetchPolicy,
+) -> Result<Response, Error> {
+	let hops = vec![Hop::of(&response)];
+	let headers = response.headers().clone();
+	let body = read_body(response, policy.max_body_size).await?;
+	Ok(Response { hops, headers, body })
+}
+
+/// Like [`get`], but passes the URL of every request, including the ones of
+/// redirects, to `check` before sending it. An error of the check stops
+/// fetching and is returned as the outer error.
+pub(crate) async fn get_checked<C, F, E>(
+	http: &ClientWithMiddleware,
+	url: Url,
+	policy: &FetchPolicy,
+	check: C,
+) -> Result<Result<Response, Error>, E>
+where
+	C: Fn(Url) -> F,
+	F: Future<Output = Result<(), E>>,
+{
+	fetch(http, url, &HeaderMap::new(), policy, check).await
+}
+
+/// Follow redirects from the URL, sending the headers with every request and
+/// passing the URL of every request to `check` first.
+async fn fetch<C, F, E>(
+	http: &ClientWithMiddleware,
+	url: Url,
+	headers: &HeaderMap,
+	policy: &FetchPolicy,
+	check: C,
+) -> Result<Result<Response, Error>, E>
+where
+	C: Fn(Url) -> F,
+	F: Future<Output = Result<(), E>>,
+{
+	let mut visited = HashSet::new();
+	let mut hops = Vec::new();
+	let mut url = url;
+	visited.insert(url.clone());
+	loop {
+		check(url.clone()).await?;
+		let next = match hop(http, &url, headers, policy, &mut hops).await {
+			Ok(ControlFlow::Continue(next)) => next,
+			Ok(ControlFlow::Break(response)) => return Ok(Ok(response)),
+			Err(e) => return Ok(Err(e)),
+		};
+		if !visited.insert(next.clone()) {
+			return Ok(Err(Error::RedirectLoop(next)));
+		}
+		url = next;
+	}
+}
+
+/// Perform a single request, returning either the final response or the URL
+/// a redirect points to.
+async fn hop(
+	http: &ClientWithMiddleware,
+	url: &Url,
+	headers: &HeaderMap,
+	policy: &FetchPolicy,
+	hops: &mut Vec<Hop>,
+) -> Result<ControlFlow<Response, Url>, Error> {
+	let response = http
+		.get(url.clone())
+		.headers(headers.clone())
+		.with_extension(MaxBodySize(policy.max_body_size))
+		.send()
+		.await
+		.map_err(|e| too_large(&e).map_or(Error::Http(e), Error::TooLarge))?;
+	if response.url() != url {
+		return Err(Error::ClientRedirect(response.url().clone()));
+	}
+	hops.push(Hop::of(&response));
+
+	if !matches!(
+		response.status(),
+		StatusCode::MOVED_PERMANENTLY
+			| StatusCode::FOUND
+			| StatusCode::SEE_OTHER
+			| StatusCode::TEMPORARY_REDIRECT
+			| StatusCode::PERMANENT_REDIRECT
+	) {
+		let headers = response.headers().clone();
+		let body = read_body(response, policy.max_body_size).await?;
+		return Ok(ControlFlow::Break(Response { hops: std::mem::take(hops), headers, body }));
+	}
+
+	if hops.len() > policy.max_redirects {
+		return Err(Error::TooManyRedirects(policy.max_redirects));
+	}
+	redirect_target(response.url(), response.headers()).map(ControlFlow::Continue)
+}
+
+/// Determine where a redirect response points to.
+fn redirect_target(current: &Url, headers: &HeaderMap) -> Result<Url, Error> {
+	let location = headers
+		.get(LOCATION)
+		.ok_or_else(|| Error::InvalidRedirect(String::new()))?
+		.to_str()
+		.map_err(|e| Error::InvalidRedirect(e.to_string()))?;
+	let next = current.join(location).map_err(|_| Error::InvalidRedirect(location.to_owned()))?;
+	if !matches!(next.scheme(), "http" | "https") {
+		return Err(Error::InvalidRedirect(location.to_owned()));
+	}
+	check_downgrade(current, &next)?;
+	Ok(next)
+}
+
+/// Reject going from an HTTPS URL to a plain HTTP one.
+fn check_downgrade(from: &Url, to: &Url) -> Result<(), Error> {
+	if from.scheme() == "https" && to.scheme() == "http" {
+		return Err(Error::Downgrade(to.clone()));
+	}
+	Ok(())
+}
+
+/// Read a response body, failing as soon as it exceeds the limit.
+async fn read_body(mut response: reqwest::Response, limit: usize) -> Result<Vec<u8>, Error> {
+	let announced = response.content_length().and_then(|len| usize::try_from(len).ok());
+	if announced.is_some_and(|len| len > limit) {
+		return Err(Error::TooLarge(limit));
+	}
+	let mut body = Vec::with_capacity(announced.unwrap_or_default());
+	while let Some(chunk) = response.chunk().await? {
+		if body.len() + chunk.len() > limit {
+			return Err(Error::TooLarge(limit));
+		}
+		body.extend_from_slice(&chunk);
+	}
+	Ok(body)
+}
+
+/// The limit of the body size error raised by [`BodyLimit`], which the
+/// caching middleware wraps in its own errors.
+fn too_large(e: &reqwest_middleware::Error) -> Option<usize> {
+	let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e);
+	while let Some(e) = source {
+		if let Some(Error::TooLarge(limit)) = e.downcast_ref::<Error>() {
+			return Some(*limit);
+		}
+		source = e.source();
+	}
+	None
+}
+
+#[async_trait::async_trait]
+impl Middleware for BodyLimit {
+	async fn handle(
+		&self,
+		req: Request,
+		extensions: &mut Extensions,
+		next: Next<'_>,
+	) -> reqwest_middleware::Result<reqwest::Response> {
+		let limit = extensions.get::<MaxBodySize>().copied();
+		let response = next.run(req, extensions).await?;
+		let Some(MaxBodySize(limit)) = limit else { return Ok(response) };
+		let mut limited = http::Response::builder()
+			.status(response.status())
+			.version(response.version())
+			.url(response.url().clone());
+		if let Some(headers) = limited.headers_mut() {
+			headers.extend(response.headers().clone());
+		}
+		let body =
+			read_body(response, limit).await.map_err(reqwest_middleware::Error::middleware)?;
+		let limited = limited.body(body).map_err(reqwest_middleware::Error::middleware)?;
+		Ok(reqwest::Response::from(limited))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use reqwest::{
+		header::{HeaderMap, LOCATION},
+		Url,
+	};
+	use reqwest_middleware::ClientWithMiddleware;
+	use serde::Deserialize;
+	use wiremock::{
+		matchers::{method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::{configure, get, redirect_target, Error, FetchPolicy};
+	use crate::cache::SharedCache;
+
+	/// Minimal document to parse responses into.
+	#[derive(Debug, Deserialize)]
+	struct Document {
+		/// Arbitrary field.
+		#[allow(dead_code)]
+		key: String,
+	}
+
+	/// Constructs a client as the resolvers do.
+	fn client() -> reqwest::Result<reqwest::Client> {
+		configure(reqwest::Client::builder()).build()
+	}
+
+	/// Mounts a response for a path on the mock server.
+	async fn mount(server: &MockServer, route: &str, response: ResponseTemplate) {
+		Mock::given(method("GET")).and(path(route)).respond_with(response).mount(server).await;
+	}
+
+	/// Validates redirect handling.
+	#[tokio::test]
+	async fn redirects() -> Result<(), Box<dyn std::error::Error>> {
+		let server = MockServer::start().await;
+		let http = ClientWithMiddleware::from(client()?);
+		let base = Url::parse(&server.uri())?;
+		let policy = FetchPolicy::default();
+
+		mount(&server, "/a", ResponseTemplate::new(302).insert_header("Location", "/b")).await;
+		mount(&server, "/b", ResponseTemplate::new(301).insert_header("Location", "/c")).await;
+		mount(
+			&server,
+			"/c",
+			ResponseTemplate::new(200).set_body_raw(r#"{"key": "value"}"#, "application/json"),
+		)
+		.await;
+		mount(&server, "/loop", ResponseTemplate::new(307).insert_header("Location", "/a2")).await;
+		mount(&server, "/a2", ResponseTemplate::new(308).insert_header("Location", "/loop")).await;
+
+		let response = get(&http, base.join("/a")?, &policy).await?;
+		assert_eq!(response.hops.len(), 3, "followed redirects");
+		assert_eq!(response.url().path(), "/c");
+		response.json::<Document>()?;
+
+		let limited = FetchPolicy { max_redirects: 1, ..policy };
+		assert!(
+			matches!(get(&http, base.join("/a")?, &limited).await, Err(Error::TooManyRedirects(1))),
+			"redirect limit"
+		);
+		assert!(
+			matches!(get(&http, base.join("/loop")?, &policy).await, Err(Error::RedirectLoop(_))),
+			"redirect loop"
+		);
+
+		let following = ClientWithMiddleware::from(reqwest::Client::new());
+		assert!(
+			matches!(
+				get(&following, base.join("/a")?, &policy).await,
+				Err(Error::ClientRedirect(url)) if url.path() == "/c"
+			),
+			"redirects followed by the client"
+		);
+
+		let mut headers = HeaderMap::new();
+		headers.insert(LOCATION, "http://example.test/".parse()?);
+		assert!(
+			matches!(
+				redirect_target(&Url::parse("https://example.test/")?, &headers),
+				Err(Error::Downgrade(_))
+			),
+			"HTTPS to HTTP downgrade"
+		);
+		headers.insert(LOCATION, "file:///etc/passwd".parse()?);
+		assert!(
+			matches!(
+				redirect_target(&Url::parse("https://example.test/")?, &headers),
+				Err(Error::InvalidRedirect(_))
+			),
+			"non-HTTP redirect"
+		);
+		Ok(())
+	}
+
+	/// Validates the body size limit and content type checks.
+	#[tokio::test]
+	async fn body() -> Result<(), Box<dyn std::error::Error>> {
+		let server = MockServer::start().await;
+		let http = ClientWithMiddleware::from(client()?);
+		let base = Url::parse(&server.uri())?;
+
+		mount(
+			&server,
+			"/large",
+			ResponseTemplate::new(200)
+				.insert_header("cache-control", "max-age=3600")
+				.set_body_raw(vec![b' '; 1024], "application/json"),
+		)
+		.await;
+		mount(
+			&server,
+			"/html",
+			ResponseTemplate::new(200).set_body_raw("<html></html>", "text/html; charset=utf-8"),
+		)
+		.await;
+		mount(
+			&server,
+			"/plain",
+			ResponseTemplate::new(200).set_body_raw(r#"{"key": "value"}"#, "text/plain"),
+		)
+		.await;
+
+		let policy = FetchPolicy { max_body_size: 512, ..FetchPolicy::default() };
+		assert!(
+			matches!(get(&http, base.join("/large")?, &policy).await, Err(Error::TooLarge(512))),
+			"body size limit"
+		);
+		let cache = SharedCache::default();
+		assert!(
+			matches!(
+				get(&cache.client(client()?), base.join("/large")?, &policy).await,
+				Err(Error::TooLarge(512))
+			),
+			"body size limit below the cache"
+		);
+		assert!(cache.entries().is_empty(), "the large body isn't cached");
+		assert!(
+			matches!(
+				get(&http, base.join("/html")?, &policy).await?.json::<Document>(),
+				Err(Error::ContentType(content_type)) if content_type == "text/html"
+			),
+			"HTML content type"
+		);
+		get(&http, base.join("/plain")?, &policy).await?.json::<Document>()?;
+		Ok(())
+	}
+}
@@ -0,0 +1,341 @@
+//! C bindings for the resolvers.
+//!
+//! The functions block the calling thread until they finish, see
+//! [`blocking`](crate::blocking), and return one of the `MATRIX_ORACLE_*`
+//! status codes. Strings returned through out parameters must be released
+//! with [`matrix_oracle_string_free`]. The declarations are in
+//! `include/matrix_oracle.h`, which is generated with `cbindgen`.
+
+use std::{
+	ffi::{CStr, CString},
+	os::raw::{c_char, c_int},
+	panic::{self, AssertUnwindSafe},
+};
+
+use tracing::debug;
+
+use crate::{blocking, client::error::Error};
+
+/// The resolution succeeded.
+pub const MATRIX_ORACLE_OK: c_int = 0;
+/// There is no well-known information, so the server name itself is used.
+/// Corresponds to the `IGNORE` outcome in the spec.
+pub const MATRIX_ORACLE_IGNORE: c_int = 1;
+/// Corresponds to the `FAIL_PROMPT` outcome in the spec.
+pub const MATRIX_ORACLE_FAIL_PROMPT: c_int = 2;
+/// Corresponds to the `FAIL_ERROR` outcome in the spec. Failed server
+/// resolution is reported with this code as well.
+pub const MATRIX_ORACLE_FAIL_ERROR: c_int = 3;
+/// A pointer was null or a string wasn't valid UTF-8.
+pub const MATRIX_ORACLE_INVALID_ARGUMENT: c_int = 4;
+/// The resolver couldn't be constructed, or failed unexpectedly.
+pub const MATRIX_ORACLE_INTERNAL: c_int = 5;
+
+/// Resolver for the client-server API, named `MatrixOracleClient` in C.
+#[derive(Debug)]
+pub struct ClientResolver(blocking::client::Resolver);
+
+/// Resolver for the server-server API, named `MatrixOracleServer` in C.
+#[derive(Debug)]
+pub struct ServerResolver(blocking::server::Resolver);
+
+/// Constructs a client resolver and stores it in `out`. It must be released
+/// with [`matrix_oracle_client_free`].
+///
+/// # Safety
+///
+/// `out` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn matrix_oracle_client_new(out: *mut *mut ClientResolver) -> c_int {
+	if out.is_null() {
+		return MATRIX_ORACLE_INVALID_ARGUMENT;
+	}
+	guard(|| match blocking::client::Resolver::new() {
+		Ok(resolver) => {
+			*out = Box::into_raw(Box::new(ClientResolver(resolver)));
+			MATRIX_ORACLE_OK
+		}
+		Err(e) => {
+			debug!("Constructing the client resolver failed: {}", e);
+			MATRIX_ORACLE_INTERNAL
+		}
+	})
+}
+
+/// Releases a client resolver.
+///
+/// # Safety
+///
+/// `resolver` must be null or returned by [`matrix_oracle_client_new`], and
+/// must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn matrix_oracle_client_free(resolver: *mut ClientResolver) {
+	if !resolver.is_null() {
+		drop(Box::from_raw(resolver));
+	}
+}
+
+/// Resolves the base URL of the homeserver for the given server name and
+/// stores it in `homeserver`. The URL is stored for both
+/// [`MATRIX_ORACLE_OK`] and [`MATRIX_ORACLE_IGNORE`].
+///
+/// # Safety
+///
+/// `resolver` must be null or a live client resolver, `name` must be null or
+/// a NUL-terminated string and `homeserver` must be null or valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn matrix_oracle_client_resolve(
+	resolver: *const ClientResolver,
+	name: *const c_char,
+	homeserver: *mut *mut c_char,
+) -> c_int {
+	let (Some(resolver), Some(name)) = (resolver.as_ref(), string(name)) else {
+		return MATRIX_ORACLE_INVALID_ARGUMENT;
+	};
+	if homeserver.is_null() {
+		return MATRIX_ORACLE_INVALID_ARGUMENT;
+	}
+	guard(|| {
+		let discovery = match resolver.0.discover(name) {
+			Ok(discovery) => discovery,
+			Err(e) => {
+				debug!("Discovery failed: {}", e);
+				return match e {
+					Error::Prompt(_) => MATRIX_ORACLE_FAIL_PROMPT,
+					Error::Fail(_) => MATRIX_ORACLE_FAIL_ERROR,
+				};
+			}
+		};
+		let Ok(url) = CString::new(discovery.homeserver.as_str()) else {
+			return MATRIX_ORACLE_INTERNAL;
+		};
+		*homeserver = url.into_raw();
+		if discovery.well_known_missing {
+			MATRIX_ORACLE_IGNORE
+		} else {
+			MATRIX_ORACLE_OK
+		}
+	})
+}
+
+/// Constructs a server resolver with the system DNS configuration and stores
+/// it in `out`. It must be released with [`matrix_oracle_server_free`].
+///
+/// # Safety
+///
+/// `out` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn matrix_oracle_server_new(out: *mut *mut ServerResolver) -> c_int {
+	if out.is_null() {
+		return MATRIX_ORACLE_INVALID_ARGUMENT;
+	}
+	guard(|| match blocking::server::Resolver::new() {
+		Ok(resolver) => {
+			*out = Box::into_raw(Box::new(ServerResolver(resolver)));
+			MATRIX_ORACLE_OK
+		}
+		Err(e) => {
+			debug!("Constructing the server resolver failed: {}", e);
+			MATRIX_ORACLE_INTERNAL
+		}
+	})
+}
+
+/// Releases a server resolver.
+///
+/// # Safety
+///
+/// `resolver` must be null or returned by [`matrix_oracle_server_new`], and
+/// must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn matrix_oracle_server_free(resolver: *mut ServerResolver) {
+	if !resolver.is_null() {
+		drop(Box::from_raw(resolver));
+	}
+}
+
+/// Resolves the given server name. The address to connect to is stored in
+/// `address`, and the value for the `Host` header in `host`.
+///
+/// # Safety
+///
+/// `resolver` must be null or a live server resolver, `name` must be null or
+/// a NUL-terminated string and `address` and `host` must be null or valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn matrix_oracle_server_resolve(
+	resolver: *const ServerResolver,
+	name: *const c_char,
+	address: *mut *mut c_char,
+	host: *mut *mut c_char,
+) -> c_int {
+	let (Some(resolver), Some(name)) = (resolver.as_ref(), string(name)) else {
+		return MATRIX_ORACLE_INVALID_ARGUMENT;
+	};
+	if address.is_null() || host.is_null() {
+		return MATRIX_ORACLE_INVALID_ARGUMENT;
+	}
+	guard(|| {
+		let server = match resolver.0.resolve(name) {
+			Ok(server) => server,
+			Err(e) => {
+				debug!("Resolution failed: {}", e);
+				return MATRIX_ORACLE_FAIL_ERROR;
+			}
+		};
+		let (Ok(server_address), Ok(server_host)) =
+			(CString::new(server.address()), CString::new(server.host_header()))
+		else {
+			return MATRIX_ORACLE_INTERNAL;
+		};
+		*address = server_address.into_raw();
+		*host = server_host.into_raw();
+		MATRIX_ORACLE_OK
+	})
+}
+
+/// Releases a string returned by one of the functions.
+///
+/// # Safety
+///
+/// `string` must be null or returned by one of the functions, and must not be
+/// used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn matrix_oracle_string_free(string: *mut c_char) {
+	if !string.is_null() {
+		drop(CString::from_raw(string));
+	}
+}
+
+/// Reads a NUL-terminated UTF-8 string, which may be null.
+///
+/// # Safety
+///
+/// `string` must be null or a NUL-terminated string.
+unsafe fn string<'a>(string: *const c_char) -> Option<&'a str> {
+	if string.is_null() {
+		return None;
+	}
+	CStr::from_ptr(string).to_str().ok()
+}
+
+/// Runs the function, reporting panics as [`MATRIX_ORACLE_INTERNAL`] instead
+/// of unwinding into C.
+fn guard(f: impl FnOnce() -> c_int) -> c_int {
+	panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(MATRIX_ORACLE_INTERNAL)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		ffi::{CStr, CString},
+		net::SocketAddr,
+		os::raw::{c_char, c_int},
+		ptr,
+	};
+
+	use wiremock::{
+		matchers::{method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::{
+		matrix_oracle_client_free, matrix_oracle_client_resolve, matrix_oracle_string_free,
+		ClientResolver, MATRIX_ORACLE_FAIL_ERROR, MATRIX_ORACLE_IGNORE, MATRIX_ORACLE_OK,
+	};
+	use crate::{blocking, client};
+
+	/// Resolves the name with the resolver and releases it afterwards, like C
+	/// code would. Returns the status code, or -1 if a homeserver was
+	/// returned which isn't the expected one.
+	///
+	/// # Safety
+	///
+	/// `resolver` must be live and is handed over.
+	unsafe fn client_resolve_with(
+		resolver: *mut ClientResolver,
+		name: *const c_char,
+		expected: &CStr,
+	) -> c_int {
+		let mut homeserver = ptr::null_mut();
+		let mut code = matrix_oracle_client_resolve(resolver, name, &mut homeserver);
+		if (code == MATRIX_ORACLE_OK || code == MATRIX_ORACLE_IGNORE)
+			&& (homeserver.is_null() || CStr::from_ptr(homeserver) != expected)
+		{
+			code = -1;
+		}
+		matrix_oracle_string_free(homeserver);
+		matrix_oracle_client_free(resolver);
+		code
+	}
+
+	/// A client resolver sending every request to the given address, owned
+	/// by the caller.
+	fn client(addr: SocketAddr) -> Result<*mut ClientResolver, Box<dyn std::error::Error>> {
+		let http = reqwest::Client::builder()
+			.resolve("example.test", addr)
+			.resolve("destination.test", addr);
+		let resolver =
+			blocking::client::Resolver::from_async(client::Resolver::with_builder(http)?)?;
+		Ok(Box::into_raw(Box::new(ClientResolver(resolver))))
+	}
+
+	/// Validates the status codes of client discovery through the bindings.
+	#[test]
+	fn client_resolve() -> Result<(), Box<dyn std::error::Error>> {
+		let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+		let mock_server = runtime.block_on(MockServer::start());
+		let port = mock_server.address().port();
+		let name = CString::new(format!("example.test:{}", port))?;
+		let versions = ResponseTemplate::new(200)
+			.set_body_raw(r#"{"versions":["r0.0.1"]}"#, "application/json");
+		let well_known = |base_url: &str| {
+			ResponseTemplate::new(200).set_body_raw(
+				format!(r#"{{"m.homeserver": {{"base_url": "{}"}} }}"#, base_url),
+				"application/json",
+			)
+		};
+		let cases = vec![
+			(
+				ResponseTemplate::new(404),
+				format!("http://example.test:{}/", port),
+				MATRIX_ORACLE_IGNORE,
+			),
+			(
+				well_known(&format!("http://destination.test:{}", port)),
+				format!("http://destination.test:{}/", port),
+				MATRIX_ORACLE_OK,
+			),
+			(
+				well_known(&format!("http://example.test:{}", port)),
+				format!("http://example.test:{}/", port),
+				MATRIX_ORACLE_OK,
+			),
+			(well_known("not a URL"), String::new(), MATRIX_ORACLE_FAIL_ERROR),
+		];
+
+		for (response, expected, code) in cases {
+			runtime.block_on(async {
+				mock_server.reset().await;
+				Mock::given(method("GET"))
+					.and(path("/.well-known/matrix/client"))
+					.respond_with(response)
+					.mount(&mock_server)
+					.await;
+				Mock::given(method("GET"))
+					.and(path("/_matrix/client/versions"))
+					.respond_with(versions.clone())
+					.mount(&mock_server)
+					.await;
+			});
+			let expected = CString::new(expected)?;
+			let resolver = client(*mock_server.address())?;
+			// SAFETY: The resolver is live and handed over, the strings outlive
+			// the call.
+			let result = unsafe { client_resolve_with(resolver, name.as_ptr(), &expected) };
+			assert_eq!(result, code, "expected {:?}", expected);
+		}
+		Ok(())
+	}
+}
@@ -18,19 +18,24 @@
 	clippy::expect_used
 )]
 
-use http_cache_reqwest::{Cache, CacheMode, CacheOptions, HttpCache, MokaManager};
-
+#[cfg(all(feature = "blocking", any(feature = "client", feature = "server")))]
+pub mod blocking;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod cache;
 #[cfg(feature = "client")]
 pub mod client;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod fetch;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod lint;
+#[cfg(all(feature = "overrides", any(feature = "client", feature = "server")))]
+pub mod overrides;
+#[cfg(feature = "publish")]
+pub mod publish;
 #[cfg(feature = "server")]
 pub mod server;
-
-/// Returns a HTTP caching middleware with appropriate settings for
-/// matrix-oracle's use-case.
-pub(crate) fn cache() -> Cache<MokaManager> {
-	Cache(HttpCache {
-		mode: CacheMode::Default,
-		manager: MokaManager::default(),
-		options: Some(CacheOptions { shared: false, ..CacheOptions::default() }),
-	})
-}
+#[cfg(feature = "service")]
+pub mod service;
+#[cfg(all(any(test, feature = "testing"), any(feature = "client", feature = "server")))]
+pub mod testing;
@@ -0,0 +1,349 @@
+//! Offline checks of draft .well-known documents, to find mistakes before a
+//! delegation is published.
+//!
+//! [`server`] checks the JSON of `/.well-known/matrix/server` and [`client`]
+//! the JSON of `/.well-known/matrix/client`. Both return every problem they
+//! find, so an empty list means the document is fine.
+
+use std::net::{IpAddr, Ipv6Addr};
+
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// The fields of the server document.
+const SERVER_FIELDS: &[&str] = &["m.server"];
+
+/// The fields of the client document, including vendor fields in common use.
+const CLIENT_FIELDS: &[&str] = &[
+	"m.homeserver",
+	"m.identity_server",
+	"m.tile_server",
+	"m.integrations",
+	"org.matrix.msc3575.proxy",
+	"org.matrix.msc2965.authentication",
+	"org.matrix.msc4143.rtc_foci",
+	"io.element.e2ee",
+	"io.element.jitsi",
+];
+
+/// Fields which were replaced, with their replacements.
+const DEPRECATED_FIELDS: &[(&str, &str)] = &[
+	("org.matrix.msc3488.tile_server", "m.tile_server"),
+	("im.vector.riot.e2ee", "io.element.e2ee"),
+	("im.vector.riot.jitsi", "io.element.jitsi"),
+];
+
+/// How serious a problem is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum Severity {
+	/// The document works, but something is unusual or deprecated.
+	Warning,
+	/// The document does not work as intended.
+	Error,
+}
+
+/// A problem found in a document.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Lint {
+	/// How serious the problem is.
+	pub severity: Severity,
+	/// The JSON pointer of the value with the problem, empty for the whole
+	/// document.
+	pub pointer: String,
+	/// Description of the problem.
+	pub message: String,
+}
+
+impl Lint {
+	/// Constructs a lint with the given severity.
+	fn new(severity: Severity, pointer: &str, message: String) -> Self {
+		Self { severity, pointer: pointer.to_owned(), message }
+	}
+}
+
+/// Checks a draft of `/.well-known/matrix/server`.
+#[must_use]
+pub fn server(json: &str) -> Vec<Lint> {
+	let mut lints = Vec::new();
+	let Some(document) = parse(json, &mut lints) else { return lints };
+	fields(&document, "", SERVER_FIELDS, &mut lints);
+	match document.get("m.server") {
+		Some(Value::String(server)) => server_name(server, "/m.server", &mut lints),
+		Some(_) => lints.push(Lint::new(
+			Severity::Error,
+			"/m.server",
+			String::from("m.server must be a string"),
+		)),
+		None => {
+			lints.push(Lint::new(Severity::Error, "", String::from("m.server is missing")));
+		}
+	}
+	lints
+}
+
+/// Checks a draft of `/.well-known/matrix/client`.
+#[must_use]
+pub fn client(json: &str) -> Vec<Lint> {
+	let mut lints = Vec::new();
+	let Some(document) = parse(json, &mut lints) else { return lints };
+	fields(&document, "", CLIENT_FIELDS, &mut lints);
+	match document.get("m.homeserver") {
+		Some(homeserver) => {
+			url_field(homeserver, "/m.homeserver", "base_url", base_url, &mut lints);
+		}
+		None => {
+			lints.push(Lint::new(Severity::Error, "", String::from("m.homeserver is missing")));
+		}
+	}
+	if let Some(identity_server) = document.get("m.identity_server") {
+		url_field(identity_server, "/m.identity_server", "base_url", base_url, &mut lints);
+	}
+	if let Some(proxy) = document.get("org.matrix.msc3575.proxy") {
+		url_field(proxy, "/org.matrix.msc3575.proxy", "url", http_url, &mut lints);
+	}
+	if let Some(tile_server) = document.get("m.tile_server") {
+		url_field(tile_server, "/m.tile_server", "map_style_url", http_url, &mut lints);
+	}
+	lints
+}
+
+/// Whether the string is a hostname or IP literal with an optional port, as
+/// required for server names.
+pub(crate) fn is_server_name(name: &str) -> bool {
+	let (host, port) = match name.strip_prefix('[') {
+		Some(rest) => {
+			let Some((ip, port)) = rest.split_once(']') else { return false };
+			if ip.parse::<Ipv6Addr>().is_err() {
+				return false;
+			}
+			(None, port)
+		}
+		None => name.find(':').map_or((Some(name), ""), |i| (Some(&name[..i]), &name[i..])),
+	};
+	let valid_port = port.is_empty()
+		|| port
+			.strip_prefix(':')
+			.is_some_and(|port| matches!(port.parse::<u16>(), Ok(port) if port != 0));
+	valid_port
+		&& host.is_none_or(|host| {
+			!host.is_empty()
+				&& host.len() <= 255
+				&& host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+		})
+}
+
+/// Parses the document, which must be a JSON object.
+fn parse(json: &str, lints: &mut Vec<Lint>) -> Option<Map<String, Value>> {
+	match serde_json::from_str(json) {
+		Ok(Value::Object(document)) => Some(document),
+		Ok(_) => {
+			lints.push(Lint::new(Severity::Error, "", String::from("not a JSON object")));
+			None
+		}
+		Err(e) => {
+			lints.push(Lint::new(Severity::Error, "", format!("invalid JSON: {}", e)));
+			None
+		}
+	}
+}
+
+/// Flags deprecated fields and unknown fields that look like typos of known
+/// ones.
+fn fields(object: &Map<String, Value>, pointer: &str, known: &[&str], lints: &mut Vec<Lint>) {
+	for key in object.keys().filter(|key| !known.contains(&key.as_str())) {
+		let field = format!("{}/{}", pointer, key.replace('~', "~0").replace('/', "~1"));
+		if let Some((_, replacement)) = DEPRECATED_FIELDS.iter().find(|(old, _)| old == key) {
+			let message = format!("{} is deprecated, use {} instead", key, replacement);
+			lints.push(Lint::new(Severity::Warning, &field, message));
+		} else if let Some(similar) = known.iter().find(|known| is_typo(key, known)) {
+			let message = format!("unknown field {}, did you mean {}?", key, similar);
+			lints.push(Lint::new(Severity::Warning, &field, message));
+		}
+	}
+}
+
+/// Checks an object with a URL in the given field, which is checked with
+/// `check`.
+fn url_field(
+	value: &Value,
+	pointer: &str,
+	field: &str,
+	check: fn(&str, &str, &mut Vec<Lint>),
+	lints: &mut Vec<Lint>,
+) {
+	let Value::Object(object) = value else {
+		let message = format!("must be an object with {}", field);
+		lints.push(Lint::new(Severity::Error, pointer, message));
+		return;
+	};
+	fields(object, pointer, &[field], lints);
+	let url_pointer = format!("{}/{}", pointer, field);
+	match object.get(field) {
+		Some(Value::String(url)) => check(url, &url_pointer, lints),
+		Some(_) => {
+			let message = format!("{} must be a string", field);
+			lints.push(Lint::new(Severity::Error, &url_pointer, message));
+		}
+		None => lints.push(Lint::new(Severity::Error, pointer, format!("{} is missing", field))),
+	}
+}
+
+/// Checks a URL which paths are appended to.
+fn base_url(url: &str, pointer: &str, lints: &mut Vec<Lint>) {
+	let Some(parsed) = parse_http_url(url, pointer, lints) else { return };
+	let mut lint = |severity, message: &str| {
+		lints.push(Lint::new(severity, pointer, format!("{}: {}", url, message)));
+	};
+	if parsed.query().is_some() || parsed.fragment().is_some() {
+		lint(Severity::Error, "a query or fragment breaks the appended paths");
+	}
+	if parsed.path().contains("/_matrix") {
+		lint(Severity::Error, "the path must not contain the API prefix /_matrix");
+	} else if parsed.path() != "/" {
+		lint(Severity::Warning, "the path is not the root, which is unusual");
+	}
+}
+
+/// Checks a URL which is used as it is.
+fn http_url(url: &str, pointer: &str, lints: &mut Vec<Lint>) {
+	parse_http_url(url, pointer, lints);
+}
+
+/// Parses an HTTP or HTTPS URL, flagging other URLs and plain HTTP.
+fn parse_http_url(url: &str, pointer: &str, lints: &mut Vec<Lint>) -> Option<Url> {
+	let mut lint = |severity, message: &str| {
+		lints.push(Lint::new(severity, pointer, format!("{}: {}", url, message)));
+	};
+	let parsed = match Url::parse(url) {
+		Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => parsed,
+		_ if !url.contains("://") => {
+			lint(Severity::Error, "the scheme is missing");
+			return None;
+		}
+		Ok(_) => {
+			lint(Severity::Error, "the scheme is not http or https");
+			return None;
+		}
+		Err(e) => {
+			lint(Severity::Error, &e.to_string());
+			return None;
+		}
+	};
+	if parsed.scheme() == "http" {
+		lint(Severity::Warning, "plain HTTP is not encrypted");
+	}
+	Some(parsed)
+}
+
+/// Checks the delegated server name.
+fn server_name(server: &str, pointer: &str, lints: &mut Vec<Lint>) {
+	let mut lint = |severity, message: &str| {
+		lints.push(Lint::new(severity, pointer, format!("{}: {}", server, message)));
+	};
+	if server.contains("://") {
+		return lint(Severity::Error, "a server name has no scheme");
+	}
+	if server.contains('/') {
+		return lint(Severity::Error, "a server name has no path");
+	}
+	if !server.starts_with('[') && server.matches(':').count() > 1 {
+		return lint(Severity::Error, "IPv6 literals must be in brackets");
+	}
+	let (host, port) = match server.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+		Some((ip, rest)) => (ip, rest.strip_prefix(':')),
+		None => server.split_once(':').map_or((server, None), |(host, port)| (host, Some(port))),
+	};
+	if port.is_some_and(|port| !matches!(port.parse::<u16>(), Ok(port) if port != 0)) {
+		return lint(Severity::Error, "the port is not between 1 and 65535");
+	}
+	if !is_server_name(server) {
+		return lint(Severity::Error, "not a hostname or IP address with an optional port");
+	}
+	if port.is_none() && host.parse::<IpAddr>().is_ok() {
+		lint(Severity::Warning, "without a port, connections to IP literals use port 8448");
+	}
+}
+
+/// Whether the unknown field is probably a misspelling of the known one.
+fn is_typo(unknown: &str, known: &str) -> bool {
+	let unknown = unknown.to_ascii_lowercase();
+	if unknown == known {
+		return true;
+	}
+	let distance = edit_distance(&unknown, known);
+	distance <= 2 && distance < known.len() / 2
+}
+
+/// The Levenshtein distance between the strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	let mut row: Vec<usize> = (0..=b.len()).collect();
+	for (i, a) in a.chars().enumerate() {
+		let mut previous = row[0];
+		row[0] = i + 1;
+		for (j, b) in b.iter().enumerate() {
+			let substitution = previous + usize::from(a != *b);
+			previous = row[j + 1];
+			row[j + 1] = substitution.min(previous + 1).min(row[j] + 1);
+		}
+	}
+	row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{client, server, Severity};
+
+	/// Validates the lints of broken and working documents.
+	#[test]
+	fn lints() {
+		let pointers = |lints: Vec<super::Lint>| {
+			lints.into_iter().map(|lint| (lint.severity, lint.pointer)).collect::<Vec<_>>()
+		};
+		assert!(server(r#"{"m.server": "matrix.example.test:443"}"#).is_empty());
+		assert!(
+			client(r#"{"m.homeserver": {"base_url": "https://matrix.example.test"}}"#).is_empty()
+		);
+		assert!(client(
+			r#"{
+				"m.homeserver": {"base_url": "https://matrix.example.test"},
+				"m.tile_server": {"map_style_url": "https://tiles.example.test/style.json?key=a"},
+				"org.matrix.msc3575.proxy": {"url": "https://proxy.example.test/sync"}
+			}"#
+		)
+		.is_empty());
+
+		for broken in ["https://example.test", "example.test/matrix", "example.test:0", "a b"] {
+			let json = serde_json::json!({ "m.server": broken }).to_string();
+			assert_eq!(pointers(server(&json)), [(Severity::Error, String::from("/m.server"))]);
+		}
+		assert_eq!(
+			pointers(server(r#"{"m.server": "[2001:db8::1]", "m.servr": "a"}"#)),
+			[
+				(Severity::Warning, String::from("/m.servr")),
+				(Severity::Warning, String::from("/m.server"))
+			]
+		);
+		assert!(server(r#"{"m.server": "[2001:db8::1]:8448"}"#).is_empty());
+		assert_eq!(pointers(server("[]")), [(Severity::Error, String::new())]);
+
+		let lints = client(
+			r#"{
+				"m.homeserver": {"base_url": "matrix.example.test"},
+				"m.identity_server": {"base_url": "https://id.example.test/_matrix/identity"},
+				"org.matrix.msc3488.tile_server": {"map_style_url": "https://tiles.example.test"},
+				"M.Integrations": {}
+			}"#,
+		);
+		assert_eq!(
+			pointers(lints),
+			[
+				(Severity::Warning, String::from("/M.Integrations")),
+				(Severity::Warning, String::from("/org.matrix.msc3488.tile_server")),
+				(Severity::Error, String::from("/m.homeserver/base_url")),
+				(Severity::Error, String::from("/m.identity_server/base_url")),
+			]
+		);
+	}
+}
@@ -0,0 +1,421 @@
+//! Static overrides of server name resolution and homeserver discovery, for
+//! staging, split-horizon and air-gapped setups.
+//!
+//! Overrides are matched against the name being resolved, ignoring case. A
+//! pattern is either an exact name, like `example.org` or `example.org:8448`,
+//! a wildcard like `*.example.org` matching every name ending in
+//! `.example.org`, or `*` matching every name. Exact patterns take precedence
+//! over wildcards, and longer wildcards over shorter ones.
+//!
+//! Override files are written in TOML, or in JSON if their extension is
+//! `.json`:
+//!
+//! ```toml
+//! [server]
+//! # Connect to the given address, sending the name as Host header and SNI
+//! "example.org" = "10.0.0.5:8448"
+//! # Connect to the address, but use a different Host header and SNI
+//! "*.staging.example.org" = { address = "10.0.0.6:8448", host = "staging.example.org" }
+//!
+//! [client]
+//! "example.org" = "https://matrix.internal.example.org"
+//! ```
+
+#[cfg(feature = "server")]
+use std::net::{IpAddr, SocketAddr};
+use std::{
+	collections::HashMap,
+	fs, io,
+	path::{Path, PathBuf},
+	sync::{Arc, RwLock},
+	time::SystemTime,
+};
+
+#[cfg(feature = "client")]
+use reqwest::Url;
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+#[cfg(feature = "server")]
+use crate::server::{split_port, Server};
+
+/// Table of overrides, optionally loaded from a file which is reloaded when it
+/// changes. Clones share the same table.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+	/// The current table and where it was loaded from.
+	state: Arc<RwLock<State>>,
+}
+
+/// The current overrides and where they were loaded from.
+#[derive(Debug, Default)]
+struct State {
+	/// The overrides.
+	table: Table,
+	/// The file the overrides were loaded from, if any.
+	path: Option<PathBuf>,
+	/// The modification time of the file when it was loaded.
+	modified: Option<SystemTime>,
+}
+
+/// The contents of an override file.
+#[derive(Debug, Default, Deserialize)]
+struct Table {
+	/// Overrides of server name resolution by pattern.
+	#[cfg(feature = "server")]
+	#[serde(default)]
+	server: HashMap<String, ServerOverride>,
+	/// Overrides of the discovered homeserver by pattern.
+	#[cfg(feature = "client")]
+	#[serde(default)]
+	client: HashMap<String, Url>,
+}
+
+/// A server name override as written in an override file.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum ServerOverride {
+	/// An IP address or hostname with optional port, as in the `m.server`
+	/// field of .well-known information.
+	Address(String),
+	/// An address to connect to, and the hostname for the `Host` header and
+	/// TLS certificate.
+	Delegation {
+		/// The hostname or IP address with optional port to connect to.
+		address: String,
+		/// The hostname for the `Host` header and TLS certificate.
+		host: String,
+	},
+	/// A server given with [`Overrides::with_server`].
+	#[serde(skip_deserializing)]
+	Server(Server),
+}
+
+/// Errors that can occur when loading overrides.
+#[derive(Debug)]
+pub enum Error {
+	/// The file couldn't be read.
+	Io(io::Error),
+	/// The file isn't valid JSON of the expected shape.
+	Json(serde_json::Error),
+	/// The file isn't valid TOML of the expected shape.
+	Toml(toml::de::Error),
+}
+
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Io(e) => write!(f, "{}", e),
+			Self::Json(e) => write!(f, "{}", e),
+			Self::Toml(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match *self {
+			Self::Io(ref e) => Some(e),
+			Self::Json(ref e) => Some(e),
+			Self::Toml(ref e) => Some(e),
+		}
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Self {
+		Self::Io(e)
+	}
+}
+
+impl From<serde_json::Error> for Error {
+	fn from(e: serde_json::Error) -> Self {
+		Self::Json(e)
+	}
+}
+
+impl From<toml::de::Error> for Error {
+	fn from(e: toml::de::Error) -> Self {
+		Self::Toml(e)
+	}
+}
+
+impl Overrides {
+	/// Constructs an empty table of overrides.
+	#[must_use]
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Loads the overrides from the given file. The file is checked for
+	/// changes without blocking whenever an override is looked up, and
+	/// reloaded if it was modified. If reloading fails, the previous overrides
+	/// stay in effect.
+	pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+		let path = path.as_ref().to_owned();
+		let modified = fs::metadata(&path)?.modified().ok();
+		let table = Table::load(&path)?;
+		Ok(Self { state: Arc::new(RwLock::new(State { table, path: Some(path), modified })) })
+	}
+
+	/// Resolves server names matching the pattern to the given server.
+	#[cfg(feature = "server")]
+	#[must_use]
+	pub fn with_server(self, pattern: &str, server: Server) -> Self {
+		if let Ok(mut state) = self.state.write() {
+			state.table.server.insert(pattern.to_owned(), ServerOverride::Server(server));
+		}
+		self
+	}
+
+	/// Discovers the given homeserver for names matching the pattern.
+	#[cfg(feature = "client")]
+	#[must_use]
+	pub fn with_homeserver(self, pattern: &str, homeserver: Url) -> Self {
+		if let Ok(mut state) = self.state.write() {
+			state.table.client.insert(pattern.to_owned(), homeserver);
+		}
+		self
+	}
+
+	/// The server the given server name is overridden to, together with the
+	/// matching pattern.
+	#[cfg(feature = "server")]
+	pub(crate) async fn server(&self, name: &str) -> Option<(String, Server)> {
+		self.reload().await;
+		let state = self.state.read().ok()?;
+		let (pattern, server) = best_match(&state.table.server, name)?;
+		Some((pattern.to_owned(), server.to_server()))
+	}
+
+	/// The homeserver the given name is overridden to, together with the
+	/// matching pattern.
+	#[cfg(feature = "client")]
+	pub(crate) async fn homeserver(&self, name: &str) -> Option<(String, Url)> {
+		self.reload().await;
+		let state = self.state.read().ok()?;
+		let (pattern, url) = best_match(&state.table.client, name)?;
+		Some((pattern.to_owned(), url.clone()))
+	}
+
+	/// Reloads the overrides if the file they were loaded from was modified.
+	async fn reload(&self) {
+		let Some((path, previous)) =
+			self.state.read().ok().and_then(|state| Some((state.path.clone()?, state.modified)))
+		else {
+			return;
+		};
+		let modified =
+			tokio::fs::metadata(&path).await.and_then(|metadata| metadata.modified()).ok();
+		if modified == previous {
+			return;
+		}
+		let table = match tokio::fs::read_to_string(&path).await {
+			Ok(data) => Table::parse(&path, &data),
+			Err(e) => Err(e.into()),
+		};
+		let Ok(mut state) = self.state.write() else { return };
+		state.modified = modified;
+		match table {
+			Ok(table) => {
+				debug!("Reloaded overrides from {}", path.display());
+				state.table = table;
+			}
+			Err(e) => {
+				warn!("Keeping previous overrides, reloading {} failed: {}", path.display(), e);
+			}
+		}
+	}
+}
+
+impl Table {
+	/// Reads the overrides from a TOML file, or a JSON file if the extension
+	/// is `.json`.
+	fn load(path: &Path) -> Result<Self, Error> {
+		Self::parse(path, &fs::read_to_string(path)?)
+	}
+
+	/// Parses the contents of an override file as TOML, or as JSON if the
+	/// extension is `.json`.
+	fn parse(path: &Path, data: &str) -> Result<Self, Error> {
+		if path.extension().is_some_and(|extension| extension == "json") {
+			Ok(serde_json::from_str(data)?)
+		} else {
+			Ok(toml::from_str(data)?)
+		}
+	}
+}
+
+#[cfg(feature = "server")]
+impl ServerOverride {
+	/// The server to use, following the rules for the `m.server` field of
+	/// .well-known information for addresses.
+	fn to_server(&self) -> Server {
+		match self {
+			Self::Address(address) => {
+				if let Ok(addr) = address.parse::<SocketAddr>() {
+					Server::Socket(addr)
+				} else if let Ok(addr) = address.parse::<IpAddr>() {
+					Server::Ip(addr)
+				} else if has_port(address) {
+					Server::HostPort(address.clone())
+				} else {
+					Server::Host(address.clone())
+				}
+			}
+			Self::Delegation { address, host } => {
+				let (address, port) = if let Ok(addr) = address.parse::<SocketAddr>() {
+					(addr.ip().to_string(), addr.port())
+				} else if let Some((address, port)) = split_port(address) {
+					(address.to_owned(), port)
+				} else {
+					(address.trim_start_matches('[').trim_end_matches(']').to_owned(), 8448)
+				};
+				Server::Override { address, port, host: host.clone() }
+			}
+			Self::Server(server) => server.clone(),
+		}
+	}
+}
+
+/// Whether a hostname ends with a port.
+#[cfg(feature = "server")]
+fn has_port(host: &str) -> bool {
+	host.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok())
+}
+
+/// The entry whose pattern matches the name best: an exact match, or else
+/// the longest matching wildcard.
+fn best_match<'a, T>(entries: &'a HashMap<String, T>, name: &str) -> Option<(&'a str, &'a T)> {
+	let name = name.to_ascii_lowercase();
+	entries
+		.iter()
+		.filter_map(|(pattern, value)| {
+			let lowercase = pattern.to_ascii_lowercase();
+			let specificity = if lowercase == name {
+				usize::MAX
+			} else if lowercase == "*" {
+				0
+			} else {
+				let suffix = lowercase.strip_prefix('*')?;
+				if !suffix.starts_with('.') || !name.ends_with(suffix) {
+					return None;
+				}
+				suffix.len()
+			};
+			Some((specificity, pattern.as_str(), value))
+		})
+		.max_by_key(|(specificity, ..)| *specificity)
+		.map(|(_, pattern, value)| (pattern, value))
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod tests {
+	use std::{
+		fs,
+		time::{Duration, SystemTime},
+	};
+
+	use super::Overrides;
+	use crate::server::{test_dns, Resolver, Server};
+
+	/// Writes the file and moves its modification time, so that changes are
+	/// noticed regardless of the timestamp resolution of the file system.
+	fn write(path: &std::path::Path, contents: &str, age: u64) -> std::io::Result<()> {
+		fs::write(path, contents)?;
+		fs::File::options()
+			.write(true)
+			.open(path)?
+			.set_modified(SystemTime::now() - Duration::from_secs(age))
+	}
+
+	/// Validates loading, matching and reloading overrides.
+	#[tokio::test]
+	async fn overrides() -> Result<(), Box<dyn std::error::Error>> {
+		let path = std::env::temp_dir()
+			.join(format!("matrix-oracle-overrides-{}.toml", std::process::id()));
+		write(
+			&path,
+			r#"
+				[server]
+				"Example.test" = "10.0.0.5:8448"
+				"*.test" = "fallback.test"
+				"*.staging.test" = { address = "10.0.0.6", host = "staging.test" }
+				"ipv6.test" = { address = "::1", host = "staging.test" }
+
+				[client]
+				"example.test" = "https://matrix.internal.test"
+			"#,
+			60,
+		)?;
+		let overrides = Overrides::from_file(&path)?;
+
+		let table = &overrides;
+		let server = |name| async move { table.server(name).await.map(|(_, server)| server) };
+		assert_eq!(
+			server("example.TEST").await,
+			Some(Server::Socket(([10, 0, 0, 5], 8448).into()))
+		);
+		assert_eq!(
+			server("a.staging.test").await,
+			Some(Server::Override {
+				address: String::from("10.0.0.6"),
+				port: 8448,
+				host: String::from("staging.test")
+			})
+		);
+		let ipv6 = server("ipv6.test").await.ok_or("missing IPv6 override")?;
+		assert_eq!(ipv6.address(), "[::1]:8448");
+		assert_eq!(ipv6.host_header(), "staging.test");
+		let resolver = Resolver::with_builder(
+			reqwest::Client::builder,
+			test_dns::resolver(Vec::new()).await?,
+		)?;
+		assert_eq!(resolver.sockets(&ipv6).await?, [([0, 0, 0, 0, 0, 0, 0, 1], 8448).into()]);
+		assert_eq!(
+			overrides.server("other.test").await,
+			Some((String::from("*.test"), Server::Host(String::from("fallback.test"))))
+		);
+		assert_eq!(server("staging.test").await, Some(Server::Host(String::from("fallback.test"))));
+		assert_eq!(server("example.org").await, None);
+		assert_eq!(
+			overrides.homeserver("example.test").await.map(|(_, url)| url.to_string()),
+			Some(String::from("https://matrix.internal.test/"))
+		);
+
+		write(
+			&path,
+			r#"[server]
+"example.test" = "example.test:1234""#,
+			30,
+		)?;
+		assert_eq!(
+			server("example.test").await,
+			Some(Server::HostPort(String::from("example.test:1234")))
+		);
+		assert_eq!(server("other.test").await, None);
+		assert_eq!(overrides.homeserver("example.test").await, None);
+
+		write(&path, "[server", 0)?;
+		assert!(server("example.test").await.is_some(), "the previous overrides stay in effect");
+		fs::remove_file(&path)?;
+
+		let json = path.with_extension("json");
+		fs::write(
+			&json,
+			r#"{"server": {"*": {"address": "10.0.0.7:443", "host": "proxy.test"}}}"#,
+		)?;
+		let overrides = Overrides::from_file(&json)?;
+		fs::remove_file(&json)?;
+		assert_eq!(
+			overrides.server("anything.test").await.map(|(_, server)| server),
+			Some(Server::Override {
+				address: String::from("10.0.0.7"),
+				port: 443,
+				host: String::from("proxy.test")
+			})
+		);
+		Ok(())
+	}
+}
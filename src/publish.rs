@@ -0,0 +1,554 @@
+//! Serving the .well-known documents of a deployment, the producing side of
+//! the resolvers.
+//!
+//! A [`Delegation`] holds the documents of one domain and is validated when
+//! it's built. [`Sites`] picks the delegation by the `Host` header, so one
+//! server can answer for many domains. [`Sites::response`] can be used with
+//! any HTTP server, and [`router`] serves it with axum.
+//!
+//! ```
+//! # use matrix_oracle::publish::{Delegation, Sites};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let homeserver = "https://matrix.example.org".parse()?;
+//! let builder = Delegation::builder().with_homeserver(homeserver);
+//! let delegation = builder.with_server("matrix.example.org").build()?;
+//! let sites = Sites::new().with_host("example.org", delegation);
+//! let app = matrix_oracle::publish::router(sites);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::{
+	extract::State,
+	http::{
+		header::{
+			ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+			ACCESS_CONTROL_ALLOW_ORIGIN, ALLOW, CACHE_CONTROL, CONTENT_TYPE, HOST,
+		},
+		HeaderMap, Method, Response, StatusCode, Uri,
+	},
+	routing::any,
+	Router,
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	client::{
+		sliding_sync::SlidingSyncProxyInfo, ClientWellKnown, HomeserverInfo, IdentityServerInfo,
+	},
+	lint::is_server_name,
+	server::{split_port, ServerWellKnown},
+};
+
+/// The default lifetime of the documents in caches.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The contents of `/.well-known/matrix/support`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SupportWellKnown {
+	/// Who to contact about the homeserver.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub contacts: Vec<Contact>,
+	/// A page with support information for users.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub support_page: Option<String>,
+}
+
+/// A contact in the support information.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contact {
+	/// The Matrix user ID of the contact.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub matrix_id: Option<String>,
+	/// The email address of the contact.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub email_address: Option<String>,
+	/// The role of the contact, like [`Contact::ADMIN`].
+	pub role: String,
+}
+
+/// The documents served for one domain.
+#[derive(Debug, Clone)]
+pub struct Delegation {
+	/// The server-server delegation.
+	server: Option<ServerWellKnown>,
+	/// The client-server information.
+	client: Option<ClientWellKnown>,
+	/// The support information.
+	support: Option<SupportWellKnown>,
+	/// How long the documents may be cached.
+	max_age: Duration,
+}
+
+/// Builder validating the configuration of a [`Delegation`].
+#[derive(Debug, Clone, Default)]
+pub struct DelegationBuilder {
+	/// The server name to delegate server-server communications to.
+	server: Option<String>,
+	/// The base URL of the homeserver.
+	homeserver: Option<Url>,
+	/// The base URL of the identity server.
+	identity_server: Option<Url>,
+	/// The URL of the sliding sync proxy.
+	sliding_sync_proxy: Option<Url>,
+	/// The support page.
+	support_page: Option<Url>,
+	/// The support contacts.
+	contacts: Vec<Contact>,
+	/// How long the documents may be cached.
+	max_age: Option<Duration>,
+}
+
+/// Errors in the configuration of a [`Delegation`].
+#[derive(Debug)]
+pub enum Error {
+	/// The delegated server name is not a hostname or IP address with an
+	/// optional port.
+	ServerName(String),
+	/// A URL which does not use HTTP or HTTPS, or has no host.
+	Url(Url),
+	/// The client information has an identity server or sliding sync proxy,
+	/// but no homeserver.
+	MissingHomeserver,
+	/// A support contact without a valid Matrix ID or email address.
+	Contact(String),
+	/// No document is configured.
+	Empty,
+}
+
+/// The delegations by domain.
+#[derive(Debug, Clone, Default)]
+pub struct Sites {
+	/// The delegations by lowercase hostname.
+	hosts: HashMap<String, Arc<Delegation>>,
+	/// The delegation for other hostnames.
+	fallback: Option<Arc<Delegation>>,
+}
+
+/// The documents which can be served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Document {
+	/// `/.well-known/matrix/server`
+	Server,
+	/// `/.well-known/matrix/client`
+	Client,
+	/// `/.well-known/matrix/support`
+	Support,
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::ServerName(name) => write!(f, "{:?} is not a valid server name", name),
+			Self::Url(url) => write!(f, "{} is not an HTTP or HTTPS URL with a host", url),
+			Self::MissingHomeserver => write!(f, "the client information has no homeserver"),
+			Self::Contact(role) => {
+				write!(f, "the {} contact has no valid Matrix ID or email address", role)
+			}
+			Self::Empty => write!(f, "no document is configured"),
+		}
+	}
+}
+
+impl Contact {
+	/// The role of the administrator of the homeserver.
+	pub const ADMIN: &'static str = "m.role.admin";
+	/// The role of the contact for security issues.
+	pub const SECURITY: &'static str = "m.role.security";
+
+	/// Constructs a contact with the given role. It needs a Matrix ID or an
+	/// email address.
+	#[must_use]
+	pub fn new(role: &str) -> Self {
+		Self { matrix_id: None, email_address: None, role: role.to_owned() }
+	}
+
+	/// Sets the Matrix user ID of the contact.
+	#[must_use]
+	pub fn with_matrix_id(mut self, matrix_id: &str) -> Self {
+		self.matrix_id = Some(matrix_id.to_owned());
+		self
+	}
+
+	/// Sets the email address of the contact.
+	#[must_use]
+	pub fn with_email_address(mut self, email_address: &str) -> Self {
+		self.email_address = Some(email_address.to_owned());
+		self
+	}
+
+	/// Whether the contact can be reached at a plausible address.
+	fn is_valid(&self) -> bool {
+		let matrix_id = self.matrix_id.as_deref().map(|id| {
+			id.strip_prefix('@')
+				.and_then(|id| id.split_once(':'))
+				.is_some_and(|(localpart, server)| !localpart.is_empty() && is_server_name(server))
+		});
+		let email_address = self.email_address.as_deref().map(|address| {
+			address
+				.split_once('@')
+				.is_some_and(|(user, domain)| !user.is_empty() && !domain.is_empty())
+		});
+		match (matrix_id, email_address) {
+			(None, None) => false,
+			(matrix_id, email_address) => {
+				matrix_id.unwrap_or(true) && email_address.unwrap_or(true)
+			}
+		}
+	}
+}
+
+impl Delegation {
+	/// Starts building a delegation.
+	#[must_use]
+	pub fn builder() -> DelegationBuilder {
+		DelegationBuilder::default()
+	}
+
+	/// The contents of `/.well-known/matrix/server`, if it is served.
+	#[must_use]
+	pub fn server(&self) -> Option<&ServerWellKnown> {
+		self.server.as_ref()
+	}
+
+	/// The contents of `/.well-known/matrix/client`, if it is served.
+	#[must_use]
+	pub fn client(&self) -> Option<&ClientWellKnown> {
+		self.client.as_ref()
+	}
+
+	/// The contents of `/.well-known/matrix/support`, if it is served.
+	#[must_use]
+	pub fn support(&self) -> Option<&SupportWellKnown> {
+		self.support.as_ref()
+	}
+
+	/// The serialized document, if it is served.
+	fn document(&self, document: Document) -> Option<String> {
+		match document {
+			Document::Server => serde_json::to_string(self.server.as_ref()?).ok(),
+			Document::Client => serde_json::to_string(self.client.as_ref()?).ok(),
+			Document::Support => serde_json::to_string(self.support.as_ref()?).ok(),
+		}
+	}
+}
+
+impl DelegationBuilder {
+	/// Sets the server name to delegate server-server communications to, with
+	/// an optional port.
+	#[must_use]
+	pub fn with_server(mut self, server: &str) -> Self {
+		self.server = Some(server.to_owned());
+		self
+	}
+
+	/// Sets the base URL of the homeserver for the client-server API.
+	#[must_use]
+	pub fn with_homeserver(mut self, base_url: Url) -> Self {
+		self.homeserver = Some(base_url);
+		self
+	}
+
+	/// Sets the base URL of the identity server.
+	#[must_use]
+	pub fn with_identity_server(mut self, base_url: Url) -> Self {
+		self.identity_server = Some(base_url);
+		self
+	}
+
+	/// Sets the URL of the sliding sync proxy.
+	#[must_use]
+	pub fn with_sliding_sync_proxy(mut self, url: Url) -> Self {
+		self.sliding_sync_proxy = Some(url);
+		self
+	}
+
+	/// Sets the page with support information for users.
+	#[must_use]
+	pub fn with_support_page(mut self, url: Url) -> Self {
+		self.support_page = Some(url);
+		self
+	}
+
+	/// Adds a support contact.
+	#[must_use]
+	pub fn with_contact(mut self, contact: Contact) -> Self {
+		self.contacts.push(contact);
+		self
+	}
+
+	/// Sets how long the documents may be cached, one day by default.
+	#[must_use]
+	pub fn with_max_age(mut self, max_age: Duration) -> Self {
+		self.max_age = Some(max_age);
+		self
+	}
+
+	/// Validates the configuration and builds the delegation.
+	pub fn build(self) -> Result<Delegation, Error> {
+		let server = match self.server {
+			Some(server) if is_server_name(&server) => Some(ServerWellKnown { server }),
+			Some(server) => return Err(Error::ServerName(server)),
+			None => None,
+		};
+
+		let urls =
+			[&self.homeserver, &self.identity_server, &self.sliding_sync_proxy, &self.support_page];
+		for url in urls.iter().copied().flatten() {
+			if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+				return Err(Error::Url(url.clone()));
+			}
+		}
+		let client = match self.homeserver {
+			Some(homeserver) => Some(ClientWellKnown {
+				homeserver: HomeserverInfo { base_url: homeserver.to_string() },
+				identity_server: self
+					.identity_server
+					.map(|url| IdentityServerInfo { base_url: url.to_string() }),
+				sliding_sync_proxy: self
+					.sliding_sync_proxy
+					.map(|url| SlidingSyncProxyInfo { url: url.to_string() }),
+				tile_server: None,
+				integrations: None,
+			}),
+			None if self.identity_server.is_some() || self.sliding_sync_proxy.is_some() => {
+				return Err(Error::MissingHomeserver)
+			}
+			None => None,
+		};
+
+		let max_age = self.max_age.unwrap_or(DEFAULT_MAX_AGE);
+		if let Some(contact) = self.contacts.iter().find(|contact| !contact.is_valid()) {
+			return Err(Error::Contact(contact.role.clone()));
+		}
+		let (contacts, support_page) = (self.contacts, self.support_page);
+		let support = (!contacts.is_empty() || support_page.is_some())
+			.then(|| SupportWellKnown { contacts, support_page: support_page.map(String::from) });
+
+		if server.is_none() && client.is_none() && support.is_none() {
+			return Err(Error::Empty);
+		}
+		Ok(Delegation { server, client, support, max_age })
+	}
+}
+
+impl Sites {
+	/// Constructs an empty set of sites.
+	#[must_use]
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Serves the delegation for requests with the given hostname in the
+	/// `Host` header.
+	#[must_use]
+	pub fn with_host(mut self, host: &str, delegation: Delegation) -> Self {
+		self.hosts.insert(normalize(host), Arc::new(delegation));
+		self
+	}
+
+	/// Serves the delegation for requests with other hostnames, or without a
+	/// `Host` header.
+	#[must_use]
+	pub fn with_fallback(mut self, delegation: Delegation) -> Self {
+		self.fallback = Some(Arc::new(delegation));
+		self
+	}
+
+	/// The delegation for the given `Host` header.
+	#[must_use]
+	pub fn delegation(&self, host: Option<&str>) -> Option<&Delegation> {
+		host.and_then(|host| self.hosts.get(&normalize(host)))
+			.or(self.fallback.as_ref())
+			.map(AsRef::as_ref)
+	}
+
+	/// The response to a request for the given path, or `None` if the path is
+	/// not one of the documents. Responses allow any origin, and successful
+	/// ones may be cached for the configured time.
+	#[must_use]
+	pub fn response(
+		&self,
+		method: &Method,
+		host: Option<&str>,
+		path: &str,
+	) -> Option<Response<String>> {
+		let document = match path.strip_prefix("/.well-known/matrix/")? {
+			"server" => Document::Server,
+			"client" => Document::Client,
+			"support" => Document::Support,
+			_ => return None,
+		};
+		let response = Response::builder()
+			.header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+			.header(ACCESS_CONTROL_ALLOW_METHODS, "GET, HEAD, OPTIONS")
+			.header(ACCESS_CONTROL_ALLOW_HEADERS, "X-Requested-With, Content-Type, Authorization");
+		let found = self
+			.delegation(host)
+			.and_then(|delegation| Some((delegation.max_age, delegation.document(document)?)));
+		let response = match (method, found) {
+			(&Method::OPTIONS, _) => response.status(StatusCode::NO_CONTENT).body(String::new()),
+			(&Method::GET | &Method::HEAD, Some((max_age, body))) => response
+				.header(CONTENT_TYPE, "application/json")
+				.header(CACHE_CONTROL, format!("public, max-age={}", max_age.as_secs()))
+				.body(body),
+			(&Method::GET | &Method::HEAD, None) => response
+				.status(StatusCode::NOT_FOUND)
+				.header(CONTENT_TYPE, "application/json")
+				.body(String::from(r#"{"errcode":"M_NOT_FOUND","error":"Not found"}"#)),
+			_ => response
+				.status(StatusCode::METHOD_NOT_ALLOWED)
+				.header(ALLOW, "GET, HEAD, OPTIONS")
+				.body(String::new()),
+		};
+		response.ok()
+	}
+}
+
+/// Constructs the router serving the documents under
+/// `/.well-known/matrix/`.
+pub fn router(sites: Sites) -> Router {
+	Router::new().route("/.well-known/matrix/:document", any(serve)).with_state(Arc::new(sites))
+}
+
+/// Serves a document.
+async fn serve(
+	State(sites): State<Arc<Sites>>,
+	method: Method,
+	headers: HeaderMap,
+	uri: Uri,
+) -> Response<String> {
+	let host = headers.get(HOST).and_then(|host| host.to_str().ok());
+	sites.response(&method, host, uri.path()).unwrap_or_else(|| {
+		let mut response = Response::new(String::new());
+		*response.status_mut() = StatusCode::NOT_FOUND;
+		response
+	})
+}
+
+/// The hostname of a `Host` header, in lowercase and without port or
+/// trailing dot.
+fn normalize(host: &str) -> String {
+	let host = if host.starts_with('[') {
+		host.split_once(']').map_or(host, |(ip, _)| ip.trim_start_matches('['))
+	} else {
+		split_port(host).map_or(host, |(host, _)| host)
+	};
+	host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{net::SocketAddr, time::Duration};
+
+	use reqwest::{header, Method, StatusCode};
+	use serde_json::Value;
+
+	use super::{router, Contact, Delegation, Error, Sites};
+
+	/// Validates the configuration checks of the builder.
+	#[test]
+	fn builder() -> Result<(), Box<dyn std::error::Error>> {
+		let homeserver: reqwest::Url = "https://matrix.example.test".parse()?;
+		assert!(matches!(Delegation::builder().build(), Err(Error::Empty)));
+		assert!(matches!(
+			Delegation::builder().with_server("example.test:99999").build(),
+			Err(Error::ServerName(_))
+		));
+		assert!(matches!(
+			Delegation::builder().with_server("example.test:0").build(),
+			Err(Error::ServerName(_))
+		));
+		assert!(matches!(
+			Delegation::builder().with_identity_server(homeserver.clone()).build(),
+			Err(Error::MissingHomeserver)
+		));
+		assert!(matches!(
+			Delegation::builder().with_homeserver("ftp://example.test".parse()?).build(),
+			Err(Error::Url(_))
+		));
+		assert!(matches!(
+			Delegation::builder().with_contact(Contact::new(Contact::ADMIN)).build(),
+			Err(Error::Contact(_))
+		));
+
+		let delegation = Delegation::builder()
+			.with_server("[2001:db8::1]:443")
+			.with_homeserver(homeserver)
+			.with_contact(Contact::new(Contact::SECURITY).with_matrix_id("@admin:example.test"))
+			.build()?;
+		assert_eq!(
+			delegation.server().map(|server| server.server.as_str()),
+			Some("[2001:db8::1]:443")
+		);
+		assert_eq!(
+			delegation.client().map(|client| client.homeserver.base_url.as_str()),
+			Some("https://matrix.example.test/")
+		);
+		assert_eq!(delegation.support().map(|support| support.contacts.len()), Some(1));
+		Ok(())
+	}
+
+	/// Serves the documents of two domains and checks the headers.
+	#[tokio::test]
+	async fn serve() -> Result<(), Box<dyn std::error::Error>> {
+		let example = Delegation::builder()
+			.with_server("matrix.example.test:443")
+			.with_max_age(Duration::from_secs(60))
+			.build()?;
+		let fallback = Delegation::builder()
+			.with_homeserver("https://matrix.fallback.test".parse()?)
+			.build()?;
+		let sites = Sites::new().with_host("Example.test", example).with_fallback(fallback);
+		let listener = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+			.serve(router(sites).into_make_service());
+		let base = format!("http://{}/.well-known/matrix", listener.local_addr());
+		tokio::spawn(listener);
+		let http = reqwest::Client::new();
+
+		let response = http
+			.get(format!("{}/server", base))
+			.header(header::HOST, "example.test.:8448")
+			.send()
+			.await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+		assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+		assert_eq!(response.headers()[header::CACHE_CONTROL], "public, max-age=60");
+		let server: Value = response.json().await?;
+		assert_eq!(server["m.server"], "matrix.example.test:443");
+
+		let missing = http
+			.get(format!("{}/client", base))
+			.header(header::HOST, "example.test")
+			.send()
+			.await?;
+		assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+		assert_eq!(missing.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+
+		let client: Value = http
+			.get(format!("{}/client", base))
+			.header(header::HOST, "other.test")
+			.send()
+			.await?
+			.json()
+			.await?;
+		assert_eq!(client["m.homeserver"]["base_url"], "https://matrix.fallback.test/");
+		assert!(client.get("m.identity_server").is_none());
+
+		let preflight = http.request(Method::OPTIONS, format!("{}/client", base)).send().await?;
+		assert_eq!(preflight.status(), StatusCode::NO_CONTENT);
+		assert_eq!(preflight.headers()[header::ACCESS_CONTROL_ALLOW_METHODS], "GET, HEAD, OPTIONS");
+
+		let post = http.post(format!("{}/client", base)).send().await?;
+		assert_eq!(post.status(), StatusCode::METHOD_NOT_ALLOWED);
+		let unknown = http.get(format!("{}/unknown", base)).send().await?;
+		assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+		Ok(())
+	}
+}
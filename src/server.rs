@@ -1,18 +1,52 @@
 //! Resolution for the server-server API
 
-use std::net::{IpAddr, SocketAddr};
+use std::{
+	net::{IpAddr, SocketAddr},
+	sync::Arc,
+	time::{Duration, Instant},
+};
 
+use reqwest::{
+	header::{HeaderMap, CACHE_CONTROL},
+	Url,
+};
 use reqwest_middleware::ClientWithMiddleware;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info, instrument};
 use trust_dns_resolver::{
 	error::{ResolveError, ResolveErrorKind},
+	lookup::SrvLookup,
+	proto::{op::ResponseCode, rr::RData},
 	TokioAsyncResolver,
 };
+use url::Host;
 
-use crate::cache;
+use self::{
+	dns::DnsResolver,
+	filter::IpFilter,
+	trace::{DnsTrace, HttpTrace, ResolutionTrace, SrvRecord, Step},
+	warning::Warning,
+};
+#[cfg(feature = "overrides")]
+use crate::overrides::Overrides;
+use crate::{
+	cache::{CachedServer, SharedCache},
+	fetch::{self, FetchPolicy},
+};
 
+pub mod diagnose;
+pub mod dns;
 pub mod error;
+pub mod federation;
+pub mod filter;
+#[cfg(feature = "keys")]
+pub mod keys;
+#[cfg(test)]
+pub(crate) mod test_dns;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+pub mod tls;
+pub mod trace;
+pub mod warning;
 
 /// well-known information about the delegated server for server-server
 /// communication.
@@ -31,14 +65,74 @@ pub struct ServerWellKnown {
 /// Client for server-server well-known lookups.
 #[derive(Debug, Clone)]
 pub struct Resolver {
+	/// Settings of the HTTP clients, applied whenever a client is built.
+	settings: ClientSettings,
+	/// HTTP client without the caching middleware.
+	client: reqwest::Client,
+	/// Whether the HTTP client was passed in by the caller, so it isn't
+	/// rebuilt with the settings.
+	fixed_client: bool,
 	/// HTTP client.
 	http: ClientWithMiddleware,
+	/// Cache of HTTP responses.
+	cache: SharedCache,
 	/// DNS resolver.
 	resolver: TokioAsyncResolver,
+	/// IP ranges the server name may resolve to.
+	filter: IpFilter,
+	/// Limits for fetching the .well-known information.
+	fetch_policy: FetchPolicy,
+	/// Whether resolutions are stored in the cache and reused until they
+	/// expire.
+	cache_resolutions: bool,
+	/// How long cached resolutions are kept after they expire.
+	stale_grace: Duration,
+	/// Whether SRV targets are checked for CNAMEs and plain HTTP.
+	check_delegation: bool,
+	/// How long requests to the federation API of resolved servers may take.
+	federation_timeout: Duration,
+	/// Clients for requests to the federation API of resolved servers.
+	federation_clients: federation::Clients,
+	/// Static overrides taking precedence over resolution.
+	#[cfg(feature = "overrides")]
+	overrides: Overrides,
+}
+
+/// Settings of the HTTP clients used by a [`Resolver`].
+#[derive(Clone)]
+struct ClientSettings(Arc<dyn Fn() -> reqwest::ClientBuilder + Send + Sync>);
+
+impl std::fmt::Debug for ClientSettings {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ClientSettings").finish_non_exhaustive()
+	}
+}
+
+impl ClientSettings {
+	/// Build a client which looks up hostnames with the given DNS resolver and
+	/// only connects to addresses allowed by the filter, so the addresses
+	/// checked are the ones connected to.
+	fn build(
+		&self,
+		resolver: &TokioAsyncResolver,
+		filter: &IpFilter,
+	) -> Result<reqwest::Client, reqwest::Error> {
+		let dns = DnsResolver::filtered(resolver.clone(), filter.clone());
+		fetch::configure((self.0)()).dns_resolver(Arc::new(dns)).build()
+	}
+}
+
+/// Resolution of server names for the server-server API, implemented by
+/// [`Resolver`]. Code depending on this trait instead of the resolver can be
+/// given fixed results in tests, see the `testing` feature.
+#[async_trait::async_trait]
+pub trait ServerResolve: Send + Sync {
+	/// Resolve the given server name.
+	async fn resolve_server(&self, name: &str) -> error::Result<Server>;
 }
 
 /// Resolved server name
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum Server {
 	/// IP address with implicit default port (8448)
 	Ip(IpAddr),
@@ -50,6 +144,30 @@ pub enum Server {
 	HostPort(String),
 	/// Address from srv record, hostname from server name.
 	Srv(String, String),
+	/// Address from an override, with the hostname for the `Host` header and
+	/// TLS certificate.
+	Override {
+		/// The hostname or IP address to connect to.
+		address: String,
+		/// The port to connect to.
+		port: u16,
+		/// The hostname for the `Host` header and TLS certificate.
+		host: String,
+	},
+}
+
+/// The result of resolving a server name.
+#[derive(Debug, Clone, Serialize)]
+pub struct Resolution {
+	/// The resolved server.
+	pub server: Server,
+	/// The steps taken to arrive at the server.
+	pub trace: ResolutionTrace,
+	/// Whether the resolution failed or was degraded, and the server is the
+	/// last good result from the cache instead.
+	pub stale: bool,
+	/// Misconfigurations of the delegation noticed while resolving.
+	pub warnings: Vec<Warning>,
 }
 
 impl Server {
@@ -62,6 +180,23 @@ impl Server {
 			Server::Host(host) => host.clone(),
 			Server::HostPort(host) => host.clone(),
 			Server::Srv(_, host) => host.to_string(),
+			Server::Override { host, .. } => host.clone(),
+		}
+	}
+
+	/// The hostname or IP address the TLS certificate of the server must be
+	/// valid for, which is also sent in the SNI extension.
+	#[must_use]
+	pub fn tls_name(&self) -> String {
+		match self {
+			Server::Ip(addr) => addr.to_string(),
+			Server::Socket(addr) => addr.ip().to_string(),
+			Server::Host(host) => host.clone(),
+			Server::HostPort(host) => {
+				split_port(host).map_or(host.as_str(), |(host, _)| host).to_owned()
+			}
+			Server::Srv(_, host) => host.clone(),
+			Server::Override { host, .. } => host.clone(),
 		}
 	}
 
@@ -74,6 +209,10 @@ impl Server {
 			Server::Host(host) => format!("{}:8448", host),
 			Server::HostPort(host) => host.clone(),
 			Server::Srv(host, _) => host.clone(),
+			Server::Override { address, port, .. } => match address.parse::<IpAddr>() {
+				Ok(ip) => SocketAddr::new(ip, *port).to_string(),
+				Err(_) => format!("{}:{}", address, port),
+			},
 		}
 	}
 }
@@ -81,130 +220,500 @@ impl Server {
 impl Resolver {
 	/// Constructs a new client.
 	pub fn new() -> Result<Self, ResolveError> {
-		Ok(Self {
-			http: reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
-				.with(cache())
-				.build(),
-			resolver: TokioAsyncResolver::tokio_from_system_conf()?,
-		})
+		let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+		#[allow(clippy::expect_used)]
+		Ok(Self::with_builder(reqwest::Client::builder, resolver)
+			.expect("the TLS backend and resolver can be initialized"))
 	}
 
 	/// Constructs a new client with the given HTTP client and DNS resolver
-	/// instances.
+	/// instances. The HTTP client must not follow redirects itself, or
+	/// responses with redirects are rejected, see [`FetchPolicy`]. It is kept
+	/// as it is, so the [IP filter](Self::with_ip_filter) is only applied
+	/// before requests are sent, and clients for the federation API are built
+	/// with the default settings. Prefer [`with_builder`](Self::with_builder).
 	#[must_use]
 	pub fn with(http: reqwest::Client, resolver: TokioAsyncResolver) -> Self {
-		Self { http: reqwest_middleware::ClientBuilder::new(http).with(cache()).build(), resolver }
+		let settings = ClientSettings(Arc::new(reqwest::Client::builder));
+		Self::from_parts(settings, http, true, resolver)
+	}
+
+	/// Constructs a new client with the given DNS resolver instance and HTTP
+	/// clients built with the given settings. The settings are applied again
+	/// whenever a client is rebuilt, like when the IP filter changes. Hostnames
+	/// are always looked up with the DNS resolver, unless the settings
+	/// override them with [`reqwest::ClientBuilder::resolve`], and redirects
+	/// are never followed by the clients, see [`FetchPolicy`].
+	pub fn with_builder<F>(http: F, resolver: TokioAsyncResolver) -> Result<Self, reqwest::Error>
+	where
+		F: Fn() -> reqwest::ClientBuilder + Send + Sync + 'static,
+	{
+		let settings = ClientSettings(Arc::new(http));
+		let client = settings.build(&resolver, &IpFilter::default())?;
+		Ok(Self::from_parts(settings, client, false, resolver))
+	}
+
+	/// Constructs a new client with the default options.
+	fn from_parts(
+		settings: ClientSettings,
+		client: reqwest::Client,
+		fixed_client: bool,
+		resolver: TokioAsyncResolver,
+	) -> Self {
+		let cache = SharedCache::default();
+		Self {
+			settings,
+			http: cache.client(client.clone()),
+			client,
+			fixed_client,
+			cache,
+			resolver,
+			filter: IpFilter::default(),
+			fetch_policy: FetchPolicy::default(),
+			cache_resolutions: false,
+			stale_grace: Duration::ZERO,
+			check_delegation: false,
+			federation_timeout: federation::DEFAULT_TIMEOUT,
+			federation_clients: federation::clients(),
+			#[cfg(feature = "overrides")]
+			overrides: Overrides::default(),
+		}
+	}
+
+	/// Restricts the addresses server names may resolve to. The filter is
+	/// applied to IP literals, delegated addresses, DNS lookups and the
+	/// addresses the .well-known information and its redirects are fetched
+	/// from, before the requests are sent. Unless it was passed to
+	/// [`with`](Self::with), the HTTP client is rebuilt to only connect to
+	/// allowed addresses.
+	#[must_use]
+	pub fn with_ip_filter(mut self, filter: IpFilter) -> Self {
+		if !self.fixed_client {
+			#[allow(clippy::expect_used)]
+			let client = self
+				.settings
+				.build(&self.resolver, &filter)
+				.expect("the client was built with the same settings before");
+			self.http = self.cache.client(client.clone());
+			self.client = client;
+		}
+		self.filter = filter;
+		self
+	}
+
+	/// Sets the limits for fetching the .well-known information.
+	#[must_use]
+	pub fn with_fetch_policy(mut self, policy: FetchPolicy) -> Self {
+		self.fetch_policy = policy;
+		self
+	}
+
+	/// Sets the cache of HTTP responses, which may be shared with other
+	/// resolvers.
+	#[must_use]
+	pub fn with_cache(mut self, cache: SharedCache) -> Self {
+		self.http = cache.client(self.client.clone());
+		self.cache = cache;
+		self
+	}
+
+	/// Sets whether resolutions are stored in the cache and reused until
+	/// they expire, see [`ResolutionTrace::ttl`]. Only
+	/// [`resolve`](Self::resolve) reuses them,
+	/// [`resolve_traced`](Self::resolve_traced) always runs the
+	/// full resolution.
+	#[must_use]
+	pub fn with_resolution_caching(mut self, enabled: bool) -> Self {
+		self.cache_resolutions = enabled;
+		self
+	}
+
+	/// Sets how long cached resolutions are kept after they expire. Within
+	/// this period, [`resolve`](Self::resolve) returns the expired result
+	/// while refreshing it in the background, and the last good result is
+	/// used when resolving again fails. Defaults to zero, which disables both.
+	#[must_use]
+	pub fn with_stale_grace(mut self, grace: Duration) -> Self {
+		self.stale_grace = grace;
+		self
+	}
+
+	/// Sets whether resolution checks that SRV targets aren't CNAMEs and don't
+	/// speak plain HTTP on port 443, which takes additional requests. The
+	/// other [warnings](Resolution::warnings) are always reported.
+	#[must_use]
+	pub fn with_delegation_checks(mut self, check: bool) -> Self {
+		self.check_delegation = check;
+		self
+	}
+
+	/// Sets how long requests to the federation API of resolved servers may
+	/// take, like querying their [version](Self::version). Defaults to 30
+	/// seconds.
+	#[must_use]
+	pub fn with_federation_timeout(mut self, timeout: Duration) -> Self {
+		self.federation_timeout = timeout;
+		self.federation_clients = federation::clients();
+		self
+	}
+
+	/// Sets static overrides, which take precedence over resolution. Their
+	/// results aren't cached, but resolutions cached before, or by other
+	/// resolvers sharing the cache, are reused until they expire. The IP filter
+	/// isn't applied to overridden servers until their
+	/// [sockets](Self::sockets) are looked up.
+	#[cfg(feature = "overrides")]
+	#[must_use]
+	pub fn with_overrides(mut self, overrides: Overrides) -> Self {
+		self.overrides = overrides;
+		self
+	}
+
+	/// The cache of HTTP responses, for inspecting and purging its entries.
+	#[must_use]
+	pub fn cache(&self) -> &SharedCache {
+		&self.cache
 	}
 
 	/// Resolve the given server name
-	#[instrument(skip(self, port), err)]
 	pub async fn resolve(
 		&self,
 		name: &str,
 		#[cfg(test)] port: Option<u16>,
 	) -> error::Result<Server> {
+		if self.cache_resolutions {
+			match self.cache.resolution(name) {
+				Some(CachedServer { server, stale: false }) => {
+					debug!("Reusing cached resolution");
+					return Ok(server);
+				}
+				Some(CachedServer { server, stale: true }) => {
+					if self.cache.start_refresh(name) {
+						debug!("Serving stale resolution while refreshing it");
+						let resolver = self.clone();
+						let name = name.to_owned();
+						tokio::spawn(async move {
+							// Failures are logged by resolve_traced
+							let _ = resolver
+								.resolve_traced(
+									&name,
+									#[cfg(test)]
+									port,
+								)
+								.await;
+							resolver.cache.finish_refresh(&name);
+						});
+					}
+					return Ok(server);
+				}
+				None => {}
+			}
+		}
+		let resolution = self
+			.resolve_traced(
+				name,
+				#[cfg(test)]
+				port,
+			)
+			.await?;
+		Ok(resolution.server)
+	}
+
+	/// Resolve the given server name, recording every step taken.
+	///
+	/// With resolution caching enabled, the last good result is returned
+	/// instead if the resolution fails or [is
+	/// degraded](ResolutionTrace::is_degraded), as long as it is within the
+	/// grace period. [`Resolution::stale`] is set in that case. Results
+	/// denied by the IP filter are never replaced, and degraded results are
+	/// never cached.
+	#[instrument(skip(self, port), err)]
+	pub async fn resolve_traced(
+		&self,
+		name: &str,
+		#[cfg(test)] port: Option<u16>,
+	) -> error::Result<Resolution> {
+		let started = Instant::now();
+		let mut trace = ResolutionTrace::new(name);
+		let result = self
+			.resolve_steps(
+				name,
+				&mut trace,
+				#[cfg(test)]
+				port,
+			)
+			.await;
+		let mut warnings = warning::check_trace(name, &trace, result.as_ref().ok());
+		if let Ok(Server::Srv(target, _)) = &result {
+			self.check_srv_target(
+				target,
+				&mut warnings,
+				#[cfg(test)]
+				port,
+			)
+			.await;
+		}
+		trace.elapsed = started.elapsed();
+		if !self.cache_resolutions {
+			return Ok(Resolution { server: result?, trace, stale: false, warnings });
+		}
+		let denied = matches!(result, Err(error::Error::Denied(_)));
+		if !denied && (result.is_err() || trace.is_degraded()) {
+			if let Some(cached) = self.cache.resolution(name) {
+				info!("Resolution failed, serving the last good result");
+				return Ok(Resolution { server: cached.server, trace, stale: true, warnings });
+			}
+		}
+		let server = result?;
+		if trace.is_degraded() {
+			return Ok(Resolution { server, trace, stale: false, warnings });
+		}
+		if let Some(ttl) = trace.ttl() {
+			self.cache.insert_resolution(name, &server, ttl, self.stale_grace);
+		}
+		Ok(Resolution { server, trace, stale: false, warnings })
+	}
+
+	/// Run the resolution algorithm, recording each step in the trace.
+	async fn resolve_steps(
+		&self,
+		name: &str,
+		trace: &mut ResolutionTrace,
+		#[cfg(test)] port: Option<u16>,
+	) -> error::Result<Server> {
+		#[cfg(feature = "overrides")]
+		{
+			let started = Instant::now();
+			if let Some((pattern, server)) = self.overrides.server(name).await {
+				info!("The server name matches the override {}", pattern);
+				trace.push(Step::Override, true, started);
+				trace.matched_override = Some(pattern);
+				return Ok(server);
+			}
+		}
 		// 1. The host is an ip literal
+		let started = Instant::now();
 		debug!("Parsing socket literal");
 		if let Ok(addr) = name.parse::<SocketAddr>() {
 			info!("The server name is a socket literal");
+			trace.push(Step::IpLiteral, true, started);
+			self.filter.check(addr.ip())?;
 			return Ok(Server::Socket(addr));
 		}
 		debug!("Parsing IP literal");
 		if let Ok(addr) = name.parse::<IpAddr>() {
 			info!("The server name is an IP literal");
+			trace.push(Step::IpLiteral, true, started);
+			self.filter.check(addr)?;
 			return Ok(Server::Ip(addr));
 		}
+		trace.push(Step::IpLiteral, false, started);
 		// 2. The host is not an ip literal, but includes a port
+		let started = Instant::now();
 		debug!("Parsing host with port");
 		if split_port(name).is_some() {
 			info!("The servername is a host with port");
+			trace.push(Step::HostPort, true, started);
 			return Ok(Server::HostPort(name.to_owned()));
 		}
+		trace.push(Step::HostPort, false, started);
 		// 3. Query the .well-known endpoint
+		let started = Instant::now();
 		debug!("Querying well known");
-		if let Some(well_known) = self
+		let mut http = HttpTrace::default();
+		let well_known = self
 			.well_known(
 				name,
+				&mut http,
 				#[cfg(test)]
 				port,
 			)
-			.await?
-		{
+			.await;
+		let received = matches!(well_known, Ok(Some(_)));
+		trace.push(Step::WellKnown, received, started).http = Some(http);
+		if let Some(well_known) = well_known? {
 			debug!("Well-known received: {:?}", &well_known);
 			// 3.1 delegated_hostname is an ip literal
+			let started = Instant::now();
 			debug!("Parsing delegated socket literal");
 			if let Ok(addr) = well_known.server.parse::<SocketAddr>() {
 				info!("The server name is a delegated IP literal");
+				trace.push(Step::DelegatedIpLiteral, true, started);
+				self.filter.check(addr.ip())?;
 				return Ok(Server::Socket(addr));
 			}
 			debug!("Parsing delegated IP literal");
 			if let Ok(addr) = well_known.server.parse::<IpAddr>() {
 				info!("The server name is a delegated socket literal");
+				trace.push(Step::DelegatedIpLiteral, true, started);
+				self.filter.check(addr)?;
 				return Ok(Server::Ip(addr));
 			}
+			trace.push(Step::DelegatedIpLiteral, false, started);
 			// 3.2 delegated_hostname includes a port
+			let started = Instant::now();
 			debug!("Parsing delegated hostname with port");
 			if split_port(&well_known.server).is_some() {
 				info!("The server name is a delegated hostname with port");
+				trace.push(Step::DelegatedHostPort, true, started);
 				return Ok(Server::HostPort(well_known.server));
 			}
+			trace.push(Step::DelegatedHostPort, false, started);
 			// 3.3 Look up SRV record
 			debug!("Looking up SRV record for delegated hostname");
-			if let Some(name) = self.srv_lookup(&well_known.server).await {
+			let query = format!("_matrix-fed._tcp.{}", well_known.server);
+			if let Some(name) = self.srv_step(&query, Step::DelegatedSrv, trace).await {
 				info!("The server name is a delegated SRV record");
 				return Ok(Server::Srv(name, well_known.server));
 			}
-			// 3.4 Use hostname in .well-known
+			// 3.4 Look up deprecated SRV record
+			debug!("Looking up deprecated SRV record for delegated hostname");
+			let query = format!("_matrix._tcp.{}", well_known.server);
+			if let Some(name) = self.srv_step(&query, Step::DelegatedSrvDeprecated, trace).await {
+				info!("The server name is a deprecated delegated SRV record");
+				return Ok(Server::Srv(name, well_known.server));
+			}
+			// 3.5 Use hostname in .well-known
 			debug!("Using delegated hostname directly");
+			trace.push(Step::DelegatedHost, true, Instant::now());
 			return Ok(Server::Host(well_known.server));
 		}
 		// 4. The .well-known lookup failed, query SRV
 		debug!("Looking up SRV record for hostname");
-		if let Some(srv) = self.srv_lookup(name).await {
+		let query = format!("_matrix-fed._tcp.{}", name);
+		if let Some(srv) = self.srv_step(&query, Step::Srv, trace).await {
 			info!("The server name is an SRV record");
 			return Ok(Server::Srv(srv, name.to_owned()));
 		}
-		// 5. No SRV record found, use hostname
+		// 5. Query deprecated SRV
+		debug!("Looking up deprecated SRV record for hostname");
+		let query = format!("_matrix._tcp.{}", name);
+		if let Some(srv) = self.srv_step(&query, Step::SrvDeprecated, trace).await {
+			info!("The server name is a deprecated SRV record");
+			return Ok(Server::Srv(srv, name.to_owned()));
+		}
+		// 6. No SRV record found, use hostname
 		debug!("Using provided hostname directly");
+		trace.push(Step::Host, true, Instant::now());
 		Ok(Server::Host(name.to_owned()))
 	}
 
-	/// Query the .well-known information for a host.
+	/// Query the .well-known information for a host, recording the request in
+	/// the trace.
 	#[cfg_attr(test, allow(unused_variables))]
-	#[instrument(skip(self, name, port), err)]
+	#[instrument(skip(self, name, trace, port), err)]
 	async fn well_known(
 		&self,
 		name: &str,
+		trace: &mut HttpTrace,
 		#[cfg(test)] port: Option<u16>,
 	) -> error::Result<Option<ServerWellKnown>> {
 		#[cfg(not(test))]
-		let response = self.http.get(format!("https://{}/.well-known/matrix/server", name)).send().await;
+		let url = Url::parse(&format!("https://{}/.well-known/matrix/server", name));
 
 		#[cfg(test)]
 		#[allow(clippy::expect_used)]
-		let response = self
-			.http
-			.get(format!(
-				"http://{name}:{port}/.well-known/matrix/server",
-				port = port.expect("port needed for test env")
-			))
-			.send()
-			.await;
+		let url = Url::parse(&format!(
+			"http://{name}:{port}/.well-known/matrix/server",
+			port = port.expect("port needed for test env")
+		));
 
+		let url = match url {
+			Ok(url) => url,
+			Err(e) => {
+				trace.error = Some(e.to_string());
+				return Ok(None);
+			}
+		};
+		trace.urls.push(url.to_string());
 		// Only return Err on connection failure, skip to next step for other errors.
-		let response = match response {
+		let check = |url| self.check_url(url);
+		let fetched = match fetch::get_checked(&self.http, url, &self.fetch_policy, check).await {
+			Ok(fetched) => fetched,
+			// A host without addresses has no .well-known information
+			Err(error::Error::Dns(e))
+				if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) =>
+			{
+				trace.error = Some(e.to_string());
+				return Ok(None);
+			}
+			Err(e) => return Err(e),
+		};
+		let response = match fetched {
 			Ok(response) => response,
-			Err(reqwest_middleware::Error::Reqwest(e)) if e.is_connect() => return Err(e.into()),
-			Err(_) => return Ok(None),
+			Err(e) => {
+				trace.error = Some(e.to_string());
+				if let fetch::Error::Http(reqwest_middleware::Error::Reqwest(e)) = e {
+					if e.is_connect() {
+						return Err(e.into());
+					}
+				}
+				debug!("Fetching .well-known failed: {:?}", trace.error);
+				return Ok(None);
+			}
+		};
+		trace.urls = response.hops.iter().map(|hop| hop.url.to_string()).collect();
+		trace.status = Some(response.status().as_u16());
+		trace.max_age = max_age(&response.headers);
+		trace.body = Some(String::from_utf8_lossy(&response.body).into_owned());
+		// Cached responses may have been received before the filter changed.
+		for addr in response.hops.iter().filter_map(|hop| hop.remote_addr) {
+			self.filter.check(addr.ip())?;
+		}
+		if !response.status().is_success() {
+			debug!("The .well-known request to {} returned {}", response.url(), response.status());
+			return Ok(None);
+		}
+		let well_known = match response.json::<ServerWellKnown>() {
+			Ok(well_known) => Some(well_known),
+			Err(e) => {
+				debug!("Invalid .well-known response: {}", e);
+				trace.error = Some(e.to_string());
+				None
+			}
 		};
-		let well_known = response.json::<ServerWellKnown>().await.ok();
 		Ok(well_known)
 	}
 
-	/// Query the matrix SRV DNS record for a hostname
-	#[instrument(skip(self, name))]
-	async fn srv_lookup(&self, name: &str) -> Option<String> {
-		let srv = self.resolver.srv_lookup(format!("_matrix._tcp.{}", name)).await.ok()?;
+	/// Checks the addresses the host of a URL resolves to before it is
+	/// requested, so nothing is sent to denied addresses. URLs whose host
+	/// can't be looked up are rejected as well, as their addresses are unknown.
+	async fn check_url(&self, url: Url) -> error::Result<()> {
+		if self.filter.is_permissive() {
+			return Ok(());
+		}
+		match url.host() {
+			Some(Host::Ipv4(ip)) => self.filter.check(ip.into()),
+			Some(Host::Ipv6(ip)) => self.filter.check(ip.into()),
+			Some(Host::Domain(domain)) => {
+				for ip in self.resolver.lookup_ip(domain).await?.iter() {
+					self.filter.check(ip)?;
+				}
+				Ok(())
+			}
+			None => Ok(()),
+		}
+	}
+
+	/// Query the given SRV record as a step of the resolution, returning the
+	/// target with the lowest priority value.
+	async fn srv_step(
+		&self,
+		query: &str,
+		step: Step,
+		trace: &mut ResolutionTrace,
+	) -> Option<String> {
+		let started = Instant::now();
+		let mut dns = DnsTrace::default();
+		let srv = self.srv_lookup(query, &mut dns).await;
+		trace.push(step, srv.is_some(), started).dns = Some(dns);
+		srv
+	}
+
+	/// Query an SRV DNS record, recording the lookup in the trace.
+	#[instrument(skip(self, query, trace))]
+	async fn srv_lookup(&self, query: &str, trace: &mut DnsTrace) -> Option<String> {
+		let srv = self.lookup_srv(query, trace).await.ok()?;
 		// Get a record with the lowest priority value
 		match srv.iter().min_by_key(|srv| srv.priority()) {
 			Some(srv) => {
@@ -216,29 +725,131 @@ impl Resolver {
 		}
 	}
 
+	/// Look up the SRV records with the given name, recording the lookup in
+	/// the trace.
+	async fn lookup_srv(
+		&self,
+		query: &str,
+		trace: &mut DnsTrace,
+	) -> Result<SrvLookup, ResolveError> {
+		trace.query = query.to_owned();
+		let srv = match self.resolver.srv_lookup(query).await {
+			Ok(srv) => srv,
+			Err(e) => {
+				trace.error = Some(e.to_string());
+				trace.transient = match e.kind() {
+					ResolveErrorKind::NoRecordsFound { response_code, .. } => {
+						*response_code == ResponseCode::ServFail
+					}
+					_ => true,
+				};
+				return Err(e);
+			}
+		};
+		for record in srv.as_lookup().record_iter() {
+			if let Some(RData::SRV(data)) = record.data() {
+				trace.records.push(SrvRecord {
+					target: data.target().to_ascii().trim_end_matches('.').to_owned(),
+					port: data.port(),
+					priority: data.priority(),
+					weight: data.weight(),
+					ttl: record.ttl(),
+				});
+			}
+		}
+		Ok(srv)
+	}
+
 	/// Get the [`SocketAddr`] of an address
-	pub async fn socket(&self, server: &Server) -> Result<SocketAddr, ResolveError> {
+	pub async fn socket(&self, server: &Server) -> error::Result<SocketAddr> {
+		// We naively get the first IP.
+		let socket = self
+			.sockets(server)
+			.await?
+			.into_iter()
+			.next()
+			.ok_or_else(|| ResolveError::from(ResolveErrorKind::Message("No records")))?;
+		Ok(socket)
+	}
+
+	/// Get every [`SocketAddr`] an address resolves to, in the order returned
+	/// by the DNS resolver. Addresses rejected by the IP filter are left out,
+	/// [`Error::Denied`](error::Error::Denied) is returned if none remain.
+	pub async fn sockets(&self, server: &Server) -> error::Result<Vec<SocketAddr>> {
 		let (host, port) = match *server {
-			Server::Ip(ip) => return Ok(SocketAddr::new(ip, 8448)),
-			Server::Socket(socket) => return Ok(socket),
+			Server::Ip(ip) => {
+				self.filter.check(ip)?;
+				return Ok(vec![SocketAddr::new(ip, 8448)]);
+			}
+			Server::Socket(socket) => {
+				self.filter.check(socket.ip())?;
+				return Ok(vec![socket]);
+			}
 			Server::Host(ref host) => (host.as_str(), 8448),
 			#[allow(clippy::expect_used)]
 			Server::HostPort(ref host) => split_port(host).expect("HostPort was constructed with port"),
 			#[allow(clippy::expect_used)]
 			Server::Srv(ref addr, _) => split_port(addr).expect("The SRV record includes the port"),
+			Server::Override { ref address, port, .. } => match address.parse::<IpAddr>() {
+				Ok(ip) => {
+					self.filter.check(ip)?;
+					return Ok(vec![SocketAddr::new(ip, port)]);
+				}
+				Err(_) => (address.as_str(), port),
+			},
 		};
-		let record = self.resolver.lookup_ip(host).await?;
-		// We naively get the first IP.
-		let socket = SocketAddr::new(
-			record.iter().next().ok_or(ResolveErrorKind::Message("No records"))?,
-			port,
-		);
-		Ok(socket)
+		lookup_sockets(&self.resolver, &self.filter, host, port).await
+	}
+
+	/// Returns an adapter which lets a reqwest client resolve hostnames with
+	/// this resolver's DNS configuration and cache.
+	#[must_use]
+	pub fn dns_resolver(&self) -> Arc<DnsResolver> {
+		Arc::new(DnsResolver::new(self.clone()))
+	}
+}
+
+#[async_trait::async_trait]
+impl ServerResolve for Resolver {
+	async fn resolve_server(&self, name: &str) -> error::Result<Server> {
+		Resolver::resolve(
+			self,
+			name,
+			#[cfg(test)]
+			None,
+		)
+		.await
 	}
 }
 
+/// Look up the addresses of a hostname, leaving out the ones rejected by the
+/// filter. [`Error::Denied`](error::Error::Denied) is returned if none remain.
+async fn lookup_sockets(
+	resolver: &TokioAsyncResolver,
+	filter: &IpFilter,
+	host: &str,
+	port: u16,
+) -> error::Result<Vec<SocketAddr>> {
+	let record = resolver.lookup_ip(host).await?;
+	let (allowed, denied): (Vec<_>, Vec<_>) = record.iter().partition(|ip| filter.is_allowed(*ip));
+	match (allowed.is_empty(), denied.first()) {
+		(true, Some(ip)) => Err(error::Error::Denied(*ip)),
+		_ => Ok(allowed.into_iter().map(|ip| SocketAddr::new(ip, port)).collect()),
+	}
+}
+
+/// The `max-age` directive of the `Cache-Control` header, if there is one.
+fn max_age(headers: &HeaderMap) -> Option<u64> {
+	headers
+		.get_all(CACHE_CONTROL)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|value| value.split(','))
+		.find_map(|directive| directive.trim().strip_prefix("max-age=")?.parse().ok())
+}
+
 /// Get the port at the end of a host string if there is one.
-fn split_port(host: &str) -> Option<(&str, u16)> {
+pub(crate) fn split_port(host: &str) -> Option<(&str, u16)> {
 	match &host.split(':').collect::<Vec<_>>()[..] {
 		[host, port] => match port.parse() {
 			Ok(port) => Some((host, port)),
@@ -250,15 +861,21 @@ fn split_port(host: &str) -> Option<(&str, u16)> {
 
 #[cfg(test)]
 mod tests {
-	use std::net::{IpAddr, SocketAddr};
+	use std::{
+		net::{IpAddr, Ipv4Addr, SocketAddr},
+		time::Duration,
+	};
 
-	use trust_dns_resolver::TokioAsyncResolver;
+	use trust_dns_resolver::{
+		proto::rr::{rdata::SRV, Name, RData, Record},
+		TokioAsyncResolver,
+	};
 	use wiremock::{
 		matchers::{method, path},
 		Mock, MockServer, ResponseTemplate,
 	};
 
-	use super::{Resolver, Server};
+	use super::{error::Error, filter::IpFilter, test_dns, trace::Step, Resolver, Server};
 
 	/// Validates correct parsing of IP literals and server name with port
 	#[tokio::test]
@@ -290,6 +907,7 @@ mod tests {
 		let client = reqwest::Client::builder()
 			.resolve("example.test", *mock_server.address())
 			.resolve("destination.test", *mock_server.address())
+			.redirect(reqwest::redirect::Policy::none())
 			.build()?;
 		let resolver = Resolver::with(client, TokioAsyncResolver::tokio_from_system_conf()?);
 
@@ -349,4 +967,312 @@ mod tests {
 		);
 		Ok(())
 	}
+
+	/// Validates that addresses in denied ranges are rejected at every step,
+	/// before any request is sent to them.
+	#[tokio::test]
+	async fn denied() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+		let addr = mock_server.address();
+		let metadata = IpAddr::from([169, 254, 169, 254]);
+
+		let socket = *addr;
+		let client = move || {
+			reqwest::Client::builder()
+				.resolve("example.test", socket)
+				.resolve("metadata.test", socket)
+		};
+		let dns = test_dns::resolver(vec![
+			Record::from_rdata(
+				Name::from_ascii("example.test.")?,
+				300,
+				RData::A(Ipv4Addr::LOCALHOST),
+			),
+			Record::from_rdata(
+				Name::from_ascii("metadata.test.")?,
+				300,
+				RData::A(Ipv4Addr::new(169, 254, 169, 254)),
+			),
+			Record::from_rdata(
+				Name::from_ascii("_matrix._tcp.nowhere.test.")?,
+				300,
+				RData::SRV(SRV::new(10, 5, 8448, Name::from_ascii("destination.test.")?)),
+			),
+		])
+		.await?;
+		let resolver = Resolver::with_builder(client, dns)?;
+
+		let loopback = resolver.clone().with_ip_filter(IpFilter::federation_default());
+		assert!(
+			matches!(loopback.resolve("127.0.0.1:8448", None).await, Err(Error::Denied(_))),
+			"1. Socket literal"
+		);
+		assert!(
+			matches!(
+				loopback.resolve("example.test", Some(addr.port())).await,
+				Err(Error::Denied(ip)) if ip == addr.ip()
+			),
+			"3. Address of the .well-known endpoint"
+		);
+		assert!(
+			matches!(
+				loopback.socket(&Server::Host(String::from("example.test"))).await,
+				Err(Error::Denied(_))
+			),
+			"DNS lookup"
+		);
+		assert!(mock_server.received_requests().await.unwrap_or_default().is_empty());
+
+		let link_local = resolver.with_ip_filter(IpFilter::new().deny("169.254.0.0/16".parse()?));
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/server"))
+			.respond_with(ResponseTemplate::new(302).insert_header(
+				"Location",
+				format!("http://metadata.test:{}/latest/meta-data", addr.port()).as_str(),
+			))
+			.expect(1)
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/latest/meta-data"))
+			.respond_with(ResponseTemplate::new(200))
+			.expect(0)
+			.mount(&mock_server)
+			.await;
+		assert!(
+			matches!(
+				link_local.resolve("example.test", Some(addr.port())).await,
+				Err(Error::Denied(ip)) if ip == metadata
+			),
+			"3. Redirect of the .well-known request"
+		);
+		mock_server.verify().await;
+
+		mock_server.reset().await;
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/server"))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.set_body_raw(r#"{"m.server": "169.254.169.254"}"#, "application/json"),
+			)
+			.expect(1)
+			.mount(&mock_server)
+			.await;
+		assert!(
+			matches!(
+				link_local.resolve("example.test", Some(addr.port())).await,
+				Err(Error::Denied(ip)) if ip == metadata
+			),
+			"3.1 delegated_hostname is an IP literal"
+		);
+		assert_eq!(
+			link_local.resolve("nowhere.test", Some(addr.port())).await?,
+			Server::Srv(String::from("destination.test:8448"), String::from("nowhere.test")),
+			"4. The .well-known host has no addresses"
+		);
+		Ok(())
+	}
+
+	/// Validates the recorded steps when falling back to an SRV record.
+	#[tokio::test]
+	async fn trace() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+		let addr = mock_server.address();
+
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/server"))
+			.respond_with(ResponseTemplate::new(404).set_body_string("Not found"))
+			.expect(2)
+			.mount(&mock_server)
+			.await;
+
+		let dns = test_dns::resolver(vec![
+			Record::from_rdata(
+				Name::from_ascii("_matrix._tcp.example.test.")?,
+				300,
+				RData::SRV(SRV::new(10, 5, 8448, Name::from_ascii("destination.test.")?)),
+			),
+			Record::from_rdata(
+				Name::from_ascii("_matrix-fed._tcp.fed.test.")?,
+				300,
+				RData::SRV(SRV::new(10, 5, 8448, Name::from_ascii("destination.test.")?)),
+			),
+			Record::from_rdata(
+				Name::from_ascii("_matrix._tcp.fed.test.")?,
+				300,
+				RData::SRV(SRV::new(10, 5, 8448, Name::from_ascii("deprecated.test.")?)),
+			),
+		])
+		.await?;
+		let socket = *addr;
+		let client = move || {
+			reqwest::Client::builder().resolve("example.test", socket).resolve("fed.test", socket)
+		};
+		let resolver = Resolver::with_builder(client, dns)?;
+
+		let resolution = resolver.resolve_traced("example.test", Some(addr.port())).await?;
+		assert_eq!(
+			resolution.server,
+			Server::Srv(String::from("destination.test:8448"), String::from("example.test"))
+		);
+
+		let trace = resolution.trace;
+		let steps: Vec<_> = trace.steps.iter().map(|step| (step.step, step.matched)).collect();
+		assert_eq!(
+			steps,
+			[
+				(Step::IpLiteral, false),
+				(Step::HostPort, false),
+				(Step::WellKnown, false),
+				(Step::Srv, false),
+				(Step::SrvDeprecated, true)
+			]
+		);
+		let well_known = trace.steps[2].http.as_ref().ok_or("missing HTTP trace")?;
+		assert_eq!(well_known.status, Some(404));
+		assert_eq!(well_known.body.as_deref(), Some("Not found"));
+		let srv = trace.steps[3].dns.as_ref().ok_or("missing DNS trace")?;
+		assert_eq!(srv.query, "_matrix-fed._tcp.example.test");
+		assert!(srv.records.is_empty());
+		let srv = trace.steps[4].dns.as_ref().ok_or("missing DNS trace")?;
+		assert_eq!(srv.query, "_matrix._tcp.example.test");
+		assert_eq!(srv.records.len(), 1);
+		assert_eq!(srv.records[0].target, "destination.test");
+		assert_eq!(srv.records[0].ttl, 300);
+		assert_eq!(trace.decisive_step().map(|step| step.step.number()), Some("5"));
+		assert_eq!(trace.ttl(), Some(Duration::from_secs(300)), "the SRV record expires first");
+
+		let resolution = resolver.resolve_traced("fed.test", Some(addr.port())).await?;
+		assert_eq!(
+			resolution.server,
+			Server::Srv(String::from("destination.test:8448"), String::from("fed.test")),
+			"_matrix-fed takes precedence"
+		);
+		assert_eq!(resolution.trace.decisive_step().map(|step| step.step.number()), Some("4"));
+		Ok(())
+	}
+
+	/// Validates that overrides take precedence and are recorded in the trace.
+	#[cfg(feature = "overrides")]
+	#[tokio::test]
+	async fn overrides() -> Result<(), Box<dyn std::error::Error>> {
+		let server = Server::HostPort(String::from("destination.test:8448"));
+		let overrides =
+			crate::overrides::Overrides::new().with_server("*.example.test", server.clone());
+		let resolver = Resolver::new()?.with_overrides(overrides);
+
+		let resolution = resolver.resolve_traced("a.example.test", None).await?;
+		assert_eq!(resolution.server, server);
+		let steps: Vec<_> =
+			resolution.trace.steps.iter().map(|step| (step.step, step.matched)).collect();
+		assert_eq!(steps, [(Step::Override, true)]);
+		assert_eq!(resolution.trace.matched_override.as_deref(), Some("*.example.test"));
+		assert_eq!(resolution.trace.decisive_step().map(|step| step.step.number()), Some("0"));
+		assert_eq!(
+			resolver.resolve("127.0.0.1", None).await?,
+			Server::Ip(IpAddr::from([127, 0, 0, 1]))
+		);
+		Ok(())
+	}
+
+	/// Validates reusing cached resolutions until they are purged.
+	#[tokio::test]
+	async fn resolution_caching() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+		let addr = mock_server.address();
+
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/server"))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.insert_header("cache-control", "no-store, max-age=600")
+					.set_body_raw(format!(r#"{{"m.server": "{}"}}"#, addr), "application/json"),
+			)
+			.expect(2)
+			.mount(&mock_server)
+			.await;
+
+		let socket = *addr;
+		let client = move || reqwest::Client::builder().resolve("example.test", socket);
+		let resolver = Resolver::with_builder(client, test_dns::resolver(Vec::new()).await?)?
+			.with_resolution_caching(true);
+
+		let resolution = resolver.resolve_traced("example.test", Some(addr.port())).await?;
+		assert_eq!(resolution.trace.ttl(), Some(Duration::from_secs(600)));
+		assert_eq!(
+			resolver.resolve("example.test", Some(addr.port())).await?,
+			Server::Socket(*addr)
+		);
+		assert_eq!(resolver.resolve("example.test", None).await?, Server::Socket(*addr));
+		assert_eq!(resolver.cache().purge("example.test").await, 1);
+		assert_eq!(
+			resolver.resolve("example.test", Some(addr.port())).await?,
+			Server::Socket(*addr)
+		);
+		Ok(())
+	}
+
+	/// Validates serving the last good result while the .well-known server
+	/// is failing, and refreshing it in the background.
+	#[tokio::test]
+	async fn stale() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+		let addr = mock_server.address();
+		let mount = |status: u16, server: String| {
+			Mock::given(method("GET")).and(path("/.well-known/matrix/server")).respond_with(
+				ResponseTemplate::new(status)
+					.insert_header("cache-control", "no-store, max-age=0")
+					.set_body_raw(format!(r#"{{"m.server": "{}"}}"#, server), "application/json"),
+			)
+		};
+		mount(200, addr.to_string()).mount(&mock_server).await;
+
+		let socket = *addr;
+		let client = move || {
+			reqwest::Client::builder().resolve("example.test", socket).resolve("other.test", socket)
+		};
+		let resolver = Resolver::with_builder(client, test_dns::resolver(Vec::new()).await?)?
+			.with_resolution_caching(true)
+			.with_stale_grace(Duration::from_secs(60));
+		let resolution = resolver.resolve_traced("example.test", Some(addr.port())).await?;
+		assert_eq!((resolution.server, resolution.stale), (Server::Socket(*addr), false));
+
+		mock_server.reset().await;
+		mount(503, String::new()).mount(&mock_server).await;
+		let resolution = resolver.resolve_traced("example.test", Some(addr.port())).await?;
+		assert!(resolution.trace.is_degraded());
+		assert_eq!((resolution.server, resolution.stale), (Server::Socket(*addr), true));
+		let resolution = resolver.resolve_traced("other.test", Some(addr.port())).await?;
+		assert!(resolution.trace.is_degraded() && !resolution.stale);
+		assert!(
+			resolver.cache().resolution("other.test").is_none(),
+			"degraded results aren't cached"
+		);
+
+		let loopback = "127.0.0.1:1";
+		resolver.cache().insert_resolution(
+			loopback,
+			&Server::Host(String::from("example.test")),
+			Duration::ZERO,
+			Duration::from_secs(60),
+		);
+		let strict = resolver.clone().with_ip_filter(IpFilter::federation_default());
+		let result = strict.resolve_traced(loopback, Some(addr.port())).await;
+		assert!(matches!(result, Err(Error::Denied(_))), "{:?}", result);
+
+		mock_server.reset().await;
+		mount(200, String::from("127.0.0.1:1")).mount(&mock_server).await;
+		let refreshed = Server::Socket(SocketAddr::from(([127, 0, 0, 1], 1)));
+		assert_eq!(
+			resolver.resolve("example.test", Some(addr.port())).await?,
+			Server::Socket(*addr)
+		);
+		for _ in 0..100 {
+			if resolver.resolve("example.test", Some(addr.port())).await? == refreshed {
+				return Ok(());
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+		Err("the stale resolution wasn't refreshed".into())
+	}
 }
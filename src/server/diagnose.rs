@@ -0,0 +1,505 @@
+//! Diagnostics of a server's federation setup, similar to the [federation
+//! tester].
+//!
+//! [federation tester]: https://federationtester.matrix.org
+
+use std::{
+	convert::TryFrom,
+	net::SocketAddr,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tracing::instrument;
+use trust_dns_resolver::error::ResolveErrorKind;
+
+use super::{
+	federation::ServerVersion,
+	split_port,
+	trace::{DnsTrace, Step},
+	Resolution, Resolver, Server,
+};
+use crate::fetch;
+
+/// Outcome of a single check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum Verdict {
+	/// Everything is in order.
+	Pass,
+	/// Federation works, but something is unusual or deprecated.
+	Warn,
+	/// Federation is broken.
+	Fail,
+}
+
+/// The outcome of a check together with the data it was based on.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check<T> {
+	/// The outcome of the check.
+	pub verdict: Verdict,
+	/// Explanation of the verdict.
+	pub message: String,
+	/// The data which was checked, if it could be obtained.
+	pub value: Option<T>,
+}
+
+impl<T> Check<T> {
+	/// A passed check.
+	fn pass(value: T, message: impl Into<String>) -> Self {
+		Self { verdict: Verdict::Pass, message: message.into(), value: Some(value) }
+	}
+
+	/// A check with a warning.
+	fn warn(value: Option<T>, message: impl Into<String>) -> Self {
+		Self { verdict: Verdict::Warn, message: message.into(), value }
+	}
+
+	/// A failed check.
+	fn fail(value: Option<T>, message: impl Into<String>) -> Self {
+		Self { verdict: Verdict::Fail, message: message.into(), value }
+	}
+}
+
+/// The .well-known response of a server.
+#[derive(Debug, Clone, Serialize)]
+pub struct WellKnownReport {
+	/// The status code of the response.
+	pub status: Option<u16>,
+	/// The body of the response.
+	pub body: Option<String>,
+}
+
+/// The SRV records of a server, for both the current and the deprecated
+/// service name.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SrvReport {
+	/// Lookup of `_matrix-fed._tcp`.
+	pub matrix_fed: DnsTrace,
+	/// Lookup of the deprecated `_matrix._tcp`.
+	pub matrix: DnsTrace,
+}
+
+/// Checks made against a single address of a server.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionReport {
+	/// The address which was connected to.
+	pub address: SocketAddr,
+	/// Whether a TLS connection valid for the expected name could be
+	/// established.
+	pub tls: Check<TlsDetails>,
+	/// The response of `/_matrix/federation/v1/version`.
+	pub version: Check<ServerVersion>,
+	/// The response of `/_matrix/key/v2/server`.
+	pub keys: Check<serde_json::Value>,
+}
+
+/// The certificate presented by a server.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+pub type TlsDetails = super::tls::TlsReport;
+/// The certificate presented by a server, which can only be inspected with a
+/// TLS backend.
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+pub type TlsDetails = ();
+
+/// Structured report of a server's federation setup.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+	/// The server name which was diagnosed.
+	pub server_name: String,
+	/// Resolution of the server name.
+	pub resolution: Check<Resolution>,
+	/// The .well-known delegation.
+	pub well_known: Check<WellKnownReport>,
+	/// The SRV records.
+	pub srv: Check<SrvReport>,
+	/// The addresses the server resolved to.
+	pub addresses: Check<Vec<SocketAddr>>,
+	/// Checks made against each address.
+	pub connections: Vec<ConnectionReport>,
+}
+
+impl Report {
+	/// The worst verdict of all checks.
+	#[must_use]
+	pub fn verdict(&self) -> Verdict {
+		let connections = self
+			.connections
+			.iter()
+			.flat_map(|report| [report.tls.verdict, report.version.verdict, report.keys.verdict]);
+		[self.resolution.verdict, self.well_known.verdict, self.srv.verdict, self.addresses.verdict]
+			.iter()
+			.copied()
+			.chain(connections)
+			.max()
+			.unwrap_or(Verdict::Pass)
+	}
+}
+
+impl Resolver {
+	/// Diagnose the federation setup of the given server name.
+	#[instrument(skip(self, port))]
+	pub async fn diagnose(&self, name: &str, #[cfg(test)] port: Option<u16>) -> Report {
+		let resolution = match self
+			.resolve_traced(
+				name,
+				#[cfg(test)]
+				port,
+			)
+			.await
+		{
+			Ok(resolution) => resolution,
+			Err(e) => {
+				let message = format!("The server name could not be resolved: {}", e);
+				return Report {
+					server_name: name.to_owned(),
+					resolution: Check::fail(None, &message),
+					well_known: Check::fail(None, &message),
+					srv: Check::fail(None, &message),
+					addresses: Check::fail(None, &message),
+					connections: Vec::new(),
+				};
+			}
+		};
+
+		let well_known = check_well_known(&resolution);
+		let srv = self.check_srv(name, &resolution).await;
+		let (addresses, connections) = match self.sockets(&resolution.server).await {
+			Ok(addresses) if addresses.is_empty() => {
+				(Check::fail(Some(addresses), "No addresses found"), Vec::new())
+			}
+			Ok(addresses) => {
+				let mut connections = Vec::new();
+				for address in &addresses {
+					connections
+						.push(self.check_connection(name, &resolution.server, *address).await);
+				}
+				(Check::pass(addresses, "Addresses found"), connections)
+			}
+			Err(e) => (Check::fail(None, format!("Address lookup failed: {}", e)), Vec::new()),
+		};
+
+		let message = format!("Resolved to {}", resolution.server.address());
+		let resolution = if resolution.warnings.is_empty() {
+			Check::pass(resolution, message)
+		} else {
+			let warnings: Vec<_> = resolution.warnings.iter().map(ToString::to_string).collect();
+			let message = format!("{}, but {}", message, warnings.join("; "));
+			Check::warn(Some(resolution), message)
+		};
+		Report { server_name: name.to_owned(), resolution, well_known, srv, addresses, connections }
+	}
+
+	/// Look up the SRV records of the hostname used for SRV resolution.
+	async fn check_srv(&self, name: &str, resolution: &Resolution) -> Check<SrvReport> {
+		let steps = &resolution.trace.steps;
+		let host =
+			match steps.iter().find(|step| matches!(step.step, Step::DelegatedSrv | Step::Srv)) {
+				Some(step) => step
+					.dns
+					.as_ref()
+					.map_or(name, |dns| dns.query.trim_start_matches("_matrix-fed._tcp.")),
+				None => {
+					return Check::pass(
+						SrvReport::default(),
+						"SRV records are not used for this server",
+					)
+				}
+			};
+
+		let mut report = SrvReport::default();
+		let matrix_fed =
+			self.lookup_srv(&format!("_matrix-fed._tcp.{}", host), &mut report.matrix_fed).await;
+		let matrix = self.lookup_srv(&format!("_matrix._tcp.{}", host), &mut report.matrix).await;
+		let failure = [&matrix_fed, &matrix].iter().find_map(|lookup| match lookup {
+			Err(e) if !matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+				Some(e.to_string())
+			}
+			_ => None,
+		});
+		match failure {
+			Some(e) => Check::warn(Some(report), format!("SRV lookup failed: {}", e)),
+			None if report.matrix_fed.records.is_empty() && !report.matrix.records.is_empty() => {
+				Check::warn(Some(report), "Only the deprecated _matrix._tcp SRV record is present")
+			}
+			None if report.matrix_fed.records.is_empty() => Check::pass(report, "No SRV records"),
+			None => Check::pass(report, "SRV records found"),
+		}
+	}
+
+	/// Check the TLS connection, version and keys of the server at one address.
+	async fn check_connection(
+		&self,
+		name: &str,
+		server: &Server,
+		address: SocketAddr,
+	) -> ConnectionReport {
+		let tls = match check_tls(server, address).await {
+			Ok(tls) => tls,
+			Err(e) => {
+				const UNREACHABLE: &str = "The server could not be reached";
+				let tls = Check::fail(None, format!("Connection failed: {}", e));
+				return ConnectionReport {
+					address,
+					tls,
+					version: Check::fail(None, UNREACHABLE),
+					keys: Check::fail(None, UNREACHABLE),
+				};
+			}
+		};
+
+		let version = match self.version_at(server, address).await {
+			Ok(version) => {
+				let message = format!("{} {}", version.name, version.version);
+				Check::pass(version, message)
+			}
+			Err(e) => Check::fail(None, format!("Version request failed: {}", e)),
+		};
+
+		let keys = match self.federation_get(server, address, "/_matrix/key/v2/server").await {
+			Ok(response) => match response.error_for_status() {
+				Ok(response) => match fetch::read(response, &self.fetch_policy)
+					.await
+					.and_then(|response| response.json::<serde_json::Value>())
+				{
+					Ok(keys) => check_keys(name, keys),
+					Err(e) => Check::fail(None, format!("Invalid key response: {}", e)),
+				},
+				Err(e) => Check::fail(None, format!("Key request failed: {}", e)),
+			},
+			Err(e) => Check::fail(None, format!("Key request failed: {}", e)),
+		};
+
+		ConnectionReport { address, tls, version, keys }
+	}
+}
+
+/// Inspect the certificate of the server at one address. Returns an error if
+/// the TCP connection could not be established.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+async fn check_tls(server: &Server, address: SocketAddr) -> Result<Check<TlsDetails>, String> {
+	judge_tls(super::tls::TlsInspector::new().inspect(server, address).await)
+}
+
+/// Judge the outcome of a TLS handshake. Returns an error if the TCP
+/// connection could not be established.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn judge_tls(inspection: super::tls::Result<TlsDetails>) -> Result<Check<TlsDetails>, String> {
+	let report = match inspection {
+		Ok(report) => report,
+		Err(e @ super::tls::Error::Connect(_)) => return Err(e.to_string()),
+		Err(e) => return Ok(Check::fail(None, format!("TLS handshake failed: {}", e))),
+	};
+	let two_weeks = std::time::Duration::from_secs(14 * 24 * 60 * 60);
+	let expires_soon =
+		report.certificate().is_some_and(|certificate| certificate.expires_within(two_weeks));
+	let check = match (&report.verification_error, report.valid_for_name) {
+		(Some(e), _) => {
+			let message = format!("Certificate is not valid: {}", e);
+			Check::fail(Some(report), message)
+		}
+		(None, false) => {
+			let message = format!("Certificate is not valid for {}", report.expected_name);
+			Check::fail(Some(report), message)
+		}
+		(None, true) if expires_soon => {
+			Check::warn(Some(report), "Certificate expires within two weeks")
+		}
+		(None, true) => {
+			let message = format!("Certificate is valid for {}", report.expected_name);
+			Check::pass(report, message)
+		}
+	};
+	Ok(check)
+}
+
+/// Check that the server at one address accepts TCP connections, as
+/// certificates cannot be inspected without a TLS backend.
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+async fn check_tls(_server: &Server, address: SocketAddr) -> Result<Check<TlsDetails>, String> {
+	match tokio::net::TcpStream::connect(address).await {
+		Ok(_) => Ok(Check::pass((), "Connection established")),
+		Err(e) => Err(e.to_string()),
+	}
+}
+
+/// Judge the .well-known response recorded in the resolution trace.
+fn check_well_known(resolution: &Resolution) -> Check<WellKnownReport> {
+	let step = resolution.trace.steps.iter().find(|step| step.step == Step::WellKnown);
+	let Some(step) = step else {
+		return Check::pass(
+			WellKnownReport { status: None, body: None },
+			"Not used for IP literals and server names with a port",
+		);
+	};
+	let http = step.http.clone().unwrap_or_default();
+	let report = WellKnownReport { status: http.status, body: http.body };
+	match (step.matched, report.status, http.error) {
+		(true, ..) => Check::pass(report, "Delegation found"),
+		(false, Some(404), _) => Check::pass(report, "No delegation"),
+		(false, _, Some(e)) => Check::warn(Some(report), format!("Invalid response: {}", e)),
+		(false, status, None) => {
+			Check::warn(Some(report), format!("Unexpected status code {:?}", status))
+		}
+	}
+}
+
+/// Judge the response of the server key endpoint.
+fn check_keys(name: &str, keys: serde_json::Value) -> Check<serde_json::Value> {
+	let expected_name = split_port(name).map_or(name, |(host, _)| host);
+	let server_name = keys.get("server_name").and_then(serde_json::Value::as_str);
+	if server_name != Some(name) && server_name != Some(expected_name) {
+		let message = format!("Keys are for {:?} instead of {}", server_name, name);
+		return Check::fail(Some(keys), message);
+	}
+	let has_keys = keys
+		.get("verify_keys")
+		.and_then(serde_json::Value::as_object)
+		.is_some_and(|verify_keys| !verify_keys.is_empty());
+	if !has_keys {
+		return Check::fail(Some(keys), "No verify keys");
+	}
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.ok()
+		.and_then(|now| u64::try_from(now.as_millis()).ok())
+		.unwrap_or_default();
+	match keys.get("valid_until_ts").and_then(serde_json::Value::as_u64) {
+		Some(valid_until) if valid_until > now => check_signatures(keys),
+		Some(_) => Check::fail(Some(keys), "Keys have expired"),
+		None => Check::fail(Some(keys), "Missing valid_until_ts"),
+	}
+}
+
+/// Verify the self signatures of the server key response.
+#[cfg(feature = "keys")]
+fn check_signatures(keys: serde_json::Value) -> Check<serde_json::Value> {
+	let server_name =
+		keys.get("server_name").and_then(serde_json::Value::as_str).unwrap_or_default();
+	match super::keys::verify_server_keys(&keys, server_name, 0) {
+		Ok(_) => Check::pass(keys, "Keys are valid and signed"),
+		Err(e) => Check::fail(Some(keys), format!("Invalid keys: {}", e)),
+	}
+}
+
+/// Accept the server key response, signatures are only verified with the
+/// `keys` feature.
+#[cfg(not(feature = "keys"))]
+fn check_signatures(keys: serde_json::Value) -> Check<serde_json::Value> {
+	Check::pass(keys, "Keys are valid")
+}
+
+#[cfg(test)]
+mod tests {
+	#[cfg(not(feature = "keys"))]
+	use std::time::{SystemTime, UNIX_EPOCH};
+
+	use wiremock::{
+		matchers::{method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::{super::test_dns, Verdict};
+	use crate::server::Resolver;
+
+	/// Mounts a JSON response for a path on the mock server.
+	async fn mount(server: &MockServer, route: &str, status: u16, body: String) {
+		Mock::given(method("GET"))
+			.and(path(route))
+			.respond_with(ResponseTemplate::new(status).set_body_raw(body, "application/json"))
+			.mount(server)
+			.await;
+	}
+
+	/// Validates a report of a working server, and one with a broken version
+	/// endpoint.
+	#[tokio::test]
+	async fn report() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+		let addr = mock_server.address();
+
+		mount(
+			&mock_server,
+			"/.well-known/matrix/server",
+			200,
+			format!(r#"{{"m.server": "{}"}}"#, addr),
+		)
+		.await;
+		#[cfg(not(feature = "keys"))]
+		let keys = format!(
+			r#"{{"server_name": "example.test", "valid_until_ts": {}, "verify_keys": {{"ed25519:a": {{"key": "AAAA"}}}}, "old_verify_keys": {{}}}}"#,
+			SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() + 3_600_000
+		);
+		#[cfg(feature = "keys")]
+		let keys = super::super::keys::tests::server_keys(
+			"example.test",
+			&ed25519_dalek::SigningKey::from_bytes(&[7; 32]),
+		)
+		.to_string();
+		mount(&mock_server, "/_matrix/key/v2/server", 200, keys).await;
+
+		let socket = *addr;
+		let client = move || reqwest::Client::builder().resolve("example.test", socket);
+		let resolver = Resolver::with_builder(client, test_dns::resolver(Vec::new()).await?)?;
+
+		let report = resolver.diagnose("example.test", Some(addr.port())).await;
+		assert_eq!(report.resolution.verdict, Verdict::Pass);
+		assert_eq!(report.well_known.verdict, Verdict::Pass);
+		assert_eq!(report.srv.verdict, Verdict::Pass);
+		assert_eq!(report.addresses.value.as_deref(), Some(&[*addr][..]));
+		assert_eq!(report.connections.len(), 1);
+		let connection = &report.connections[0];
+		assert_eq!(connection.tls.verdict, Verdict::Fail, "the mock server doesn't speak TLS");
+		assert_eq!(connection.keys.verdict, Verdict::Pass, "{}", connection.keys.message);
+		assert_eq!(connection.version.verdict, Verdict::Fail, "version endpoint is missing");
+		assert_eq!(report.verdict(), Verdict::Fail);
+
+		mount(
+			&mock_server,
+			"/_matrix/federation/v1/version",
+			200,
+			String::from(r#"{"server": {"name": "Synapse", "version": "1.2.3"}}"#),
+		)
+		.await;
+		let report = resolver.diagnose("example.test", Some(addr.port())).await;
+		let version = report.connections[0].version.value.as_ref().ok_or("missing version")?;
+		assert_eq!((version.name.as_str(), version.version.as_str()), ("Synapse", "1.2.3"));
+		assert_eq!(report.connections[0].version.verdict, Verdict::Pass);
+		Ok(())
+	}
+
+	/// Validates the TLS check with a valid, a mismatched and an expired
+	/// certificate, and with a closed port.
+	#[cfg(any(feature = "native-tls", feature = "rustls"))]
+	#[tokio::test]
+	async fn tls() -> Result<(), Box<dyn std::error::Error>> {
+		use super::{
+			super::tls::{tests::listener, Certificate, TlsInspector},
+			judge_tls,
+		};
+		use crate::server::Server;
+
+		let server = Server::Host(String::from("localhost"));
+		let (addr, ca) = listener(&["localhost"], false).await?;
+		let inspector = TlsInspector::new().with_root_certificate(ca);
+		let check = judge_tls(inspector.inspect(&server, addr).await)?;
+		assert_eq!(check.verdict, Verdict::Pass, "{}", check.message);
+
+		let other = Server::HostPort(format!("example.test:{}", addr.port()));
+		let check = judge_tls(inspector.inspect(&other, addr).await)?;
+		assert_eq!(check.verdict, Verdict::Fail, "name mismatch");
+		assert!(check.value.is_some_and(|report| !report.valid_for_name));
+
+		let (addr, ca) = listener(&["localhost"], true).await?;
+		let inspector = TlsInspector::new().with_root_certificate(ca);
+		let check = judge_tls(inspector.inspect(&server, addr).await)?;
+		assert_eq!(check.verdict, Verdict::Fail, "expired");
+		let report = check.value.ok_or("missing report")?;
+		assert!(report.valid_for_name);
+		assert!(report.verification_error.is_some());
+		assert!(report.certificate().is_some_and(Certificate::is_expired));
+
+		let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+		assert!(judge_tls(inspector.inspect(&server, closed).await).is_err(), "closed port");
+		Ok(())
+	}
+}
@@ -0,0 +1,91 @@
+//! Adapter for reusing the oracle's DNS resolver in reqwest clients.
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use trust_dns_resolver::TokioAsyncResolver;
+
+use super::{filter::IpFilter, lookup_sockets, Resolver};
+
+/// Implementation of reqwest's [`Resolve`] trait on top of
+/// [`Resolver::sockets`].
+///
+/// Installing this with [`reqwest::ClientBuilder::dns_resolver`] makes the
+/// client share the trust-dns resolver and its cache with the oracle, so
+/// discovery and the connections made afterwards use the same DNS
+/// configuration and don't look up the same records twice.
+///
+/// Only hostnames are resolved here; the port of the request URL always takes
+/// precedence over the one in the returned addresses, so requests should use
+/// the port from [`Server::address`](super::Server::address). Addresses
+/// rejected by the IP filter of the resolver are left out.
+#[derive(Debug, Clone)]
+pub struct DnsResolver {
+	/// The DNS resolver to perform lookups with.
+	resolver: TokioAsyncResolver,
+	/// The addresses which may be returned.
+	filter: IpFilter,
+}
+
+impl DnsResolver {
+	/// Constructs a new adapter using the DNS resolver and IP filter of the
+	/// given resolver.
+	#[must_use]
+	pub fn new(resolver: Resolver) -> Self {
+		Self::filtered(resolver.resolver, resolver.filter)
+	}
+
+	/// Constructs a new adapter around a DNS resolver and IP filter.
+	pub(super) fn filtered(resolver: TokioAsyncResolver, filter: IpFilter) -> Self {
+		Self { resolver, filter }
+	}
+}
+
+impl Resolve for DnsResolver {
+	fn resolve(&self, name: Name) -> Resolving {
+		let adapter = self.clone();
+		Box::pin(async move {
+			let sockets =
+				lookup_sockets(&adapter.resolver, &adapter.filter, name.as_str(), 0).await?;
+			let addrs: Addrs = Box::new(sockets.into_iter());
+			Ok(addrs)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use wiremock::{
+		matchers::{method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::super::Resolver;
+
+	/// Validates that reqwest connects to the addresses found by the oracle.
+	#[tokio::test]
+	async fn connect() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+
+		Mock::given(method("GET"))
+			.and(path("/_matrix/federation/v1/version"))
+			.respond_with(ResponseTemplate::new(200))
+			.expect(1)
+			.mount(&mock_server)
+			.await;
+
+		let resolver = Resolver::new()?;
+		let client = reqwest::Client::builder().dns_resolver(resolver.dns_resolver()).build()?;
+
+		// localhost is answered from the hosts file by the trust-dns resolver
+		let status = client
+			.get(format!(
+				"http://localhost:{}/_matrix/federation/v1/version",
+				mock_server.address().port()
+			))
+			.send()
+			.await?
+			.status();
+		assert!(status.is_success());
+		Ok(())
+	}
+}
@@ -1,5 +1,11 @@
 //! Errors that can occur when performing a well-known lookup.
 
+use std::net::IpAddr;
+
+use trust_dns_resolver::error::ResolveError;
+
+use crate::fetch;
+
 /// The result of attempting to perform well-known lookup.
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -8,12 +14,22 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
 	/// An error happened while fetching an HTTP request.
 	Http(reqwest::Error),
+	/// An error happened while performing a DNS lookup.
+	Dns(ResolveError),
+	/// The server name resolved to an address in a denied range.
+	Denied(IpAddr),
+	/// A response of the server could not be read within the limits of the
+	/// resolver's [`FetchPolicy`](fetch::FetchPolicy).
+	Fetch(fetch::Error),
 }
 
 impl std::fmt::Display for Error {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		match self {
 			Self::Http(http) => write!(f, "{}", http),
+			Self::Dns(dns) => write!(f, "{}", dns),
+			Self::Denied(ip) => write!(f, "{} is in a denied IP range", ip),
+			Self::Fetch(e) => write!(f, "{}", e),
 		}
 	}
 }
@@ -22,6 +38,9 @@ impl std::error::Error for Error {
 	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
 		match *self {
 			Self::Http(ref err) => Some(err),
+			Self::Dns(ref err) => Some(err),
+			Self::Denied(_) => None,
+			Self::Fetch(ref err) => Some(err),
 		}
 	}
 }
@@ -31,3 +50,18 @@ impl From<reqwest::Error> for Error {
 		Self::Http(err)
 	}
 }
+
+impl From<fetch::Error> for Error {
+	fn from(err: fetch::Error) -> Self {
+		match err {
+			fetch::Error::Http(reqwest_middleware::Error::Reqwest(err)) => Self::Http(err),
+			err => Self::Fetch(err),
+		}
+	}
+}
+
+impl From<ResolveError> for Error {
+	fn from(err: ResolveError) -> Self {
+		Self::Dns(err)
+	}
+}
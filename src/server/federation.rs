@@ -0,0 +1,211 @@
+//! Requests to the federation API of a resolved server.
+
+use std::{
+	net::{IpAddr, SocketAddr},
+	time::Duration,
+};
+
+use reqwest::{header::HOST, Method, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument};
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+
+use super::{error, Resolver, Server};
+use crate::fetch;
+
+/// Scheme used for federation requests.
+#[cfg(not(test))]
+const SCHEME: &str = "https";
+/// Scheme used for federation requests.
+#[cfg(test)]
+const SCHEME: &str = "http";
+
+/// How long federation requests may take by default.
+pub(super) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many clients for federation requests are kept, one for each server
+/// name and address.
+const CLIENT_CAPACITY: u64 = 256;
+
+/// Clients for federation requests, with the server name pinned to an
+/// address.
+pub(super) type Clients = moka::sync::Cache<(String, SocketAddr), reqwest::Client>;
+
+/// Constructs an empty set of clients for federation requests.
+pub(super) fn clients() -> Clients {
+	moka::sync::Cache::new(CLIENT_CAPACITY)
+}
+
+/// Software name and version reported by `/_matrix/federation/v1/version`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ServerVersion {
+	/// Name of the homeserver implementation.
+	pub name: String,
+	/// Version of the homeserver implementation.
+	pub version: String,
+}
+
+/// Response body of `/_matrix/federation/v1/version`.
+#[derive(Deserialize)]
+struct VersionResponse {
+	/// Information about the homeserver implementation.
+	server: ServerVersion,
+}
+
+impl Resolver {
+	/// Resolve the given server name and query the software name and version
+	/// of the server it resolved to. The addresses of the server are tried
+	/// in order until one responds.
+	#[instrument(skip(self, port), err)]
+	pub async fn version(
+		&self,
+		name: &str,
+		#[cfg(test)] port: Option<u16>,
+	) -> error::Result<ServerVersion> {
+		let server = self
+			.resolve(
+				name,
+				#[cfg(test)]
+				port,
+			)
+			.await?;
+		let mut result = Err(ResolveError::from(ResolveErrorKind::Message("No records")).into());
+		for addr in self.sockets(&server).await? {
+			result = self.version_at(&server, addr).await.map_err(error::Error::from);
+			match &result {
+				Ok(_) => break,
+				Err(e) => debug!("Querying the version at {} failed: {}", addr, e),
+			}
+		}
+		result
+	}
+
+	/// Send an unauthenticated GET request for the given path to a resolved
+	/// server at one of its addresses.
+	pub(crate) async fn federation_get(
+		&self,
+		server: &Server,
+		addr: SocketAddr,
+		path: &str,
+	) -> Result<reqwest::Response, reqwest::Error> {
+		self.federation_request(Method::GET, server, addr, path)?.send().await
+	}
+
+	/// Build an unauthenticated request for the given path to a resolved
+	/// server at one of its addresses, with the `Host` header and SNI set as
+	/// required by the specification.
+	pub(crate) fn federation_request(
+		&self,
+		method: Method,
+		server: &Server,
+		addr: SocketAddr,
+		path: &str,
+	) -> Result<RequestBuilder, reqwest::Error> {
+		let name = server.tls_name();
+		let host = match name.parse() {
+			Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+			_ => name.clone(),
+		};
+		let url = format!("{}://{}:{}{}", SCHEME, host, addr.port(), path);
+		let client = self.federation_client(name, addr)?;
+		Ok(client.request(method, url).header(HOST, server.host_header()))
+	}
+
+	/// The client connecting to the given address for the server name, which
+	/// is reused for further requests to keep its connections.
+	fn federation_client(
+		&self,
+		name: String,
+		addr: SocketAddr,
+	) -> Result<reqwest::Client, reqwest::Error> {
+		let key = (name, addr);
+		if let Some(client) = self.federation_clients.get(&key) {
+			return Ok(client);
+		}
+		let client = fetch::configure((self.settings.0)())
+			.resolve(&key.0, addr)
+			.timeout(self.federation_timeout)
+			.build()?;
+		self.federation_clients.insert(key, client.clone());
+		Ok(client)
+	}
+
+	/// Query the software name and version of a resolved server at one of its
+	/// addresses.
+	pub(crate) async fn version_at(
+		&self,
+		server: &Server,
+		addr: SocketAddr,
+	) -> Result<ServerVersion, fetch::Error> {
+		let response = self
+			.federation_get(server, addr, "/_matrix/federation/v1/version")
+			.await?
+			.error_for_status()?;
+		Ok(fetch::read(response, &self.fetch_policy).await?.json::<VersionResponse>()?.server)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+
+	use trust_dns_resolver::proto::rr::{Name, RData, Record};
+	use wiremock::{
+		matchers::{header, method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::super::{error::Error, test_dns, Resolver};
+	use crate::fetch::{self, FetchPolicy};
+
+	/// Validates querying the version of a delegated server with the
+	/// delegated name in the Host header.
+	#[tokio::test]
+	async fn version() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+		let addr = mock_server.address();
+		let delegated = format!("destination.test:{}", addr.port());
+
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/server"))
+			.respond_with(
+				ResponseTemplate::new(200).set_body_raw(
+					format!(r#"{{"m.server": "{}"}}"#, delegated),
+					"application/json",
+				),
+			)
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/_matrix/federation/v1/version"))
+			.and(header("host", delegated.as_str()))
+			.respond_with(ResponseTemplate::new(200).set_body_raw(
+				r#"{"server": {"name": "Synapse", "version": "1.2.3"}}"#,
+				"application/json",
+			))
+			.expect(2)
+			.mount(&mock_server)
+			.await;
+
+		let dns = test_dns::resolver(vec![Record::from_rdata(
+			Name::from_ascii("destination.test.")?,
+			300,
+			RData::A([127, 0, 0, 1].into()),
+		)])
+		.await?;
+		let socket = *addr;
+		let client = move || reqwest::Client::builder().resolve("example.test", socket);
+		let resolver = Resolver::with_builder(client, dns)?;
+
+		let version = resolver.version("example.test", Some(addr.port())).await?;
+		assert_eq!((version.name.as_str(), version.version.as_str()), ("Synapse", "1.2.3"));
+		let pinned =
+			(String::from("destination.test"), SocketAddr::from(([127, 0, 0, 1], addr.port())));
+		assert!(resolver.federation_clients.contains_key(&pinned), "the client is kept");
+
+		let policy = FetchPolicy { max_body_size: 45, ..FetchPolicy::default() };
+		let limited = resolver.with_fetch_policy(policy);
+		let result = limited.version("example.test", Some(addr.port())).await;
+		assert!(matches!(result, Err(Error::Fetch(fetch::Error::TooLarge(45)))), "{:?}", result);
+		Ok(())
+	}
+}
@@ -0,0 +1,136 @@
+//! Filtering of the IP addresses a server name may resolve to.
+
+use std::net::IpAddr;
+
+pub use ipnet::IpNet;
+
+use super::error::{Error, Result};
+
+/// IP ranges a server name is allowed to resolve to, analogous to Synapse's
+/// `federation_ip_range_blacklist` and `federation_ip_range_whitelist`.
+///
+/// An address is rejected if it is contained in one of the denied ranges,
+/// unless it is also contained in one of the allowed ranges. The default filter
+/// denies nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+	/// Ranges which are accepted even if they are also denied.
+	allow: Vec<IpNet>,
+	/// Ranges which are rejected.
+	deny: Vec<IpNet>,
+}
+
+/// Ranges denied by [`IpFilter::federation_default`], matching Synapse's
+/// default deny list.
+const DEFAULT_DENY: &[&str] = &[
+	"127.0.0.0/8",
+	"10.0.0.0/8",
+	"172.16.0.0/12",
+	"192.168.0.0/16",
+	"100.64.0.0/10",
+	"192.0.0.0/24",
+	"169.254.0.0/16",
+	"192.88.99.0/24",
+	"198.18.0.0/15",
+	"192.0.2.0/24",
+	"198.51.100.0/24",
+	"203.0.113.0/24",
+	"224.0.0.0/4",
+	"0.0.0.0/8",
+	"::1/128",
+	"::/128",
+	"fe80::/10",
+	"fc00::/7",
+	"2001:db8::/32",
+	"ff00::/8",
+	"fec0::/10",
+];
+
+impl IpFilter {
+	/// Constructs a filter which accepts every address.
+	#[must_use]
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Constructs a filter which denies loopback, private, link-local and
+	/// other reserved ranges that should never be contacted over federation.
+	#[must_use]
+	pub fn federation_default() -> Self {
+		Self {
+			allow: Vec::new(),
+			#[allow(clippy::expect_used)]
+			deny: DEFAULT_DENY
+				.iter()
+				.map(|net| net.parse().expect("default ranges are valid CIDR notation"))
+				.collect(),
+		}
+	}
+
+	/// Adds a range to reject.
+	#[must_use]
+	pub fn deny(mut self, net: IpNet) -> Self {
+		self.deny.push(net);
+		self
+	}
+
+	/// Adds a range to accept even if it is contained in a denied range.
+	#[must_use]
+	pub fn allow(mut self, net: IpNet) -> Self {
+		self.allow.push(net);
+		self
+	}
+
+	/// Whether the filter denies nothing, in which case no checks need to be
+	/// performed.
+	#[must_use]
+	pub fn is_permissive(&self) -> bool {
+		self.deny.is_empty()
+	}
+
+	/// Whether the given address may be contacted.
+	#[must_use]
+	pub fn is_allowed(&self, ip: IpAddr) -> bool {
+		// IPv4-mapped IPv6 addresses reach the IPv4 host, so check them as such
+		let ip = match ip {
+			IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+			IpAddr::V4(_) => ip,
+		};
+		self.allow.iter().any(|net| net.contains(&ip))
+			|| !self.deny.iter().any(|net| net.contains(&ip))
+	}
+
+	/// Returns [`Error::Denied`] if the given address may not be contacted.
+	pub fn check(&self, ip: IpAddr) -> Result<()> {
+		if self.is_allowed(ip) {
+			Ok(())
+		} else {
+			Err(Error::Denied(ip))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::IpAddr;
+
+	use super::IpFilter;
+
+	/// Validates the precedence of allowed over denied ranges.
+	#[test]
+	fn ranges() -> Result<(), Box<dyn std::error::Error>> {
+		let filter = IpFilter::federation_default();
+		assert!(!filter.is_allowed(IpAddr::from([127, 0, 0, 1])), "loopback");
+		assert!(!filter.is_allowed(IpAddr::from([169, 254, 169, 254])), "link-local");
+		assert!(!filter.is_allowed(IpAddr::from([10, 1, 2, 3])), "private");
+		assert!(!filter.is_allowed("::ffff:192.168.0.1".parse()?), "IPv4-mapped");
+		assert!(filter.is_allowed(IpAddr::from([1, 1, 1, 1])), "public");
+
+		let filter = filter.allow("10.1.0.0/16".parse()?);
+		assert!(filter.is_allowed(IpAddr::from([10, 1, 2, 3])), "allowed overrides denied");
+		assert!(!filter.is_allowed(IpAddr::from([10, 2, 0, 1])), "outside allowed range");
+
+		assert!(IpFilter::new().is_allowed(IpAddr::from([127, 0, 0, 1])), "empty filter");
+		Ok(())
+	}
+}
@@ -0,0 +1,614 @@
+//! Fetching and verification of server signing keys.
+//!
+//! See [the specification] for more information.
+//!
+//! [the specification]: https://spec.matrix.org/latest/server-server-api/#retrieving-server-keys
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	convert::TryFrom,
+	net::SocketAddr,
+	sync::{Arc, Mutex},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{
+	alphabet,
+	engine::{general_purpose, DecodePaddingMode, GeneralPurpose},
+	Engine,
+};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, instrument};
+
+use super::{Resolver, Server};
+use crate::fetch;
+
+/// Base64 engine for keys and signatures, which are unpadded in the
+/// specification but accepted with padding as well.
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+	&alphabet::STANDARD,
+	general_purpose::NO_PAD.with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// A public key currently used by a server.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VerifyKey {
+	/// The unpadded base64 encoded ed25519 public key.
+	pub key: String,
+}
+
+/// A public key a server no longer uses.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OldVerifyKey {
+	/// The unpadded base64 encoded ed25519 public key.
+	pub key: String,
+	/// When the key stopped being used, in milliseconds since the unix epoch.
+	pub expired_ts: u64,
+}
+
+/// The signing keys of a server, as returned by `/_matrix/key/v2/server`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ServerKeys {
+	/// The name of the server the keys belong to.
+	pub server_name: String,
+	/// Until when the keys may be used, in milliseconds since the unix epoch.
+	pub valid_until_ts: u64,
+	/// Current keys by key id.
+	#[serde(default)]
+	pub verify_keys: BTreeMap<String, VerifyKey>,
+	/// Previously used keys by key id.
+	#[serde(default)]
+	pub old_verify_keys: BTreeMap<String, OldVerifyKey>,
+	/// Signatures of the response by server name and key id.
+	#[serde(default)]
+	pub signatures: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl ServerKeys {
+	/// The point in time after which the keys must be fetched again.
+	#[must_use]
+	pub fn valid_until(&self) -> SystemTime {
+		UNIX_EPOCH + Duration::from_millis(self.valid_until_ts)
+	}
+}
+
+/// A notary server which is trusted to serve the keys of other servers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notary {
+	/// The server name of the notary.
+	pub server_name: String,
+	/// The base64 encoded public keys of the notary by key id.
+	pub verify_keys: BTreeMap<String, String>,
+}
+
+impl Notary {
+	/// Constructs a notary with a single trusted key.
+	#[must_use]
+	pub fn new(server_name: &str, key_id: &str, key: &str) -> Self {
+		let verify_keys = std::iter::once((key_id.to_owned(), key.to_owned())).collect();
+		Self { server_name: server_name.to_owned(), verify_keys }
+	}
+}
+
+/// Response body of `/_matrix/key/v2/query`.
+#[derive(Deserialize)]
+struct QueryResponse {
+	/// The keys of the queried servers.
+	server_keys: Vec<Value>,
+}
+
+/// Fetches and verifies server signing keys, caching them until they expire.
+#[derive(Debug, Clone)]
+pub struct KeyFetcher {
+	/// Resolver used to locate servers.
+	resolver: Resolver,
+	/// Notaries to ask when a server cannot be reached directly.
+	notaries: Vec<Notary>,
+	/// Verified keys by server name.
+	cache: Arc<Mutex<HashMap<String, ServerKeys>>>,
+}
+
+impl KeyFetcher {
+	/// Constructs a key fetcher which locates servers with the given resolver.
+	#[must_use]
+	pub fn new(resolver: Resolver) -> Self {
+		Self { resolver, notaries: Vec::new(), cache: Arc::default() }
+	}
+
+	/// Adds a notary to query when a server's keys cannot be fetched
+	/// directly. Notaries are queried in the order they were added.
+	#[must_use]
+	pub fn with_notary(mut self, notary: Notary) -> Self {
+		self.notaries.push(notary);
+		self
+	}
+
+	/// Get the verified signing keys of the given server, from the cache if
+	/// they have not expired yet, else directly from the server or from the
+	/// configured notaries.
+	#[instrument(skip(self, port), err)]
+	pub async fn server_keys(
+		&self,
+		name: &str,
+		#[cfg(test)] port: Option<u16>,
+	) -> Result<ServerKeys> {
+		if let Some(keys) = self.cached(name) {
+			debug!("Using cached keys");
+			return Ok(keys);
+		}
+
+		let mut result = self
+			.fetch(
+				name,
+				#[cfg(test)]
+				port,
+			)
+			.await;
+		for notary in &self.notaries {
+			let Err(e) = &result else { break };
+			debug!("Fetching keys directly failed: {}, querying {}", e, notary.server_name);
+			result = self
+				.query(
+					notary,
+					name,
+					#[cfg(test)]
+					port,
+				)
+				.await;
+		}
+		let keys = result?;
+		self.store(&keys);
+		Ok(keys)
+	}
+
+	/// Get the verified signing keys of a server directly from one of its
+	/// addresses, bypassing the cache.
+	pub async fn server_keys_at(
+		&self,
+		name: &str,
+		server: &Server,
+		addr: SocketAddr,
+	) -> Result<ServerKeys> {
+		let response = self
+			.resolver
+			.federation_get(server, addr, "/_matrix/key/v2/server")
+			.await?
+			.error_for_status()?;
+		let value = fetch::read(response, &self.resolver.fetch_policy).await?.json::<Value>()?;
+		verify_server_keys(&value, name, now())
+	}
+
+	/// Query a notary for the signing keys of the given server. Both the
+	/// notary's signature and the server's own signature are verified.
+	#[instrument(skip(self, notary, port), fields(notary = %notary.server_name), err)]
+	pub async fn query(
+		&self,
+		notary: &Notary,
+		name: &str,
+		#[cfg(test)] port: Option<u16>,
+	) -> Result<ServerKeys> {
+		let server = self
+			.resolver
+			.resolve(
+				&notary.server_name,
+				#[cfg(test)]
+				port,
+			)
+			.await?;
+		let body = json!({ "server_keys": { name: {} } });
+		let mut last_error = Error::NotFound(name.to_owned());
+		for addr in self.resolver.sockets(&server).await? {
+			let request = self.resolver.federation_request(
+				Method::POST,
+				&server,
+				addr,
+				"/_matrix/key/v2/query",
+			)?;
+			let response = match request
+				.json(&body)
+				.send()
+				.await
+				.and_then(reqwest::Response::error_for_status)
+			{
+				Ok(response) => response,
+				Err(e) => {
+					last_error = e.into();
+					continue;
+				}
+			};
+			let response = fetch::read(response, &self.resolver.fetch_policy)
+				.await?
+				.json::<QueryResponse>()?;
+			let now = now();
+			for value in &response.server_keys {
+				if value.get("server_name").and_then(Value::as_str) != Some(name) {
+					continue;
+				}
+				let verified = verify_signatures(value, &notary.server_name, &notary.verify_keys)
+					.and_then(|_| verify_server_keys(value, name, now));
+				match verified {
+					Ok(keys) => return Ok(keys),
+					Err(e) => last_error = e,
+				}
+			}
+			break;
+		}
+		Err(last_error)
+	}
+
+	/// Fetch the keys from the server itself, trying each of its addresses.
+	async fn fetch(&self, name: &str, #[cfg(test)] port: Option<u16>) -> Result<ServerKeys> {
+		let server = self
+			.resolver
+			.resolve(
+				name,
+				#[cfg(test)]
+				port,
+			)
+			.await?;
+		let mut last_error = Error::NotFound(name.to_owned());
+		for addr in self.resolver.sockets(&server).await? {
+			match self.server_keys_at(name, &server, addr).await {
+				Ok(keys) => return Ok(keys),
+				Err(e) => {
+					debug!("Fetching keys from {} failed: {}", addr, e);
+					last_error = e;
+				}
+			}
+		}
+		Err(last_error)
+	}
+
+	/// Get the unexpired cached keys of a server.
+	fn cached(&self, name: &str) -> Option<ServerKeys> {
+		let mut cache = self.cache.lock().ok()?;
+		let now = now();
+		cache.retain(|_, keys| keys.valid_until_ts > now);
+		cache.get(name).cloned()
+	}
+
+	/// Cache the keys of a server until they expire.
+	fn store(&self, keys: &ServerKeys) {
+		if let Ok(mut cache) = self.cache.lock() {
+			cache.insert(keys.server_name.clone(), keys.clone());
+		}
+	}
+}
+
+/// Parse a key response of the given server and verify its expiry and self
+/// signatures.
+pub fn verify_server_keys(value: &Value, name: &str, now: u64) -> Result<ServerKeys> {
+	let keys: ServerKeys = serde_json::from_value(value.clone())?;
+	if keys.server_name != name {
+		return Err(Error::ServerName(keys.server_name));
+	}
+	if keys.valid_until_ts <= now {
+		return Err(Error::Expired(keys.valid_until_ts));
+	}
+	let verify_keys =
+		keys.verify_keys.iter().map(|(id, key)| (id.clone(), key.key.clone())).collect();
+	verify_signatures(value, name, &verify_keys)?;
+	Ok(keys)
+}
+
+/// Verify the signatures of the given signer on a JSON object. Every
+/// signature made with one of the given keys must be valid, and at least one
+/// must be present.
+pub fn verify_signatures(
+	value: &Value,
+	signer: &str,
+	verify_keys: &BTreeMap<String, String>,
+) -> Result<()> {
+	let signatures = value
+		.get("signatures")
+		.and_then(|signatures| signatures.get(signer))
+		.and_then(Value::as_object)
+		.ok_or_else(|| Error::MissingSignature(signer.to_owned()))?;
+
+	let mut unsigned = value.clone();
+	if let Some(object) = unsigned.as_object_mut() {
+		object.remove("signatures");
+		object.remove("unsigned");
+	}
+	let message = canonical_json(&unsigned);
+
+	let mut verified = 0;
+	for (key_id, signature) in signatures {
+		let Some(key) = verify_keys.get(key_id) else { continue };
+		let key = BASE64
+			.decode(key)
+			.ok()
+			.and_then(|key| <[u8; 32]>::try_from(key.as_slice()).ok())
+			.and_then(|key| VerifyingKey::from_bytes(&key).ok())
+			.ok_or_else(|| Error::InvalidKey(key_id.clone()))?;
+		let signature = signature
+			.as_str()
+			.and_then(|signature| BASE64.decode(signature).ok())
+			.and_then(|signature| Signature::from_slice(&signature).ok())
+			.ok_or_else(|| Error::InvalidSignature(key_id.clone()))?;
+		key.verify(message.as_bytes(), &signature)
+			.map_err(|_| Error::InvalidSignature(key_id.clone()))?;
+		verified += 1;
+	}
+	if verified == 0 {
+		return Err(Error::MissingSignature(signer.to_owned()));
+	}
+	Ok(())
+}
+
+/// Encode a JSON value as [canonical JSON], with object keys sorted by
+/// codepoint and no insignificant whitespace.
+///
+/// [canonical JSON]: https://spec.matrix.org/latest/appendices/#canonical-json
+#[must_use]
+pub fn canonical_json(value: &Value) -> String {
+	let mut out = String::new();
+	write_canonical(value, &mut out);
+	out
+}
+
+/// Append the canonical encoding of a JSON value.
+fn write_canonical(value: &Value, out: &mut String) {
+	match value {
+		Value::Array(values) => {
+			out.push('[');
+			for (i, value) in values.iter().enumerate() {
+				if i > 0 {
+					out.push(',');
+				}
+				write_canonical(value, out);
+			}
+			out.push(']');
+		}
+		Value::Object(object) => {
+			let mut entries: Vec<_> = object.iter().collect();
+			entries.sort_by_key(|(key, _)| *key);
+			out.push('{');
+			for (i, (key, value)) in entries.into_iter().enumerate() {
+				if i > 0 {
+					out.push(',');
+				}
+				out.push_str(&Value::String(key.clone()).to_string());
+				out.push(':');
+				write_canonical(value, out);
+			}
+			out.push('}');
+		}
+		value => out.push_str(&value.to_string()),
+	}
+}
+
+/// The current time in milliseconds since the unix epoch.
+fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.ok()
+		.and_then(|now| u64::try_from(now.as_millis()).ok())
+		.unwrap_or_default()
+}
+
+/// The result of fetching server keys.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors that can happen when fetching server keys.
+#[derive(Debug)]
+pub enum Error {
+	/// The server could not be resolved.
+	Resolve(super::error::Error),
+	/// An error happened while fetching an HTTP request.
+	Http(reqwest::Error),
+	/// The response was not valid.
+	Json(serde_json::Error),
+	/// The response could not be read within the limits of the resolver's
+	/// [`FetchPolicy`](crate::fetch::FetchPolicy).
+	Fetch(fetch::Error),
+	/// No keys were returned for the server.
+	NotFound(String),
+	/// The keys belong to a different server.
+	ServerName(String),
+	/// The keys expired at the given time.
+	Expired(u64),
+	/// The response is not signed by the given server.
+	MissingSignature(String),
+	/// The public key with the given id is not valid.
+	InvalidKey(String),
+	/// The signature made with the given key id is not valid.
+	InvalidSignature(String),
+}
+
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Resolve(e) => write!(f, "{}", e),
+			Self::Http(e) => write!(f, "{}", e),
+			Self::Json(e) => write!(f, "{}", e),
+			Self::Fetch(e) => write!(f, "{}", e),
+			Self::NotFound(name) => write!(f, "No keys found for {}", name),
+			Self::ServerName(name) => write!(f, "Keys are for {}", name),
+			Self::Expired(ts) => write!(f, "Keys expired at {}", ts),
+			Self::MissingSignature(name) => write!(f, "Keys are not signed by {}", name),
+			Self::InvalidKey(id) => write!(f, "Public key {} is not valid", id),
+			Self::InvalidSignature(id) => write!(f, "Signature by {} is not valid", id),
+		}
+	}
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Self::Resolve(e) => Some(e),
+			Self::Http(e) => Some(e),
+			Self::Json(e) => Some(e),
+			Self::Fetch(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl From<super::error::Error> for Error {
+	fn from(err: super::error::Error) -> Self {
+		Self::Resolve(err)
+	}
+}
+
+impl From<reqwest::Error> for Error {
+	fn from(err: reqwest::Error) -> Self {
+		Self::Http(err)
+	}
+}
+
+impl From<serde_json::Error> for Error {
+	fn from(err: serde_json::Error) -> Self {
+		Self::Json(err)
+	}
+}
+
+impl From<fetch::Error> for Error {
+	fn from(err: fetch::Error) -> Self {
+		Self::Fetch(err)
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use ed25519_dalek::{Signer, SigningKey};
+	use serde_json::{json, Value};
+	use wiremock::{
+		matchers::{method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::{canonical_json, now, Engine, Error, KeyFetcher, Notary, BASE64};
+	use crate::{
+		fetch::FetchPolicy,
+		server::{test_dns, Resolver},
+	};
+
+	/// Adds a signature of the given server to a JSON object.
+	pub(crate) fn sign(value: &mut Value, signer: &str, key_id: &str, key: &SigningKey) {
+		let mut unsigned = value.clone();
+		if let Some(object) = unsigned.as_object_mut() {
+			object.remove("signatures");
+			object.remove("unsigned");
+		}
+		let signature = key.sign(canonical_json(&unsigned).as_bytes());
+		value["signatures"][signer][key_id] = Value::from(BASE64.encode(signature.to_bytes()));
+	}
+
+	/// A signed key response for the given server.
+	pub(crate) fn server_keys(name: &str, key: &SigningKey) -> Value {
+		let mut value = json!({
+			"server_name": name,
+			"valid_until_ts": now() + 3_600_000,
+			"verify_keys": { "ed25519:a": { "key": BASE64.encode(key.verifying_key().as_bytes()) } },
+			"old_verify_keys": {},
+		});
+		sign(&mut value, name, "ed25519:a", key);
+		value
+	}
+
+	/// Validates the canonical JSON encoding.
+	#[test]
+	fn canonical() {
+		let value = json!({ "b": [1, "ü", null], "a": { "d": true, "c": "\"" } });
+		assert_eq!(canonical_json(&value), r#"{"a":{"c":"\"","d":true},"b":[1,"ü",null]}"#);
+	}
+
+	/// Validates fetching, caching and rejecting keys from the server itself.
+	#[tokio::test]
+	async fn fetch() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+		let addr = mock_server.address();
+		let key = SigningKey::from_bytes(&[7; 32]);
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/server"))
+			.respond_with(ResponseTemplate::new(200).set_body_json(json!({ "m.server": addr })))
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/_matrix/key/v2/server"))
+			.respond_with(ResponseTemplate::new(200).set_body_json(server_keys("a.test", &key)))
+			.expect(1)
+			.mount(&mock_server)
+			.await;
+		let tampered_server = MockServer::start().await;
+		let tampered_addr = tampered_server.address();
+		let mut tampered = server_keys("b.test", &key);
+		tampered["valid_until_ts"] = Value::from(now() + 7_200_000);
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/server"))
+			.respond_with(
+				ResponseTemplate::new(200).set_body_json(json!({ "m.server": tampered_addr })),
+			)
+			.mount(&tampered_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/_matrix/key/v2/server"))
+			.respond_with(ResponseTemplate::new(200).set_body_json(tampered))
+			.mount(&tampered_server)
+			.await;
+
+		let (a, b) = (*addr, *tampered_addr);
+		let client = move || reqwest::Client::builder().resolve("a.test", a).resolve("b.test", b);
+		let fetcher =
+			KeyFetcher::new(Resolver::with_builder(client, test_dns::resolver(Vec::new()).await?)?);
+
+		let keys = fetcher.server_keys("a.test", Some(addr.port())).await?;
+		assert_eq!(keys.server_name, "a.test");
+		assert!(keys.verify_keys.contains_key("ed25519:a"));
+		let cached = fetcher.server_keys("a.test", Some(addr.port())).await?;
+		assert_eq!(keys, cached, "second lookup is served from the cache");
+
+		let result = fetcher.server_keys("b.test", Some(tampered_addr.port())).await;
+		assert!(matches!(result, Err(Error::InvalidSignature(_))), "{:?}", result);
+		Ok(())
+	}
+
+	/// Validates querying keys through a notary.
+	#[tokio::test]
+	async fn notary() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+		let addr = mock_server.address();
+		let origin_key = SigningKey::from_bytes(&[7; 32]);
+		let notary_key = SigningKey::from_bytes(&[8; 32]);
+		let mut keys = server_keys("origin.test", &origin_key);
+		sign(&mut keys, "notary.test", "ed25519:n", &notary_key);
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/server"))
+			.respond_with(ResponseTemplate::new(200).set_body_json(json!({ "m.server": addr })))
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("POST"))
+			.and(path("/_matrix/key/v2/query"))
+			.respond_with(
+				ResponseTemplate::new(200).set_body_json(json!({ "server_keys": [keys] })),
+			)
+			.mount(&mock_server)
+			.await;
+
+		let socket = *addr;
+		let client = move || {
+			reqwest::Client::builder().resolve("origin.test", socket).resolve("notary.test", socket)
+		};
+		let resolver = Resolver::with_builder(client, test_dns::resolver(Vec::new()).await?)?;
+		let notary_public = BASE64.encode(notary_key.verifying_key().as_bytes());
+		let notary = Notary::new("notary.test", "ed25519:n", &notary_public);
+		let fetcher = KeyFetcher::new(resolver).with_notary(notary.clone());
+
+		let keys = fetcher.server_keys("origin.test", Some(addr.port())).await?;
+		assert_eq!(keys.server_name, "origin.test", "falls back to the notary");
+
+		let untrusted = Notary::new("notary.test", "ed25519:n", &BASE64.encode([1; 32]));
+		let result = fetcher.query(&untrusted, "origin.test", Some(addr.port())).await;
+		assert!(result.is_err(), "signature by an untrusted key is rejected");
+
+		let policy = FetchPolicy { max_body_size: 100, ..FetchPolicy::default() };
+		let limited = KeyFetcher::new(fetcher.resolver.clone().with_fetch_policy(policy));
+		let result = limited.query(&notary, "origin.test", Some(addr.port())).await;
+		assert!(matches!(result, Err(Error::Fetch(_))), "{:?}", result);
+		Ok(())
+	}
+}
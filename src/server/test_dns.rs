@@ -0,0 +1,63 @@
+//! DNS server answering from a fixed set of records, for tests.
+
+use tokio::net::UdpSocket;
+use trust_dns_resolver::{
+	config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+	proto::{
+		op::{Message, MessageType, ResponseCode},
+		rr::{Record, RecordType},
+		serialize::binary::{BinDecodable, BinEncodable},
+	},
+	TokioAsyncResolver,
+};
+
+/// Starts a DNS server on localhost answering queries from the given records,
+/// and constructs a resolver using it. CNAME records are returned for queries
+/// of any type.
+pub(crate) async fn resolver(
+	records: Vec<Record>,
+) -> Result<TokioAsyncResolver, Box<dyn std::error::Error>> {
+	let socket = UdpSocket::bind("127.0.0.1:0").await?;
+	let addr = socket.local_addr()?;
+	tokio::spawn(serve(socket, records));
+
+	let servers = NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true);
+	let mut opts = ResolverOpts::default();
+	opts.attempts = 1;
+	opts.use_hosts_file = false;
+	Ok(TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, Vec::new(), servers), opts)?)
+}
+
+/// Answers queries received on the socket.
+async fn serve(socket: UdpSocket, records: Vec<Record>) {
+	let mut buf = vec![0; 4096];
+	while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+		let Ok(request) = Message::from_bytes(&buf[..len]) else { continue };
+		let mut response = Message::new();
+		response
+			.set_id(request.id())
+			.set_message_type(MessageType::Response)
+			.set_op_code(request.op_code())
+			.set_recursion_desired(request.recursion_desired())
+			.set_recursion_available(true)
+			.add_queries(request.queries().to_vec());
+		for query in request.queries() {
+			response.add_answers(
+				records
+					.iter()
+					.filter(|record| record.name() == query.name())
+					.filter(|record| {
+						record.record_type() == query.query_type()
+							|| record.record_type() == RecordType::CNAME
+					})
+					.cloned(),
+			);
+		}
+		if response.answers().is_empty() {
+			response.set_response_code(ResponseCode::NXDomain);
+		}
+		if let Ok(bytes) = response.to_bytes() {
+			let _ = socket.send_to(&bytes, peer).await;
+		}
+	}
+}
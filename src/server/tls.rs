@@ -0,0 +1,540 @@
+//! Inspection of the TLS certificates presented by resolved servers.
+//!
+//! The specification defines which hostname the certificate of a server must
+//! be valid for, depending on how the server name was resolved. This is
+//! [`Server::tls_name`], which is also sent in the SNI extension.
+
+use std::{
+	convert::TryFrom,
+	net::{IpAddr, SocketAddr},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tracing::{debug, instrument};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName};
+
+use super::Server;
+
+/// TLS implementation used for the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Backend {
+	/// The platform's native TLS implementation.
+	#[cfg(feature = "native-tls")]
+	NativeTls,
+	/// rustls with the webpki root certificates.
+	#[cfg(feature = "rustls")]
+	Rustls,
+}
+
+impl Default for Backend {
+	fn default() -> Self {
+		#[cfg(feature = "rustls")]
+		let backend = Self::Rustls;
+		#[cfg(not(feature = "rustls"))]
+		let backend = Self::NativeTls;
+		backend
+	}
+}
+
+/// A certificate presented by a server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Certificate {
+	/// The subject distinguished name.
+	pub subject: String,
+	/// The issuer distinguished name.
+	pub issuer: String,
+	/// Start of the validity period, in seconds since the unix epoch.
+	pub not_before: i64,
+	/// End of the validity period, in seconds since the unix epoch.
+	pub not_after: i64,
+	/// DNS names and IP addresses from the subject alternative name
+	/// extension.
+	pub subject_alt_names: Vec<String>,
+	/// The DER encoded certificate.
+	#[serde(skip)]
+	pub der: Vec<u8>,
+}
+
+impl Certificate {
+	/// Parse a DER encoded certificate.
+	pub fn from_der(der: &[u8]) -> Result<Self> {
+		let (_, cert) = x509_parser::parse_x509_certificate(der)
+			.map_err(|e| Error::Certificate(e.to_string()))?;
+		Ok(Self {
+			subject: cert.subject().to_string(),
+			issuer: cert.issuer().to_string(),
+			not_before: cert.validity().not_before.timestamp(),
+			not_after: cert.validity().not_after.timestamp(),
+			subject_alt_names: subject_alt_names(&cert),
+			der: der.to_vec(),
+		})
+	}
+
+	/// Whether the certificate is valid for the given hostname or IP
+	/// address. Wildcards match a single label only.
+	#[must_use]
+	pub fn is_valid_for(&self, name: &str) -> bool {
+		let name = name.trim_end_matches('.').to_ascii_lowercase();
+		let ip = name.parse::<IpAddr>().ok();
+		self.subject_alt_names.iter().any(|san| {
+			if let Some(ip) = ip {
+				return san.parse::<IpAddr>().ok() == Some(ip);
+			}
+			let san = san.trim_end_matches('.').to_ascii_lowercase();
+			match san.strip_prefix("*.") {
+				Some(parent) => name
+					.split_once('.')
+					.is_some_and(|(label, rest)| !label.is_empty() && rest == parent),
+				None => san == name,
+			}
+		})
+	}
+
+	/// Whether the current time is outside of the validity period.
+	#[must_use]
+	pub fn is_expired(&self) -> bool {
+		let now = unix_time(SystemTime::now());
+		now < self.not_before || now > self.not_after
+	}
+
+	/// Whether the validity period ends within the given time from now.
+	#[must_use]
+	pub fn expires_within(&self, period: Duration) -> bool {
+		unix_time(SystemTime::now() + period) > self.not_after
+	}
+}
+
+/// The result of a TLS handshake with a server.
+#[derive(Debug, Clone, Serialize)]
+pub struct TlsReport {
+	/// The address which was connected to.
+	pub address: SocketAddr,
+	/// The name the certificate must be valid for.
+	pub expected_name: String,
+	/// The TLS implementation used.
+	pub backend: Backend,
+	/// The certificates presented by the server, starting with the end
+	/// entity certificate. The native TLS backend only reports the end
+	/// entity certificate.
+	pub chain: Vec<Certificate>,
+	/// Whether the end entity certificate is valid for the expected name.
+	pub valid_for_name: bool,
+	/// Why the TLS implementation rejected the certificate, if it did.
+	pub verification_error: Option<String>,
+}
+
+impl TlsReport {
+	/// The end entity certificate.
+	#[must_use]
+	pub fn certificate(&self) -> Option<&Certificate> {
+		self.chain.first()
+	}
+
+	/// Whether the certificate was accepted for the expected name.
+	#[must_use]
+	pub fn is_valid(&self) -> bool {
+		self.verification_error.is_none() && self.valid_for_name
+	}
+}
+
+/// Performs TLS handshakes with servers to inspect their certificates.
+#[derive(Debug, Clone)]
+pub struct TlsInspector {
+	/// TLS implementation to use.
+	backend: Backend,
+	/// Additional trusted DER encoded root certificates.
+	roots: Vec<Vec<u8>>,
+	/// Timeout for connecting and the handshake.
+	timeout: Duration,
+}
+
+impl Default for TlsInspector {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl TlsInspector {
+	/// Constructs an inspector using the default backend and root
+	/// certificates.
+	#[must_use]
+	pub fn new() -> Self {
+		Self { backend: Backend::default(), roots: Vec::new(), timeout: Duration::from_secs(30) }
+	}
+
+	/// Sets the TLS implementation to use.
+	#[must_use]
+	pub fn with_backend(mut self, backend: Backend) -> Self {
+		self.backend = backend;
+		self
+	}
+
+	/// Trusts an additional DER encoded root certificate.
+	#[must_use]
+	pub fn with_root_certificate(mut self, der: Vec<u8>) -> Self {
+		self.roots.push(der);
+		self
+	}
+
+	/// Sets the timeout for connecting and the handshake.
+	#[must_use]
+	pub fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		self
+	}
+
+	/// Perform a TLS handshake with a resolved server at one of its
+	/// addresses and report the certificate it presented.
+	#[instrument(skip(self), err)]
+	pub async fn inspect(&self, server: &Server, address: SocketAddr) -> Result<TlsReport> {
+		let name = server.tls_name();
+		let handshake = async {
+			match self.backend {
+				#[cfg(feature = "native-tls")]
+				Backend::NativeTls => self.native_tls(&name, address).await,
+				#[cfg(feature = "rustls")]
+				Backend::Rustls => self.rustls(&name, address).await,
+			}
+		};
+		let (chain, verification_error) =
+			tokio::time::timeout(self.timeout, handshake).await.map_err(|_| Error::Timeout)??;
+		if let Some(e) = &verification_error {
+			debug!("Certificate was rejected: {}", e);
+		}
+		let chain =
+			chain.iter().map(|der| Certificate::from_der(der)).collect::<Result<Vec<_>>>()?;
+		let valid_for_name = chain.first().ok_or(Error::NoCertificate)?.is_valid_for(&name);
+		Ok(TlsReport {
+			address,
+			expected_name: name,
+			backend: self.backend,
+			chain,
+			valid_for_name,
+			verification_error,
+		})
+	}
+
+	/// Handshake using native-tls. If verification fails, the handshake is
+	/// repeated without verification to obtain the certificate.
+	#[cfg(feature = "native-tls")]
+	async fn native_tls(
+		&self,
+		name: &str,
+		address: SocketAddr,
+	) -> Result<(Vec<Vec<u8>>, Option<String>)> {
+		use tokio_native_tls::native_tls;
+
+		let connector = |verify: bool| -> Result<tokio_native_tls::TlsConnector> {
+			let mut builder = native_tls::TlsConnector::builder();
+			for root in &self.roots {
+				builder.add_root_certificate(native_tls::Certificate::from_der(root)?);
+			}
+			builder.danger_accept_invalid_certs(!verify).danger_accept_invalid_hostnames(!verify);
+			Ok(builder.build()?.into())
+		};
+
+		let stream = TcpStream::connect(address).await.map_err(Error::Connect)?;
+		let (stream, verification_error) = match connector(true)?.connect(name, stream).await {
+			Ok(stream) => (stream, None),
+			Err(e) => {
+				let stream = TcpStream::connect(address).await.map_err(Error::Connect)?;
+				(connector(false)?.connect(name, stream).await?, Some(e.to_string()))
+			}
+		};
+		let certificate = stream.get_ref().peer_certificate()?;
+		let chain = certificate.map(|cert| cert.to_der()).transpose()?.into_iter().collect();
+		Ok((chain, verification_error))
+	}
+
+	/// Handshake using rustls, recording the chain and the verification
+	/// result without aborting the handshake.
+	#[cfg(feature = "rustls")]
+	async fn rustls(
+		&self,
+		name: &str,
+		address: SocketAddr,
+	) -> Result<(Vec<Vec<u8>>, Option<String>)> {
+		use std::sync::Arc;
+
+		use tokio_rustls::{
+			rustls::{self, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+			TlsConnector,
+		};
+
+		let mut roots = RootCertStore::empty();
+		roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+			OwnedTrustAnchor::from_subject_spki_name_constraints(
+				anchor.subject,
+				anchor.spki,
+				anchor.name_constraints,
+			)
+		}));
+		for root in &self.roots {
+			roots
+				.add(&rustls::Certificate(root.clone()))
+				.map_err(|e| Error::Certificate(e.to_string()))?;
+		}
+		let verifier = Arc::new(rustls_verifier::RecordingVerifier::new(roots));
+		let config = ClientConfig::builder()
+			.with_safe_defaults()
+			.with_custom_certificate_verifier(verifier.clone())
+			.with_no_client_auth();
+		let server_name =
+			ServerName::try_from(name).map_err(|_| Error::InvalidName(name.to_owned()))?;
+
+		let stream = TcpStream::connect(address).await.map_err(Error::Connect)?;
+		TlsConnector::from(Arc::new(config)).connect(server_name, stream).await?;
+		Ok(verifier.result())
+	}
+}
+
+/// Certificate verifier for rustls which records the presented chain.
+#[cfg(feature = "rustls")]
+mod rustls_verifier {
+	use std::{sync::Mutex, time::SystemTime};
+
+	use tokio_rustls::rustls::{
+		client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+		Certificate, Error, RootCertStore, ServerName,
+	};
+
+	/// Verifies certificates with webpki, but records the outcome instead of
+	/// failing the handshake.
+	pub(super) struct RecordingVerifier {
+		/// The actual verifier.
+		inner: WebPkiVerifier,
+		/// The presented chain and the verification error.
+		result: Mutex<(Vec<Vec<u8>>, Option<String>)>,
+	}
+
+	impl RecordingVerifier {
+		/// Constructs a verifier trusting the given roots.
+		pub(super) fn new(roots: RootCertStore) -> Self {
+			Self { inner: WebPkiVerifier::new(roots, None), result: Mutex::default() }
+		}
+
+		/// The recorded chain and verification error.
+		pub(super) fn result(&self) -> (Vec<Vec<u8>>, Option<String>) {
+			self.result.lock().map(|result| result.clone()).unwrap_or_default()
+		}
+	}
+
+	impl ServerCertVerifier for RecordingVerifier {
+		fn verify_server_cert(
+			&self,
+			end_entity: &Certificate,
+			intermediates: &[Certificate],
+			server_name: &ServerName,
+			scts: &mut dyn Iterator<Item = &[u8]>,
+			ocsp_response: &[u8],
+			now: SystemTime,
+		) -> Result<ServerCertVerified, Error> {
+			let chain = std::iter::once(end_entity)
+				.chain(intermediates)
+				.map(|cert| cert.0.clone())
+				.collect();
+			let error = self
+				.inner
+				.verify_server_cert(
+					end_entity,
+					intermediates,
+					server_name,
+					scts,
+					ocsp_response,
+					now,
+				)
+				.err()
+				.map(|e| e.to_string());
+			if let Ok(mut result) = self.result.lock() {
+				*result = (chain, error);
+			}
+			Ok(ServerCertVerified::assertion())
+		}
+	}
+}
+
+/// Collect the DNS names and IP addresses of a certificate's subject
+/// alternative name extension.
+fn subject_alt_names(cert: &X509Certificate<'_>) -> Vec<String> {
+	let Ok(Some(extension)) = cert.subject_alternative_name() else { return Vec::new() };
+	extension
+		.value
+		.general_names
+		.iter()
+		.filter_map(|name| match name {
+			GeneralName::DNSName(name) => Some((*name).to_owned()),
+			GeneralName::IPAddress(bytes) => match bytes.len() {
+				4 => <[u8; 4]>::try_from(*bytes).ok().map(|ip| IpAddr::from(ip).to_string()),
+				16 => <[u8; 16]>::try_from(*bytes).ok().map(|ip| IpAddr::from(ip).to_string()),
+				_ => None,
+			},
+			_ => None,
+		})
+		.collect()
+}
+
+/// Seconds since the unix epoch.
+fn unix_time(time: SystemTime) -> i64 {
+	time.duration_since(UNIX_EPOCH)
+		.ok()
+		.and_then(|time| i64::try_from(time.as_secs()).ok())
+		.unwrap_or_default()
+}
+
+/// The result of inspecting a TLS connection.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors that can happen when inspecting a TLS connection.
+#[derive(Debug)]
+pub enum Error {
+	/// The TCP connection could not be established.
+	Connect(std::io::Error),
+	/// The handshake failed.
+	Io(std::io::Error),
+	/// An error from the native TLS implementation.
+	#[cfg(feature = "native-tls")]
+	NativeTls(tokio_native_tls::native_tls::Error),
+	/// The connection or handshake timed out.
+	Timeout,
+	/// The expected name is not a valid hostname or IP address.
+	InvalidName(String),
+	/// A certificate could not be parsed.
+	Certificate(String),
+	/// The server did not present a certificate.
+	NoCertificate,
+}
+
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Connect(e) | Self::Io(e) => write!(f, "{}", e),
+			#[cfg(feature = "native-tls")]
+			Self::NativeTls(e) => write!(f, "{}", e),
+			Self::Timeout => write!(f, "Timed out"),
+			Self::InvalidName(name) => write!(f, "{} is not a valid server name", name),
+			Self::Certificate(e) => write!(f, "Invalid certificate: {}", e),
+			Self::NoCertificate => write!(f, "No certificate was presented"),
+		}
+	}
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Self::Connect(e) | Self::Io(e) => Some(e),
+			#[cfg(feature = "native-tls")]
+			Self::NativeTls(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl From<std::io::Error> for Error {
+	fn from(err: std::io::Error) -> Self {
+		Self::Io(err)
+	}
+}
+
+#[cfg(feature = "native-tls")]
+impl From<tokio_native_tls::native_tls::Error> for Error {
+	fn from(err: tokio_native_tls::native_tls::Error) -> Self {
+		Self::NativeTls(err)
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+	use rcgen::{
+		date_time_ymd, BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+		IsCa,
+	};
+	use tokio::net::TcpListener;
+	use tokio_rustls::{
+		rustls::{self, ServerConfig},
+		TlsAcceptor,
+	};
+
+	use super::{Backend, TlsInspector};
+	use crate::server::Server;
+
+	/// Starts a TLS listener with a certificate for the given names signed by
+	/// a new CA, returning its address and the DER encoded CA certificate.
+	pub(crate) async fn listener(
+		names: &[&str],
+		expired: bool,
+	) -> Result<(SocketAddr, Vec<u8>), Box<dyn std::error::Error>> {
+		let mut ca_params = CertificateParams::new(Vec::new());
+		ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+		ca_params.distinguished_name = DistinguishedName::new();
+		ca_params.distinguished_name.push(DnType::CommonName, "Test CA");
+		let ca = Certificate::from_params(ca_params)?;
+		let mut leaf_params =
+			CertificateParams::new(names.iter().map(|name| (*name).to_owned()).collect::<Vec<_>>());
+		leaf_params.distinguished_name = DistinguishedName::new();
+		leaf_params
+			.distinguished_name
+			.push(DnType::CommonName, names.first().copied().unwrap_or_default());
+		if expired {
+			leaf_params.not_before = date_time_ymd(2000, 1, 1);
+			leaf_params.not_after = date_time_ymd(2001, 1, 1);
+		}
+		let leaf = Certificate::from_params(leaf_params)?;
+		let config =
+			ServerConfig::builder().with_safe_defaults().with_no_client_auth().with_single_cert(
+				vec![rustls::Certificate(leaf.serialize_der_with_signer(&ca)?)],
+				rustls::PrivateKey(leaf.serialize_private_key_der()),
+			)?;
+		let acceptor = TlsAcceptor::from(Arc::new(config));
+		let listener = TcpListener::bind("127.0.0.1:0").await?;
+		let addr = listener.local_addr()?;
+		tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				let _ = acceptor.accept(stream).await;
+			}
+		});
+		Ok((addr, ca.serialize_der()?))
+	}
+
+	/// Validates the reports of trusted, mismatched and untrusted
+	/// certificates with every enabled backend.
+	#[tokio::test]
+	async fn inspect() -> Result<(), Box<dyn std::error::Error>> {
+		let (addr, ca) = listener(&["localhost", "127.0.0.1"], false).await?;
+		let backends = [
+			#[cfg(feature = "native-tls")]
+			Backend::NativeTls,
+			#[cfg(feature = "rustls")]
+			Backend::Rustls,
+		];
+		for backend in backends.iter().copied() {
+			let inspector = TlsInspector::new().with_backend(backend);
+			let trusting = inspector.clone().with_root_certificate(ca.clone());
+
+			let report = trusting.inspect(&Server::Host(String::from("localhost")), addr).await?;
+			assert!(report.is_valid(), "{:?}: {:?}", backend, report.verification_error);
+			let certificate = report.certificate().ok_or("missing certificate")?;
+			assert_eq!(certificate.subject_alt_names, ["localhost", "127.0.0.1"]);
+			assert!(!certificate.is_expired());
+			assert!(!certificate.expires_within(Duration::from_secs(60)));
+			assert!(certificate.is_valid_for("LOCALHOST."));
+
+			let report = trusting.inspect(&Server::Socket(addr), addr).await?;
+			assert!(report.is_valid(), "{:?}: IP address in SAN", backend);
+
+			let other = Server::HostPort(format!("example.test:{}", addr.port()));
+			let report = trusting.inspect(&other, addr).await?;
+			assert_eq!(report.expected_name, "example.test");
+			assert!(!report.valid_for_name, "{:?}: name mismatch", backend);
+			assert!(report.verification_error.is_some(), "{:?}: name mismatch", backend);
+
+			let report = inspector.inspect(&Server::Host(String::from("localhost")), addr).await?;
+			assert!(report.valid_for_name);
+			assert!(report.verification_error.is_some(), "{:?}: untrusted CA", backend);
+		}
+		Ok(())
+	}
+}
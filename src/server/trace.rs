@@ -0,0 +1,214 @@
+//! Record of the steps taken while resolving a server name.
+
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// How long .well-known information is reused if the response doesn't say.
+const WELL_KNOWN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// The longest time .well-known information is reused.
+const MAX_WELL_KNOWN_TTL: Duration = Duration::from_secs(48 * 60 * 60);
+/// How long a failure to fetch .well-known information is remembered.
+const WELL_KNOWN_ERROR_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A step of the server name resolution algorithm, as numbered in [the
+/// specification].
+///
+/// [the specification]: https://spec.matrix.org/latest/server-server-api/#resolving-server-names
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Step {
+	/// The server name matched a static override, before the algorithm of the
+	/// specification is applied.
+	Override,
+	/// 1. The server name is an IP literal.
+	IpLiteral,
+	/// 2. The server name is a hostname with an explicit port.
+	HostPort,
+	/// 3. The .well-known information is requested.
+	WellKnown,
+	/// 3.1 The delegated hostname is an IP literal.
+	DelegatedIpLiteral,
+	/// 3.2 The delegated hostname includes a port.
+	DelegatedHostPort,
+	/// 3.3 The `_matrix-fed._tcp` SRV record of the delegated hostname.
+	DelegatedSrv,
+	/// 3.4 The deprecated `_matrix._tcp` SRV record of the delegated hostname.
+	DelegatedSrvDeprecated,
+	/// 3.5 The delegated hostname is used directly.
+	DelegatedHost,
+	/// 4. The `_matrix-fed._tcp` SRV record of the server name.
+	Srv,
+	/// 5. The deprecated `_matrix._tcp` SRV record of the server name.
+	SrvDeprecated,
+	/// 6. The server name is used directly.
+	Host,
+}
+
+impl Step {
+	/// The number of the step in the specification, `0` for overrides.
+	#[must_use]
+	pub fn number(self) -> &'static str {
+		match self {
+			Self::Override => "0",
+			Self::IpLiteral => "1",
+			Self::HostPort => "2",
+			Self::WellKnown => "3",
+			Self::DelegatedIpLiteral => "3.1",
+			Self::DelegatedHostPort => "3.2",
+			Self::DelegatedSrv => "3.3",
+			Self::DelegatedSrvDeprecated => "3.4",
+			Self::DelegatedHost => "3.5",
+			Self::Srv => "4",
+			Self::SrvDeprecated => "5",
+			Self::Host => "6",
+		}
+	}
+}
+
+/// Every step taken while resolving a server name.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResolutionTrace {
+	/// The server name which was resolved.
+	pub name: String,
+	/// The steps in the order they were tried.
+	pub steps: Vec<TraceStep>,
+	/// The pattern of the static override which determined the result, if
+	/// any.
+	pub matched_override: Option<String>,
+	/// Time spent on the whole resolution.
+	pub elapsed: Duration,
+}
+
+/// A single step taken while resolving a server name.
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceStep {
+	/// Which step of the algorithm this is.
+	pub step: Step,
+	/// Whether this step determined the result. For the .well-known step, this
+	/// means valid delegation information was received.
+	pub matched: bool,
+	/// Time spent on this step.
+	pub elapsed: Duration,
+	/// The HTTP request made in this step, if any.
+	pub http: Option<HttpTrace>,
+	/// The DNS lookup made in this step, if any.
+	pub dns: Option<DnsTrace>,
+}
+
+/// An HTTP request made during resolution.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HttpTrace {
+	/// The requested URLs, including redirects that were followed.
+	pub urls: Vec<String>,
+	/// The status code of the final response.
+	pub status: Option<u16>,
+	/// The body of the final response.
+	pub body: Option<String>,
+	/// The number of seconds the final response may be cached for, from its
+	/// `Cache-Control` header.
+	pub max_age: Option<u64>,
+	/// The reason the request or its response was rejected.
+	pub error: Option<String>,
+}
+
+/// An SRV lookup made during resolution.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DnsTrace {
+	/// The queried name.
+	pub query: String,
+	/// The records received.
+	pub records: Vec<SrvRecord>,
+	/// The reason the lookup failed.
+	pub error: Option<String>,
+	/// Whether the lookup failed for a reason other than the record not
+	/// existing, like an unreachable DNS server.
+	pub transient: bool,
+}
+
+/// An SRV record received during resolution.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SrvRecord {
+	/// The target hostname, without the trailing dot.
+	pub target: String,
+	/// The target port.
+	pub port: u16,
+	/// The priority of the record, lower values are preferred.
+	pub priority: u16,
+	/// The weight of the record among those with the same priority.
+	pub weight: u16,
+	/// The time to live of the record in seconds.
+	pub ttl: u32,
+}
+
+impl ResolutionTrace {
+	/// Constructs an empty trace for the given server name.
+	pub(crate) fn new(name: &str) -> Self {
+		Self {
+			name: name.to_owned(),
+			steps: Vec::new(),
+			matched_override: None,
+			elapsed: Duration::default(),
+		}
+	}
+
+	/// Records a step which was started at the given time.
+	pub(crate) fn push(&mut self, step: Step, matched: bool, started: Instant) -> &mut TraceStep {
+		self.steps.push(TraceStep {
+			step,
+			matched,
+			elapsed: started.elapsed(),
+			http: None,
+			dns: None,
+		});
+		#[allow(clippy::expect_used)]
+		self.steps.last_mut().expect("a step was just pushed")
+	}
+
+	/// The step which determined the result.
+	#[must_use]
+	pub fn decisive_step(&self) -> Option<&TraceStep> {
+		self.steps.iter().rev().find(|step| step.matched)
+	}
+
+	/// Whether a step failed for a reason which is likely temporary, like an
+	/// unreachable server, so the result may differ from the usual one. A
+	/// missing .well-known file or SRV record doesn't count.
+	#[must_use]
+	pub fn is_degraded(&self) -> bool {
+		self.steps.iter().any(|step| {
+			let http = step.http.as_ref().is_some_and(|http| {
+				http.status.map_or(http.error.is_some(), |status| status >= 500)
+			});
+			http || step.dns.as_ref().is_some_and(|dns| dns.transient)
+		})
+	}
+
+	/// How long the result may be reused. .well-known information is cached
+	/// as the specification recommends, and the result expires with the SRV
+	/// record it came from. Results which didn't need the .well-known
+	/// information, like IP literals, aren't worth caching and return `None`.
+	#[must_use]
+	pub fn ttl(&self) -> Option<Duration> {
+		let well_known = self.steps.iter().find(|step| step.step == Step::WellKnown)?;
+		let ttl = if well_known.matched {
+			well_known
+				.http
+				.as_ref()
+				.and_then(|http| http.max_age)
+				.map_or(WELL_KNOWN_TTL, |max_age| {
+					Duration::from_secs(max_age).min(MAX_WELL_KNOWN_TTL)
+				})
+		} else {
+			WELL_KNOWN_ERROR_TTL
+		};
+		let srv_ttl = self
+			.steps
+			.iter()
+			.filter(|step| step.matched)
+			.filter_map(|step| step.dns.as_ref())
+			.flat_map(|dns| dns.records.iter())
+			.map(|record| Duration::from_secs(record.ttl.into()))
+			.min();
+		Some(srv_ttl.map_or(ttl, |srv_ttl| srv_ttl.min(ttl)))
+	}
+}
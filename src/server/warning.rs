@@ -0,0 +1,310 @@
+//! Misconfigurations of the delegation which resolution works around or
+//! which break federation later on.
+
+use std::time::Duration;
+
+use reqwest::Url;
+use serde::Serialize;
+use tracing::debug;
+use trust_dns_resolver::proto::rr::{RData, RecordType};
+
+use super::{
+	split_port,
+	trace::{ResolutionTrace, Step},
+	Resolver, Server,
+};
+use crate::{
+	fetch::{self, FetchPolicy},
+	lint::is_server_name,
+};
+
+/// How long the probe of an SRV target may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A misconfiguration noticed while resolving a server name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum Warning {
+	/// The .well-known information delegates the server name to itself, which
+	/// has no effect.
+	SelfDelegation,
+	/// The delegated server name is not a hostname or IP literal with an
+	/// optional port, like a URL with `https://` or a path.
+	InvalidDelegation(String),
+	/// The .well-known request was redirected to the given URL on another
+	/// domain.
+	CrossDomainRedirect(String),
+	/// The target of the SRV record is a CNAME, which the specification
+	/// forbids.
+	SrvCname {
+		/// The target of the SRV record.
+		target: String,
+		/// The name the target is an alias of.
+		cname: String,
+	},
+	/// The SRV record points at port 443 of a host which responds with plain
+	/// HTTP there, while federation requires TLS.
+	SrvPlainHttp {
+		/// The target of the SRV record.
+		target: String,
+	},
+}
+
+impl std::fmt::Display for Warning {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::SelfDelegation => write!(f, "the .well-known information delegates to itself"),
+			Self::InvalidDelegation(server) => {
+				write!(
+					f,
+					"the delegated server name {:?} is not a hostname with optional port",
+					server
+				)
+			}
+			Self::CrossDomainRedirect(url) => {
+				write!(f, "the .well-known request was redirected to {}", url)
+			}
+			Self::SrvCname { target, cname } => {
+				write!(f, "the SRV target {} is a CNAME for {}", target, cname)
+			}
+			Self::SrvPlainHttp { target } => {
+				write!(f, "the SRV target {} speaks plain HTTP on port 443", target)
+			}
+		}
+	}
+}
+
+/// Checks the .well-known request in the trace and the server name it
+/// delegated to.
+pub(super) fn check_trace(
+	name: &str,
+	trace: &ResolutionTrace,
+	server: Option<&Server>,
+) -> Vec<Warning> {
+	let mut warnings = Vec::new();
+	let Some(step) = trace.steps.iter().find(|step| step.step == Step::WellKnown) else {
+		return warnings;
+	};
+	if let Some(url) = step.http.as_ref().and_then(|http| redirect(name, &http.urls)) {
+		warnings.push(Warning::CrossDomainRedirect(url.to_owned()));
+	}
+	// IP literals are valid and never the server name itself
+	let delegated = match server {
+		Some(Server::Host(host) | Server::HostPort(host) | Server::Srv(_, host))
+			if step.matched =>
+		{
+			host
+		}
+		_ => return warnings,
+	};
+	if !is_server_name(delegated) {
+		warnings.push(Warning::InvalidDelegation(delegated.clone()));
+	} else if normalize(delegated) == normalize(name) {
+		warnings.push(Warning::SelfDelegation);
+	}
+	warnings
+}
+
+/// The final URL, if the requests were redirected away from the server name
+/// and its subdomains.
+fn redirect<'a>(name: &str, urls: &'a [String]) -> Option<&'a str> {
+	let url = urls.last()?;
+	let host = normalize(Url::parse(url).ok()?.host_str()?);
+	let name = normalize(name);
+	(host != name && !host.ends_with(&format!(".{}", name))).then_some(url.as_str())
+}
+
+impl Resolver {
+	/// Checks the target of an SRV record, if the delegation checks are
+	/// enabled.
+	#[cfg_attr(test, allow(unused_variables))]
+	pub(super) async fn check_srv_target(
+		&self,
+		target: &str,
+		warnings: &mut Vec<Warning>,
+		#[cfg(test)] port: Option<u16>,
+	) {
+		if !self.check_delegation {
+			return;
+		}
+		let Some((host, srv_port)) = split_port(target) else { return };
+		if let Ok(lookup) = self.resolver.lookup(host, RecordType::CNAME).await {
+			let cname = lookup.record_iter().find_map(|record| match record.data() {
+				Some(RData::CNAME(cname)) => Some(cname.to_ascii()),
+				_ => None,
+			});
+			if let Some(cname) = cname {
+				warnings.push(Warning::SrvCname {
+					target: host.to_owned(),
+					cname: cname.trim_end_matches('.').to_owned(),
+				});
+			}
+		}
+		if srv_port != 443 {
+			return;
+		}
+		#[cfg(test)]
+		let srv_port = port.unwrap_or(srv_port);
+		let Ok(url) = Url::parse(&format!("http://{}:{}/_matrix/key/v2/server", host, srv_port))
+		else {
+			return;
+		};
+		// Only keys served without TLS prove that the port speaks plain HTTP,
+		// TLS servers answer with errors like 400 Bad Request
+		let policy = FetchPolicy { max_redirects: 0, ..self.fetch_policy };
+		let check = |url| self.check_url(url);
+		let probe = fetch::get_checked(&self.http, url.clone(), &policy, check);
+		match tokio::time::timeout(PROBE_TIMEOUT, probe).await {
+			Ok(Ok(Ok(response)))
+				if response.status().is_success() && is_key_response(&response) =>
+			{
+				warnings.push(Warning::SrvPlainHttp { target: host.to_owned() });
+			}
+			Ok(Ok(Ok(_))) => {}
+			Ok(Ok(Err(e))) => debug!("Probing {} for plain HTTP failed: {}", url, e),
+			Ok(Err(e)) => debug!("Not probing {} for plain HTTP: {}", url, e),
+			Err(_) => debug!("Probing {} for plain HTTP timed out", url),
+		}
+	}
+}
+
+/// Whether the response holds the keys of a server.
+fn is_key_response(response: &fetch::Response) -> bool {
+	response.json::<serde_json::Value>().is_ok_and(|keys| {
+		keys.get("server_name").is_some_and(serde_json::Value::is_string)
+			&& keys.get("verify_keys").is_some_and(serde_json::Value::is_object)
+	})
+}
+
+/// The hostname in lowercase and without trailing dot.
+fn normalize(host: &str) -> String {
+	host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::Ipv4Addr;
+
+	use trust_dns_resolver::proto::rr::{rdata::SRV, Name, RData, Record};
+	use wiremock::{
+		matchers::{method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::Warning;
+	use crate::server::{filter::IpFilter, test_dns, Resolver};
+
+	/// Validates the warning for every misconfiguration.
+	#[tokio::test]
+	async fn warnings() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+		let addr = mock_server.address();
+		let port = addr.port();
+		let well_known = |server: &str| {
+			ResponseTemplate::new(200)
+				.set_body_raw(format!(r#"{{"m.server": "{}"}}"#, server), "application/json")
+		};
+		let socket = *addr;
+		let http = move || {
+			reqwest::Client::builder()
+				.resolve("self.test", socket)
+				.resolve("scheme.test", socket)
+				.resolve("redirect.test", socket)
+				.resolve("other.test", socket)
+				.resolve("srv.test", socket)
+				.resolve("alias.test", socket)
+		};
+		let dns = test_dns::resolver(vec![
+			Record::from_rdata(
+				Name::from_ascii("_matrix._tcp.srv.test.")?,
+				300,
+				RData::SRV(SRV::new(10, 5, 443, Name::from_ascii("alias.test.")?)),
+			),
+			Record::from_rdata(
+				Name::from_ascii("alias.test.")?,
+				300,
+				RData::CNAME(Name::from_ascii("destination.test.")?),
+			),
+			Record::from_rdata(
+				Name::from_ascii("destination.test.")?,
+				300,
+				RData::A(Ipv4Addr::LOCALHOST),
+			),
+		])
+		.await?;
+		let resolver = Resolver::with_builder(http, dns)?;
+		let warnings = |name: &'static str| {
+			let resolver = resolver.clone();
+			async move {
+				resolver
+					.resolve_traced(name, Some(port))
+					.await
+					.map(|resolution| resolution.warnings)
+			}
+		};
+
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/server"))
+			.respond_with(well_known("Self.test."))
+			.mount(&mock_server)
+			.await;
+		assert_eq!(warnings("self.test").await?, [Warning::SelfDelegation]);
+
+		mock_server.reset().await;
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/server"))
+			.respond_with(well_known("https://destination.test"))
+			.mount(&mock_server)
+			.await;
+		assert_eq!(
+			warnings("scheme.test").await?,
+			[Warning::InvalidDelegation(String::from("https://destination.test"))]
+		);
+
+		mock_server.reset().await;
+		let target = format!("http://other.test:{}/delegation", port);
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/server"))
+			.respond_with(ResponseTemplate::new(302).insert_header("Location", target.as_str()))
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/delegation"))
+			.respond_with(well_known("destination.test:8448"))
+			.mount(&mock_server)
+			.await;
+		assert_eq!(warnings("redirect.test").await?, [Warning::CrossDomainRedirect(target)]);
+
+		mock_server.reset().await;
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/server"))
+			.respond_with(ResponseTemplate::new(404))
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/_matrix/key/v2/server"))
+			.respond_with(ResponseTemplate::new(200).set_body_raw(
+				r#"{"server_name": "srv.test", "verify_keys": {}}"#,
+				"application/json",
+			))
+			.mount(&mock_server)
+			.await;
+		assert!(
+			warnings("srv.test").await?.is_empty(),
+			"the SRV target is only checked on request"
+		);
+		let cname = Warning::SrvCname {
+			target: String::from("alias.test"),
+			cname: String::from("destination.test"),
+		};
+		let checked = resolver.with_delegation_checks(true);
+		let resolution = checked.resolve_traced("srv.test", Some(port)).await?;
+		assert_eq!(
+			resolution.warnings,
+			[cname.clone(), Warning::SrvPlainHttp { target: String::from("alias.test") }]
+		);
+		let strict = checked.with_ip_filter(IpFilter::federation_default());
+		let resolution = strict.resolve_traced("srv.test", Some(port)).await?;
+		assert_eq!(resolution.warnings, [cname], "denied targets aren't probed");
+		Ok(())
+	}
+}
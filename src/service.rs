@@ -0,0 +1,189 @@
+//! HTTP API exposing the resolvers, so that several tools can query one
+//! oracle and share its cache.
+//!
+//! All endpoints take the `server_name` query parameter:
+//!
+//! - `GET /api/report` diagnoses the federation setup and responds with a
+//!   [`ServerReport`] in the format of the federation tester.
+//! - `GET /api/federation-ok` responds with `GOOD` or `BAD`.
+//! - `GET /api/resolve` responds with the [`Resolution`] of the server name.
+//! - `GET /api/discover` responds with the [`Discovery`] of the homeserver.
+//!
+//! Failed resolutions are reported with status 502 and an `error` field.
+
+use std::sync::Arc;
+
+use axum::{
+	extract::{Query, State},
+	http::StatusCode,
+	routing::get,
+	Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use self::report::ServerReport;
+use crate::{
+	client::{self, Discovery},
+	server::{self, diagnose::Verdict, Resolution},
+};
+
+pub mod report;
+
+/// The resolvers shared by the handlers.
+#[derive(Debug)]
+struct Resolvers {
+	/// The resolver for the server-server API.
+	server: server::Resolver,
+	/// The resolver for the client-server API.
+	client: client::Resolver,
+}
+
+/// The query parameters of the endpoints.
+#[derive(Debug, Deserialize)]
+struct ServerName {
+	/// The server name to resolve.
+	server_name: String,
+}
+
+/// The body of error responses.
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+	/// The error message.
+	error: String,
+}
+
+/// The response of a handler that resolves a server name.
+type Response<T> = Result<Json<T>, (StatusCode, Json<ErrorBody>)>;
+
+/// Constructs the router serving the API with the given resolvers. Sharing a
+/// [cache](crate::cache::SharedCache) between them avoids fetching the same
+/// responses for both APIs.
+pub fn router(server: server::Resolver, client: client::Resolver) -> Router {
+	Router::new()
+		.route("/api/report", get(report))
+		.route("/api/federation-ok", get(federation_ok))
+		.route("/api/resolve", get(resolve))
+		.route("/api/discover", get(discover))
+		.with_state(Arc::new(Resolvers { server, client }))
+}
+
+/// Diagnoses the federation setup of the server name.
+async fn report(
+	State(resolvers): State<Arc<Resolvers>>,
+	Query(query): Query<ServerName>,
+) -> Json<ServerReport> {
+	let report = resolvers
+		.server
+		.diagnose(
+			&query.server_name,
+			#[cfg(test)]
+			None,
+		)
+		.await;
+	Json(ServerReport::from(&report))
+}
+
+/// Whether federation with the server works.
+async fn federation_ok(
+	State(resolvers): State<Arc<Resolvers>>,
+	Query(query): Query<ServerName>,
+) -> &'static str {
+	let report = resolvers
+		.server
+		.diagnose(
+			&query.server_name,
+			#[cfg(test)]
+			None,
+		)
+		.await;
+	if report.verdict() == Verdict::Fail {
+		"BAD"
+	} else {
+		"GOOD"
+	}
+}
+
+/// Resolves the server name for the server-server API.
+async fn resolve(
+	State(resolvers): State<Arc<Resolvers>>,
+	Query(query): Query<ServerName>,
+) -> Response<Resolution> {
+	resolvers
+		.server
+		.resolve_traced(
+			&query.server_name,
+			#[cfg(test)]
+			None,
+		)
+		.await
+		.map(Json)
+		.map_err(|e| bad_gateway(&e))
+}
+
+/// Discovers the homeserver for the client-server API.
+async fn discover(
+	State(resolvers): State<Arc<Resolvers>>,
+	Query(query): Query<ServerName>,
+) -> Response<Discovery> {
+	resolvers.client.discover(&query.server_name).await.map(Json).map_err(|e| bad_gateway(&e))
+}
+
+/// The response for a failed resolution.
+fn bad_gateway(error: &dyn std::error::Error) -> (StatusCode, Json<ErrorBody>) {
+	(StatusCode::BAD_GATEWAY, Json(ErrorBody { error: error.to_string() }))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+
+	use serde_json::Value;
+	use wiremock::{
+		matchers::{method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::router;
+	use crate::{client, server};
+
+	/// Validates the responses of the endpoints for an unreachable server.
+	#[tokio::test]
+	async fn endpoints() -> Result<(), Box<dyn std::error::Error>> {
+		let mock_server = MockServer::start().await;
+		Mock::given(method("GET"))
+			.and(path("/.well-known/matrix/client"))
+			.respond_with(ResponseTemplate::new(404))
+			.mount(&mock_server)
+			.await;
+		let http = reqwest::Client::builder().resolve("example.test", *mock_server.address());
+		let app = router(server::Resolver::new()?, client::Resolver::with_builder(http)?);
+		let listener = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+			.serve(app.into_make_service());
+		let api = format!("http://{}/api", listener.local_addr());
+		tokio::spawn(listener);
+
+		let report: Value =
+			reqwest::get(format!("{}/report?server_name=127.0.0.1:1", api)).await?.json().await?;
+		assert_eq!(report["FederationOK"], false);
+		assert_eq!(report["DNSResult"]["SRVSkipped"], true);
+		assert_eq!(report["DNSResult"]["Addrs"][0], "127.0.0.1:1");
+		assert!(report["ConnectionErrors"]["127.0.0.1:1"]["Message"].is_string());
+		assert!(report["Version"]["error"].is_string());
+
+		let ok = reqwest::get(format!("{}/federation-ok?server_name=127.0.0.1:1", api)).await?;
+		assert_eq!(ok.text().await?, "BAD");
+
+		let resolution: Value =
+			reqwest::get(format!("{}/resolve?server_name=127.0.0.1:1", api)).await?.json().await?;
+		assert_eq!(resolution["server"]["Socket"], "127.0.0.1:1");
+
+		let name = format!("example.test:{}", mock_server.address().port());
+		let discovery: Value =
+			reqwest::get(format!("{}/discover?server_name={}", api, name)).await?.json().await?;
+		assert_eq!(discovery["homeserver"], format!("http://{}/", name));
+
+		let missing = reqwest::get(format!("{}/report", api)).await?;
+		assert_eq!(missing.status(), reqwest::StatusCode::BAD_REQUEST);
+		Ok(())
+	}
+}
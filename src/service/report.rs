@@ -0,0 +1,478 @@
+//! Reports in the format of the [federation tester], built from a
+//! [diagnosis](crate::server::diagnose).
+//!
+//! Fields the diagnosis has no data for, like the TLS cipher, are left empty.
+//! Errors are objects with a `Message` field.
+//!
+//! [federation tester]: https://github.com/matrix-org/matrix-federation-tester
+
+use std::{
+	collections::BTreeMap,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::server::{
+	diagnose::{self, Check, TlsDetails, Verdict},
+	split_port,
+	trace::Step,
+	Server,
+};
+
+/// The report of the `/api/report` endpoint of the federation tester.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ServerReport {
+	/// Why the server name couldn't be resolved.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+	/// The .well-known delegation.
+	pub well_known_result: WellKnownResult,
+	/// The SRV records and addresses.
+	#[serde(rename = "DNSResult")]
+	pub dns_result: DnsResult,
+	/// Checks of the addresses which could be connected to.
+	pub connection_reports: BTreeMap<String, ConnectionReport>,
+	/// Why the other addresses couldn't be connected to.
+	pub connection_errors: BTreeMap<String, ErrorMessage>,
+	/// The server software of the first address which reported it.
+	pub version: VersionResult,
+	/// Whether federation with the server works.
+	#[serde(rename = "FederationOK")]
+	pub federation_ok: bool,
+}
+
+/// An error message.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ErrorMessage {
+	/// The message.
+	pub message: String,
+}
+
+/// The .well-known delegation of a server.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WellKnownResult {
+	/// The delegated server, empty if there is none.
+	#[serde(rename = "m.server")]
+	pub server_address: String,
+	/// Why the response was rejected.
+	#[serde(rename = "result", skip_serializing_if = "Option::is_none")]
+	pub result: Option<String>,
+	/// Until when the response may be cached, in seconds since the unix
+	/// epoch, or zero.
+	#[serde(rename = "CacheExpiresAt")]
+	pub cache_expires_at: u64,
+}
+
+/// The SRV records and addresses of a server.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DnsResult {
+	/// Whether SRV records were not used, because of an IP literal, a port or
+	/// delegation to either.
+	#[serde(rename = "SRVSkipped")]
+	pub srv_skipped: bool,
+	/// The SRV records found.
+	#[serde(rename = "SRVRecords")]
+	pub srv_records: Vec<SrvRecord>,
+	/// Why the SRV lookup failed.
+	#[serde(rename = "SRVError", skip_serializing_if = "Option::is_none")]
+	pub srv_error: Option<ErrorMessage>,
+	/// The addresses of the looked up hostname.
+	#[serde(rename = "Hosts")]
+	pub hosts: BTreeMap<String, HostResult>,
+	/// The addresses to connect to.
+	#[serde(rename = "Addrs")]
+	pub addrs: Vec<String>,
+}
+
+/// An SRV record.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SrvRecord {
+	/// The target hostname, with a trailing dot.
+	pub target: String,
+	/// The target port.
+	pub port: u16,
+	/// The priority of the record.
+	pub priority: u16,
+	/// The weight of the record.
+	pub weight: u16,
+}
+
+/// The addresses of a hostname.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HostResult {
+	/// The canonical name, which isn't recorded.
+	#[serde(rename = "CName")]
+	pub cname: String,
+	/// The IP addresses.
+	pub addrs: Vec<String>,
+}
+
+/// Checks of a single address.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ConnectionReport {
+	/// The certificate chain, starting with the end entity certificate.
+	pub certificates: Vec<CertificateSummary>,
+	/// The TLS version and cipher suite, which aren't recorded.
+	pub cipher: Cipher,
+	/// The outcomes of the checks.
+	pub checks: KeyChecks,
+	/// Messages of the failed checks.
+	pub errors: Vec<String>,
+	/// The verify keys by key ID.
+	pub ed25519_verify_keys: BTreeMap<String, String>,
+	/// Additional information, which is always empty.
+	pub info: BTreeMap<String, Value>,
+	/// The response of `/_matrix/key/v2/server`.
+	pub keys: Option<Value>,
+}
+
+/// A certificate presented by a server.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CertificateSummary {
+	/// The common name of the subject.
+	pub subject_common_name: String,
+	/// The common name of the issuer.
+	pub issuer_common_name: String,
+	/// The unpadded base64 SHA-256 fingerprint of the certificate.
+	#[serde(rename = "SHA256Fingerprint")]
+	pub sha256_fingerprint: String,
+	/// The DNS names the certificate is valid for.
+	#[serde(rename = "DNSNames")]
+	pub dns_names: Vec<String>,
+}
+
+/// The TLS version and cipher suite of a connection.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Cipher {
+	/// The TLS version.
+	pub version: String,
+	/// The cipher suite.
+	pub cipher_suite: String,
+}
+
+/// The outcomes of the checks of a single address.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct KeyChecks {
+	/// Whether all checks passed.
+	#[serde(rename = "AllChecksOK")]
+	pub all_checks_ok: bool,
+	/// Whether the keys are for the server name.
+	#[serde(rename = "MatchingServerName")]
+	pub matching_server_name: bool,
+	/// Whether the keys are still valid.
+	#[serde(rename = "FutureValidUntilTS")]
+	pub future_valid_until_ts: bool,
+	/// Whether there is an ed25519 key.
+	#[serde(rename = "HasEd25519Key")]
+	pub has_ed25519_key: bool,
+	/// Whether all ed25519 checks passed.
+	#[serde(rename = "AllEd25519ChecksOK")]
+	pub all_ed25519_checks_ok: bool,
+	/// The checks of each ed25519 key by key ID.
+	#[serde(rename = "Ed25519Checks")]
+	pub ed25519_checks: BTreeMap<String, Ed25519Checks>,
+	/// Whether the certificate is valid.
+	#[serde(rename = "ValidCertificates")]
+	pub valid_certificates: bool,
+}
+
+/// The checks of a single ed25519 key.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Ed25519Checks {
+	/// Whether the key is a valid ed25519 key.
+	#[serde(rename = "ValidEd25519")]
+	pub valid_ed25519: bool,
+	/// Whether the keys are signed with this key.
+	pub matching_signature: bool,
+}
+
+/// The server software, or why it couldn't be queried.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum VersionResult {
+	/// The software name and version.
+	Version {
+		/// Name of the homeserver implementation.
+		name: String,
+		/// Version of the homeserver implementation.
+		version: String,
+	},
+	/// Why the version couldn't be queried.
+	Error {
+		/// The message.
+		error: String,
+	},
+}
+
+impl From<&diagnose::Report> for ServerReport {
+	fn from(report: &diagnose::Report) -> Self {
+		let mut connection_reports = BTreeMap::new();
+		let mut connection_errors = BTreeMap::new();
+		for connection in &report.connections {
+			let address = connection.address.to_string();
+			// The connection itself failed if the TLS check didn't get a certificate
+			if connection.tls.verdict == Verdict::Fail && connection.tls.value.is_none() {
+				let message = connection.tls.message.clone();
+				connection_errors.insert(address, ErrorMessage { message });
+			} else {
+				connection_reports
+					.insert(address, connection_report(&report.server_name, connection));
+			}
+		}
+		let version = report
+			.connections
+			.iter()
+			.find_map(|connection| connection.version.value.as_ref())
+			.map_or_else(
+				|| VersionResult::Error {
+					error: report.connections.first().map_or_else(
+						|| String::from("No address could be connected to"),
+						|connection| connection.version.message.clone(),
+					),
+				},
+				|version| VersionResult::Version {
+					name: version.name.clone(),
+					version: version.version.clone(),
+				},
+			);
+		Self {
+			error: (report.resolution.verdict == Verdict::Fail)
+				.then(|| report.resolution.message.clone()),
+			well_known_result: well_known_result(report),
+			dns_result: dns_result(report),
+			connection_reports,
+			connection_errors,
+			version,
+			federation_ok: report.verdict() != Verdict::Fail,
+		}
+	}
+}
+
+/// The .well-known result of the report.
+fn well_known_result(report: &diagnose::Report) -> WellKnownResult {
+	let server_address = report
+		.well_known
+		.value
+		.as_ref()
+		.and_then(|well_known| well_known.body.as_deref())
+		.and_then(|body| serde_json::from_str::<Value>(body).ok())
+		.and_then(|body| body.get("m.server").and_then(Value::as_str).map(str::to_owned))
+		.unwrap_or_default();
+	let max_age = report.resolution.value.as_ref().and_then(|resolution| {
+		let step = resolution.trace.steps.iter().find(|step| step.step == Step::WellKnown)?;
+		step.http.as_ref()?.max_age
+	});
+	WellKnownResult {
+		server_address,
+		result: (report.well_known.verdict != Verdict::Pass)
+			.then(|| report.well_known.message.clone()),
+		cache_expires_at: max_age.map_or(0, |max_age| unix_time() + max_age),
+	}
+}
+
+/// The DNS result of the report.
+fn dns_result(report: &diagnose::Report) -> DnsResult {
+	let srv = report.srv.value.clone().unwrap_or_default();
+	let srv_records = srv
+		.matrix_fed
+		.records
+		.iter()
+		.chain(&srv.matrix.records)
+		.map(|record| SrvRecord {
+			target: format!("{}.", record.target),
+			port: record.port,
+			priority: record.priority,
+			weight: record.weight,
+		})
+		.collect();
+	let addrs: Vec<_> = report.addresses.value.iter().flatten().map(ToString::to_string).collect();
+	let mut hosts = BTreeMap::new();
+	if let Some(resolution) = &report.resolution.value {
+		let address = resolution.server.address();
+		if !matches!(resolution.server, Server::Ip(_) | Server::Socket(_)) {
+			let host = split_port(&address).map_or(address.as_str(), |(host, _)| host);
+			let ips =
+				report.addresses.value.iter().flatten().map(|addr| addr.ip().to_string()).collect();
+			hosts.insert(host.to_owned(), HostResult { cname: String::new(), addrs: ips });
+		}
+	}
+	DnsResult {
+		srv_skipped: srv.matrix_fed.query.is_empty() && srv.matrix.query.is_empty(),
+		srv_records,
+		srv_error: (report.srv.verdict != Verdict::Pass)
+			.then(|| ErrorMessage { message: report.srv.message.clone() }),
+		hosts,
+		addrs,
+	}
+}
+
+/// The checks of a single address.
+fn connection_report(
+	server_name: &str,
+	connection: &diagnose::ConnectionReport,
+) -> ConnectionReport {
+	let keys = connection.keys.value.as_ref();
+	let expected_name = split_port(server_name).map_or(server_name, |(host, _)| host);
+	let matching_server_name = keys
+		.and_then(|keys| keys.get("server_name"))
+		.and_then(Value::as_str)
+		.is_some_and(|name| name == server_name || name == expected_name);
+	let future_valid_until_ts = keys
+		.and_then(|keys| keys.get("valid_until_ts"))
+		.and_then(Value::as_u64)
+		.is_some_and(|valid_until| valid_until / 1000 > unix_time());
+	let signatures = keys.and_then(|keys| keys.get("signatures")).and_then(|signatures| {
+		signatures.get(server_name).or_else(|| signatures.get(expected_name))
+	});
+	let mut ed25519_verify_keys = BTreeMap::new();
+	let mut ed25519_checks = BTreeMap::new();
+	let verify_keys = keys.and_then(|keys| keys.get("verify_keys")).and_then(Value::as_object);
+	for (id, key) in verify_keys.into_iter().flatten() {
+		let Some(key) = key.get("key").and_then(Value::as_str) else { continue };
+		if !id.starts_with("ed25519:") {
+			continue;
+		}
+		let valid_ed25519 = STANDARD_NO_PAD.decode(key).is_ok_and(|key| key.len() == 32);
+		let matching_signature = connection.keys.verdict == Verdict::Pass
+			&& signatures.and_then(|signatures| signatures.get(id)).is_some();
+		ed25519_verify_keys.insert(id.clone(), key.to_owned());
+		ed25519_checks.insert(id.clone(), Ed25519Checks { valid_ed25519, matching_signature });
+	}
+	let has_ed25519_key = !ed25519_checks.is_empty();
+	let all_ed25519_checks_ok = has_ed25519_key
+		&& ed25519_checks.values().all(|check| check.valid_ed25519 && check.matching_signature);
+	let valid_certificates = connection.tls.verdict != Verdict::Fail;
+	let checks = KeyChecks {
+		all_checks_ok: matching_server_name
+			&& future_valid_until_ts
+			&& all_ed25519_checks_ok
+			&& valid_certificates,
+		matching_server_name,
+		future_valid_until_ts,
+		has_ed25519_key,
+		all_ed25519_checks_ok,
+		ed25519_checks,
+		valid_certificates,
+	};
+	let errors = [&connection.tls.verdict, &connection.version.verdict, &connection.keys.verdict]
+		.iter()
+		.zip([&connection.tls.message, &connection.version.message, &connection.keys.message])
+		.filter(|(verdict, _)| ***verdict == Verdict::Fail)
+		.map(|(_, message)| message.clone())
+		.collect();
+	ConnectionReport {
+		certificates: certificates(&connection.tls),
+		cipher: Cipher::default(),
+		checks,
+		errors,
+		ed25519_verify_keys,
+		info: BTreeMap::new(),
+		keys: keys.cloned(),
+	}
+}
+
+/// Summaries of the certificates the server presented.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn certificates(tls: &Check<TlsDetails>) -> Vec<CertificateSummary> {
+	use sha2::{Digest, Sha256};
+
+	let chain = tls.value.as_ref().map(|report| report.chain.as_slice()).unwrap_or_default();
+	chain
+		.iter()
+		.map(|certificate| CertificateSummary {
+			subject_common_name: common_name(&certificate.subject),
+			issuer_common_name: common_name(&certificate.issuer),
+			sha256_fingerprint: STANDARD_NO_PAD.encode(Sha256::digest(&certificate.der)),
+			dns_names: certificate
+				.subject_alt_names
+				.iter()
+				.filter(|name| name.parse::<std::net::IpAddr>().is_err())
+				.cloned()
+				.collect(),
+		})
+		.collect()
+}
+
+/// Certificates can't be inspected without a TLS backend.
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+fn certificates(_tls: &Check<TlsDetails>) -> Vec<CertificateSummary> {
+	Vec::new()
+}
+
+/// The common name of a distinguished name, or the whole name if it has
+/// none.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn common_name(name: &str) -> String {
+	name.split(", ").find_map(|part| part.strip_prefix("CN=")).unwrap_or(name).to_owned()
+}
+
+/// The current time in seconds since the unix epoch.
+fn unix_time() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+
+	use serde_json::json;
+
+	use super::ServerReport;
+	use crate::server::diagnose::{Check, ConnectionReport, Report, Verdict};
+
+	/// A check with the given verdict.
+	fn check<T>(verdict: Verdict, value: Option<T>) -> Check<T> {
+		Check { verdict, message: format!("{:?}", verdict), value }
+	}
+
+	/// Validates the key checks of a reachable server.
+	#[test]
+	fn keys() -> Result<(), Box<dyn std::error::Error>> {
+		let address: SocketAddr = "192.0.2.1:8448".parse()?;
+		let keys = json!({
+			"server_name": "example.test",
+			"valid_until_ts": 4_102_444_800_000_u64,
+			"verify_keys": {"ed25519:a": {"key": "Nfy2TN4eHlFJZMtXVj2hKMVMoIVvuCV3KCfjXhXkq5I"}},
+			"signatures": {"example.test": {"ed25519:a": "c2lnbmF0dXJl"}},
+		});
+		let report = Report {
+			server_name: String::from("example.test"),
+			resolution: check(Verdict::Pass, None),
+			well_known: check(Verdict::Pass, None),
+			srv: check(Verdict::Pass, None),
+			addresses: check(Verdict::Pass, Some(vec![address])),
+			connections: vec![ConnectionReport {
+				address,
+				tls: check(Verdict::Pass, None),
+				version: check(Verdict::Fail, None),
+				keys: check(Verdict::Pass, Some(keys)),
+			}],
+		};
+
+		let tester = ServerReport::from(&report);
+		assert!(!tester.federation_ok);
+		let connection =
+			tester.connection_reports.get("192.0.2.1:8448").ok_or("missing connection")?;
+		assert!(connection.checks.all_checks_ok);
+		assert!(connection.checks.ed25519_checks["ed25519:a"].valid_ed25519);
+		assert!(connection.checks.ed25519_checks["ed25519:a"].matching_signature);
+		assert_eq!(connection.errors, ["Fail"]);
+		assert_eq!(
+			serde_json::to_value(&tester)?["ConnectionReports"]["192.0.2.1:8448"]["Checks"]
+				["FutureValidUntilTS"],
+			true
+		);
+		Ok(())
+	}
+}
@@ -0,0 +1,219 @@
+//! Resolvers returning fixed results, for testing code which depends on the
+//! [`ServerResolve`](crate::server::ServerResolve) and
+//! [`ClientDiscover`](crate::client::ClientDiscover) traits without making
+//! network requests.
+
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
+
+#[cfg(feature = "client")]
+use reqwest::{StatusCode, Url};
+#[cfg(feature = "server")]
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+
+#[cfg(feature = "server")]
+use crate::server::{error, Server, ServerResolve};
+#[cfg(feature = "client")]
+use crate::{
+	client::{self, ClientDiscover, Discovery},
+	fetch,
+};
+
+/// Resolver answering from a fixed map of names to results. Names which
+/// aren't in the map fail to resolve.
+///
+/// With `StaticResolver<Server>`, it implements
+/// [`ServerResolve`](crate::server::ServerResolve), and with
+/// `StaticResolver<Discovery>`, it implements
+/// [`ClientDiscover`](crate::client::ClientDiscover).
+#[derive(Debug, Clone)]
+pub struct StaticResolver<T> {
+	/// The results by name.
+	entries: HashMap<String, T>,
+}
+
+/// Resolver answering with the results of a function, which records the names
+/// it was asked to resolve.
+///
+/// With a function returning `server::error::Result<Server>`, it implements
+/// [`ServerResolve`](crate::server::ServerResolve), and with a function
+/// returning `Result<Discovery, client::error::Error>`, it implements
+/// [`ClientDiscover`](crate::client::ClientDiscover).
+#[derive(Clone)]
+pub struct MockResolver<F> {
+	/// The function producing the results.
+	respond: F,
+	/// The names which were resolved, in order.
+	calls: Arc<Mutex<Vec<String>>>,
+}
+
+impl<T> StaticResolver<T> {
+	/// Constructs a resolver without any names.
+	#[must_use]
+	pub fn new() -> Self {
+		Self { entries: HashMap::new() }
+	}
+
+	/// Adds the result for the given name.
+	#[must_use]
+	pub fn with_entry(mut self, name: &str, result: T) -> Self {
+		self.entries.insert(name.to_owned(), result);
+		self
+	}
+}
+
+#[cfg(feature = "client")]
+impl StaticResolver<Discovery> {
+	/// Adds a name for which only the given homeserver is discovered.
+	#[must_use]
+	pub fn with_homeserver(self, name: &str, homeserver: Url) -> Self {
+		self.with_entry(
+			name,
+			Discovery {
+				homeserver,
+				well_known_missing: false,
+				identity_server: None,
+				versions: None,
+				sliding_sync: None,
+				tile_server: None,
+				integration_managers: Vec::new(),
+				cors_warnings: Vec::new(),
+				warnings: Vec::new(),
+			},
+		)
+	}
+}
+
+impl<T> Default for StaticResolver<T> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(feature = "server")]
+#[async_trait::async_trait]
+impl ServerResolve for StaticResolver<Server> {
+	async fn resolve_server(&self, name: &str) -> error::Result<Server> {
+		match self.entries.get(name) {
+			Some(server) => Ok(server.clone()),
+			None => Err(ResolveError::from(ResolveErrorKind::Msg(format!(
+				"no static resolution for {}",
+				name
+			)))
+			.into()),
+		}
+	}
+}
+
+/// Unknown names fail as if the server couldn't be reached.
+#[cfg(feature = "client")]
+#[async_trait::async_trait]
+impl ClientDiscover for StaticResolver<Discovery> {
+	async fn discover_homeserver(&self, name: &str) -> Result<Discovery, client::error::Error> {
+		match self.entries.get(name) {
+			Some(discovery) => Ok(discovery.clone()),
+			None => Err(fetch::Error::Status(StatusCode::NOT_FOUND).into()),
+		}
+	}
+}
+
+impl<F> MockResolver<F> {
+	/// Constructs a resolver answering with the results of the given function.
+	#[must_use]
+	pub fn new(respond: F) -> Self {
+		Self { respond, calls: Arc::default() }
+	}
+
+	/// The names which were resolved so far, in order.
+	#[must_use]
+	pub fn calls(&self) -> Vec<String> {
+		self.calls.lock().map(|calls| calls.clone()).unwrap_or_default()
+	}
+
+	/// Records that the given name was resolved.
+	fn record(&self, name: &str) {
+		if let Ok(mut calls) = self.calls.lock() {
+			calls.push(name.to_owned());
+		}
+	}
+}
+
+impl<F> std::fmt::Debug for MockResolver<F> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("MockResolver").field("calls", &self.calls).finish_non_exhaustive()
+	}
+}
+
+#[cfg(feature = "server")]
+#[async_trait::async_trait]
+impl<F> ServerResolve for MockResolver<F>
+where
+	F: Fn(&str) -> error::Result<Server> + Send + Sync,
+{
+	async fn resolve_server(&self, name: &str) -> error::Result<Server> {
+		self.record(name);
+		(self.respond)(name)
+	}
+}
+
+#[cfg(feature = "client")]
+#[async_trait::async_trait]
+impl<F> ClientDiscover for MockResolver<F>
+where
+	F: Fn(&str) -> Result<Discovery, client::error::Error> + Send + Sync,
+{
+	async fn discover_homeserver(&self, name: &str) -> Result<Discovery, client::error::Error> {
+		self.record(name);
+		(self.respond)(name)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#[cfg(feature = "client")]
+	use reqwest::Url;
+
+	use super::{MockResolver, StaticResolver};
+	#[cfg(feature = "client")]
+	use crate::client::{ClientDiscover, Discovery};
+	#[cfg(feature = "server")]
+	use crate::server::{error::Error, Server, ServerResolve};
+
+	/// Validates answering server names through the trait.
+	#[cfg(feature = "server")]
+	#[tokio::test]
+	async fn server() -> Result<(), Box<dyn std::error::Error>> {
+		let server = Server::HostPort(String::from("destination.test:8448"));
+		let fixed = StaticResolver::new().with_entry("example.test", server.clone());
+		let resolver: &dyn ServerResolve = &fixed;
+		assert_eq!(resolver.resolve_server("example.test").await?, server);
+		assert!(matches!(resolver.resolve_server("other.test").await, Err(Error::Dns(_))));
+
+		let mock = MockResolver::new(|name: &str| Ok(Server::Host(name.to_owned())));
+		assert_eq!(mock.resolve_server("a.test").await?, Server::Host(String::from("a.test")));
+		assert_eq!(mock.resolve_server("b.test").await?, Server::Host(String::from("b.test")));
+		assert_eq!(mock.calls(), ["a.test", "b.test"]);
+		Ok(())
+	}
+
+	/// Validates discovering homeservers through the trait.
+	#[cfg(feature = "client")]
+	#[tokio::test]
+	async fn client() -> Result<(), Box<dyn std::error::Error>> {
+		let homeserver = Url::parse("https://matrix.example.test")?;
+		let fixed =
+			StaticResolver::<Discovery>::new().with_homeserver("example.test", homeserver.clone());
+		let resolver: &dyn ClientDiscover = &fixed;
+		assert_eq!(resolver.resolve_homeserver("example.test").await?, homeserver);
+		assert!(resolver.discover_homeserver("other.test").await.is_err());
+
+		let discovery = fixed.discover_homeserver("example.test").await?;
+		let mock = MockResolver::new(move |_: &str| Ok(discovery.clone()));
+		assert_eq!(mock.resolve_homeserver("a.test").await?, homeserver);
+		assert_eq!(mock.discover_homeserver("b.test").await?.homeserver, homeserver);
+		assert_eq!(mock.calls(), ["a.test", "b.test"]);
+		Ok(())
+	}
+}
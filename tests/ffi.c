@@ -0,0 +1,77 @@
+/* Calls the C bindings the way C code would, built and run by tests/ffi.rs. */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "matrix_oracle.h"
+
+/*
+ * Passes invalid arguments to the client resolver. Returns 0 on success, or
+ * the line of the failed check.
+ */
+int test_client_arguments(void) {
+	MatrixOracleClient *resolver = NULL;
+	char *homeserver = NULL;
+	int result = 0;
+
+	if (matrix_oracle_client_new(NULL) != MATRIX_ORACLE_INVALID_ARGUMENT) {
+		return __LINE__;
+	}
+	if (matrix_oracle_client_new(&resolver) != MATRIX_ORACLE_OK) {
+		return __LINE__;
+	}
+	if (matrix_oracle_client_resolve(resolver, NULL, &homeserver) !=
+	    MATRIX_ORACLE_INVALID_ARGUMENT) {
+		result = __LINE__;
+	} else if (matrix_oracle_client_resolve(NULL, "example.org", &homeserver) !=
+	           MATRIX_ORACLE_INVALID_ARGUMENT) {
+		result = __LINE__;
+	} else if (homeserver != NULL) {
+		result = __LINE__;
+	}
+	matrix_oracle_client_free(resolver);
+	return result;
+}
+
+/*
+ * Resolves a socket literal and passes invalid arguments. Returns 0 on
+ * success, or the line of the failed check.
+ */
+int test_server_resolve(void) {
+	MatrixOracleServer *resolver = NULL;
+	char *address = NULL;
+	char *host = NULL;
+	int result = 0;
+
+	if (matrix_oracle_server_new(NULL) != MATRIX_ORACLE_INVALID_ARGUMENT) {
+		return __LINE__;
+	}
+	if (matrix_oracle_server_new(&resolver) != MATRIX_ORACLE_OK) {
+		return __LINE__;
+	}
+	if (matrix_oracle_server_resolve(resolver, NULL, &address, &host) !=
+	    MATRIX_ORACLE_INVALID_ARGUMENT) {
+		result = __LINE__;
+	} else if (matrix_oracle_server_resolve(resolver, "127.0.0.1:4884", &address, &host) !=
+	           MATRIX_ORACLE_OK) {
+		result = __LINE__;
+	} else if (strcmp(address, "127.0.0.1:4884") != 0 || strcmp(host, "127.0.0.1:4884") != 0) {
+		result = __LINE__;
+	}
+	matrix_oracle_string_free(address);
+	matrix_oracle_string_free(host);
+	matrix_oracle_server_free(resolver);
+	return result;
+}
+
+int main(void) {
+	int line = test_client_arguments();
+	if (line == 0) {
+		line = test_server_resolve();
+	}
+	if (line != 0) {
+		fprintf(stderr, "check on line %d failed\n", line);
+		return 1;
+	}
+	return 0;
+}
@@ -0,0 +1,34 @@
+//! Builds `tests/ffi.c` against the shared library and runs it, to check the
+//! C bindings the way C code calls them. The compiler is taken from `CC`, or
+//! `cc` if it isn't set.
+#![cfg(all(feature = "ffi", unix))]
+
+use std::{env, path::PathBuf, process::Command};
+
+/// Validates the bindings and the declarations in `include/matrix_oracle.h`
+/// from C.
+#[test]
+fn ffi() -> Result<(), Box<dyn std::error::Error>> {
+	let manifest = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+	// The test binary is in the `deps` directory, next to the libraries.
+	let exe = env::current_exe()?;
+	let libraries = exe.parent().ok_or("no deps directory")?;
+	let program = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("matrix-oracle-ffi");
+
+	let status = Command::new(env::var_os("CC").unwrap_or_else(|| "cc".into()))
+		.arg(manifest.join("tests/ffi.c"))
+		.arg("-I")
+		.arg(manifest.join("include"))
+		.args(["-Wall", "-Werror", "-o"])
+		.arg(&program)
+		.arg("-L")
+		.arg(libraries)
+		.arg(format!("-Wl,-rpath,{}", libraries.display()))
+		.arg("-lmatrix_oracle")
+		.status()?;
+	assert!(status.success(), "compiling tests/ffi.c failed");
+
+	let output = Command::new(&program).output()?;
+	assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+	Ok(())
+}